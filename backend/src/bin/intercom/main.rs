@@ -1,38 +1,112 @@
 use common::build::Build;
-use common::device;
+use common::capabilities::{self, DeviceKind, Health, Inventory};
+use common::config::Config;
+use common::device::audit;
 use common::device::door;
+use common::device::feedback;
 use common::device::keypad;
 use common::device::nfc;
+use common::device::scheduler;
 use common::device::terminal;
 use common::dispatch;
+use common::gpio;
+use common::notify::Notifier;
+use common::schedule::GuestCodes;
+use common::supervisor::Supervisor;
+use std::env;
 use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 fn main() {
     // Create
     dotenv::dotenv().expect("Failed to read .env file");
+    let config = Config::load();
+    let gpio = gpio::Backend::from_config(&config.gpio);
+    let inventory = Inventory::default();
+    let supervisor = Supervisor::new(inventory.clone());
+    supervisor.stop_on_signals();
+    if env::args().any(|x| x == "--keypad-diagnostic") {
+        // Only reads the keypad, so the wiring can be checked without touching the door
+        let matrix = keypad::KeyPadMatrix::build((gpio, config.keypad));
+        println!("Press keys to see their position, Ctrl-C to stop");
+        matrix.diagnose(|| supervisor.is_stopping());
+        matrix.release();
+        return;
+    }
+    let doors = config.doors();
+    let keypad_doors = config.reader_doors(&config.keypad.doors);
+    let nfc_doors = config.reader_doors(&config.nfc.doors);
+    let door_names = config.door_names();
     let (terminal_channel, terminal_device) = terminal::TerminalDevice::build(());
-    let (door_channel, door_internal_channel, door_device) = door::DoorDevice::build(());
-    let (nfc_channel, nfc_device) = nfc::NFCDevice::build(door_internal_channel.clone());
-    let (keypad_channel, keypad_device) = keypad::KeyPadDevice::build(door_internal_channel);
-    let dispatcher =
-        dispatch::Dispatcher::build((terminal_channel, door_channel, keypad_channel, nfc_channel));
-    let terminal_handle = device::launch_device(terminal_device);
-    let nfc_handle = device::launch_device(nfc_device);
-    let door_handle = device::launch_device(door_device);
-    let keypad_handle = device::launch_device(keypad_device);
+    let (audit_channel, audit_device) = audit::AuditDevice::build(config.audit);
+    let lockdown = door::Lockdown::default();
+    let notifier = Notifier::from_env();
+    let guest_codes = GuestCodes::default();
+    let (door_channel, door_device) = door::DoorDevice::build((
+        gpio.clone(),
+        doors,
+        audit_channel.clone(),
+        lockdown.clone(),
+        notifier.clone(),
+    ));
+    let (feedback_channel, feedback_device) =
+        feedback::FeedbackDevice::build((gpio.clone(), config.feedback));
+    let nfc_reader = Arc::new(Mutex::new(nfc::Pn532::new()));
+    let (nfc_channel, nfc_device) = nfc::NFCDevice::build((
+        door_channel.clone(),
+        audit_channel.clone(),
+        feedback_channel.clone(),
+        nfc_reader,
+        inventory.clone(),
+        lockdown.clone(),
+        nfc_doors,
+        door_names.clone(),
+    ));
+    let (keypad_channel, keypad_device) = keypad::KeyPadDevice::build((
+        door_channel.clone(),
+        audit_channel.clone(),
+        feedback_channel.clone(),
+        gpio,
+        config.lockout,
+        config.keypad,
+        lockdown,
+        notifier,
+        guest_codes.clone(),
+        keypad_doors,
+        door_names,
+    ));
+    let (scheduler_channel, scheduler_device) =
+        scheduler::SchedulerDevice::build((door_channel.clone(), guest_codes));
+    let mut dispatcher = dispatch::Dispatcher::new(supervisor.clone());
+    dispatcher.register(&terminal_channel);
+    dispatcher.register(&door_channel);
+    dispatcher.register(&keypad_channel);
+    dispatcher.register(&nfc_channel);
+    dispatcher.register(&audit_channel);
+    dispatcher.register(&feedback_channel);
+    dispatcher.register(&scheduler_channel);
+    if Path::new(capabilities::CAMERA_DEVICE).exists() {
+        inventory.set(DeviceKind::Camera, Health::Ok);
+    }
+    supervisor.launch(DeviceKind::Terminal, terminal_device);
+    supervisor.launch(DeviceKind::Audit, audit_device);
+    supervisor.launch(DeviceKind::Nfc, nfc_device);
+    supervisor.launch(DeviceKind::Door, door_device);
+    supervisor.launch(DeviceKind::Keypad, keypad_device);
+    supervisor.launch(DeviceKind::Feedback, feedback_device);
+    supervisor.launch(DeviceKind::Scheduler, scheduler_device);
 
     // Start server
     let listener = TcpListener::bind("0.0.0.0:2000").unwrap();
     println!("Listening on 192.168.7.2:2000");
-    let dispatch_handle = thread::spawn(|| {
-        dispatch::start_server(dispatcher, listener);
+    let link = config.link.link();
+    thread::spawn(move || {
+        dispatch::start_server(dispatcher, listener, link);
     });
 
-    // Clean up
-    terminal_handle.join().unwrap();
-    door_handle.join().unwrap();
-    nfc_handle.join().unwrap();
-    keypad_handle.join().unwrap();
-    dispatch_handle.join().unwrap();
+    // Clean up once asked to stop
+    supervisor.wait();
+    println!("Intercom stopped");
 }
@@ -0,0 +1,338 @@
+use common::build::Build;
+use common::capabilities::{DeviceKind, Inventory};
+use common::config::Config;
+use common::device::audit;
+use common::device::door::{self, DoorConfig};
+use common::device::feedback;
+use common::device::keypad;
+use common::device::nfc;
+use common::device::scheduler;
+use common::device::terminal;
+use common::dispatch;
+use common::feedback::FeedbackConfig;
+use common::gpio::{self, MockBoard};
+use common::keyscan::KeypadConfig;
+use common::notify::Notifier;
+use common::schedule::GuestCodes;
+use common::supervisor::Supervisor;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::net::TcpListener;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type PressedKeys = Arc<Mutex<HashSet<(usize, usize)>>>;
+
+const KEY_HOLD: Duration = Duration::from_millis(150);
+const HELP: &str = "Commands:
+  press <keys>   type keys on the keypad, e.g. `press 1234#`
+  tap <uid>      tap a card with the hex UID, e.g. `tap 04a1b2c3`
+  wait <ms>      pause before the next command
+  door [name]    print the state of every door, or of the named one
+  open / close [name]
+                 open or close a door, if it has a sensor; the first door by default
+  exit [name]    press and let go of a door's exit button
+  quit           exit the simulator";
+
+// A pressed key connects its row and column, so the column reads low while the
+// scanner is driving that row low.
+fn wire_keypad(board: &MockBoard, pressed: PressedKeys, keypad: &KeypadConfig) {
+    for (j, col) in keypad.cols.iter().enumerate() {
+        let rows: Vec<_> = keypad.rows.iter().map(|x| board.pin(*x)).collect();
+        let pressed = pressed.clone();
+        board.pin(*col).set_input(Arc::new(move || {
+            let pressed = pressed.lock().unwrap();
+            let connected = rows.iter().enumerate().any(|(i, row)| {
+                pressed.contains(&(i, j))
+                    && row.direction() == gpio::Direction::Out
+                    && row.level() == 0
+            });
+            if connected {
+                0
+            } else {
+                1
+            }
+        }));
+    }
+}
+
+fn press_keys(keys: &str, pressed: &PressedKeys, keypad: &KeypadConfig) {
+    for key in keys.chars() {
+        let position = match keypad.position(key) {
+            Some(position) => position,
+            None => {
+                println!("No key '{}' on the keypad", key);
+                continue;
+            }
+        };
+        pressed.lock().unwrap().insert(position);
+        thread::sleep(KEY_HOLD);
+        pressed.lock().unwrap().remove(&position);
+        thread::sleep(KEY_HOLD);
+    }
+}
+
+fn parse_uid(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn door_state(board: &MockBoard, door: &DoorConfig) -> &'static str {
+    match board.pin(door.pin).level() ^ door.active_low as u8 {
+        0 => "Locked",
+        _ => "Unlocked",
+    }
+}
+
+// Sets the door sensor to read open or closed.
+fn set_door_open(board: &MockBoard, door: &DoorConfig, open: bool) {
+    match door.sensor {
+        Some(sensor) => board
+            .pin(sensor)
+            .set_level(open as u8 ^ door.sensor_active_low as u8),
+        None => println!("The door has no sensor"),
+    }
+}
+
+fn press_exit_button(board: &MockBoard, door: &DoorConfig) {
+    let button = match door.exit_button {
+        Some(button) => board.pin(button),
+        None => return println!("The door has no exit button"),
+    };
+    let pressed = !door.exit_button_active_low as u8;
+    button.set_level(pressed);
+    thread::sleep(Duration::from_millis(door.exit_debounce_ms + 100));
+    button.set_level(pressed ^ 1);
+}
+
+// The named door, or the first one without a name.
+fn find_door<'a>(doors: &'a [DoorConfig], name: &str) -> Option<&'a DoorConfig> {
+    let door = match name {
+        "" => doors.first(),
+        name => doors.iter().find(|x| x.name == name),
+    };
+    if door.is_none() {
+        println!("No door named '{}'", name);
+    }
+    door
+}
+
+fn watch_doors(board: MockBoard, doors: Vec<DoorConfig>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last: Vec<_> = doors.iter().map(|x| door_state(&board, x)).collect();
+        loop {
+            for (door, last) in doors.iter().zip(last.iter_mut()) {
+                let state = door_state(&board, door);
+                if state != *last {
+                    println!("[door] {}: {}", door.name, state);
+                    *last = state;
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    })
+}
+
+fn watch_leds(board: MockBoard, feedback: &FeedbackConfig) -> JoinHandle<()> {
+    let leds: Vec<_> = [("red", feedback.red_led), ("green", feedback.green_led)]
+        .into_iter()
+        .filter_map(|(name, pin)| Some((name, board.pin(pin?))))
+        .collect();
+    thread::spawn(move || {
+        let mut last: Vec<u8> = leds.iter().map(|(_, pin)| pin.level()).collect();
+        loop {
+            for ((name, pin), last) in leds.iter().zip(last.iter_mut()) {
+                let level = pin.level();
+                if level != *last {
+                    println!("[led] {} {}", name, if level == 1 { "on" } else { "off" });
+                    *last = level;
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    })
+}
+
+// Returns false once the simulator should exit.
+fn run_command(
+    line: &str,
+    board: &MockBoard,
+    pressed: &PressedKeys,
+    reader: &nfc::MockCardReader,
+    keypad: &KeypadConfig,
+    doors: &[DoorConfig],
+) -> bool {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with("//") {
+        return true;
+    }
+    let (command, argument) = match line.split_once(' ') {
+        Some((command, argument)) => (command, argument.trim()),
+        None => (line, ""),
+    };
+    match command {
+        "press" => press_keys(argument, pressed, keypad),
+        "tap" => match parse_uid(argument) {
+            Some(uid) => reader.tap(uid),
+            None => println!("Invalid card UID '{}'", argument),
+        },
+        "wait" => match argument.parse() {
+            Ok(ms) => thread::sleep(Duration::from_millis(ms)),
+            Err(_) => println!("Invalid wait '{}'", argument),
+        },
+        "door" => {
+            for door in doors {
+                if argument.is_empty() || door.name == argument {
+                    println!("[door] {}: {}", door.name, door_state(board, door));
+                }
+            }
+        }
+        "open" | "close" => {
+            if let Some(door) = find_door(doors, argument) {
+                set_door_open(board, door, command == "open");
+            }
+        }
+        "exit" => {
+            if let Some(door) = find_door(doors, argument) {
+                press_exit_button(board, door);
+            }
+        }
+        "quit" => return false,
+        _ => println!("{}", HELP),
+    }
+    true
+}
+
+fn main() {
+    // Create
+    dotenv::dotenv().ok();
+    if env::var("TO_NUMBER").is_err() {
+        env::set_var("TO_NUMBER", "+15555550100");
+    }
+    let config = Config::load();
+    let board = MockBoard::default();
+    let gpio = gpio::Backend::Mock(board.clone());
+    let pressed = PressedKeys::default();
+    let keypad = config.keypad.clone();
+    let doors = config.doors();
+    wire_keypad(&board, pressed.clone(), &keypad);
+    for door in doors.iter() {
+        if door.sensor.is_some() {
+            set_door_open(&board, door, false);
+        }
+        if let Some(button) = door.exit_button {
+            board
+                .pin(button)
+                .set_level(door.exit_button_active_low as u8);
+        }
+    }
+    let reader = nfc::MockCardReader::default();
+
+    let inventory = Inventory::default();
+    let supervisor = Supervisor::new(inventory.clone());
+    supervisor.stop_on_signals();
+    let keypad_doors = config.reader_doors(&config.keypad.doors);
+    let nfc_doors = config.reader_doors(&config.nfc.doors);
+    let door_names = config.door_names();
+    let (terminal_channel, terminal_device) = terminal::TerminalDevice::build(());
+    let (audit_channel, audit_device) = audit::AuditDevice::build(config.audit);
+    let lockdown = door::Lockdown::default();
+    let notifier = Notifier::from_env();
+    let guest_codes = GuestCodes::default();
+    let (door_channel, door_device) = door::DoorDevice::build((
+        gpio.clone(),
+        doors.clone(),
+        audit_channel.clone(),
+        lockdown.clone(),
+        notifier.clone(),
+    ));
+    let (feedback_channel, feedback_device) =
+        feedback::FeedbackDevice::build((gpio.clone(), config.feedback.clone()));
+    let (nfc_channel, nfc_device) = nfc::NFCDevice::build((
+        door_channel.clone(),
+        audit_channel.clone(),
+        feedback_channel.clone(),
+        Arc::new(Mutex::new(reader.clone())),
+        inventory.clone(),
+        lockdown.clone(),
+        nfc_doors,
+        door_names.clone(),
+    ));
+    let (keypad_channel, keypad_device) = keypad::KeyPadDevice::build((
+        door_channel.clone(),
+        audit_channel.clone(),
+        feedback_channel.clone(),
+        gpio,
+        config.lockout,
+        config.keypad,
+        lockdown,
+        notifier,
+        guest_codes.clone(),
+        keypad_doors,
+        door_names,
+    ));
+    let (scheduler_channel, scheduler_device) =
+        scheduler::SchedulerDevice::build((door_channel.clone(), guest_codes));
+    let mut dispatcher = dispatch::Dispatcher::new(supervisor.clone());
+    dispatcher.register(&terminal_channel);
+    dispatcher.register(&door_channel);
+    dispatcher.register(&keypad_channel);
+    dispatcher.register(&nfc_channel);
+    dispatcher.register(&audit_channel);
+    dispatcher.register(&feedback_channel);
+    dispatcher.register(&scheduler_channel);
+    supervisor.launch(DeviceKind::Terminal, terminal_device);
+    supervisor.launch(DeviceKind::Audit, audit_device);
+    supervisor.launch(DeviceKind::Nfc, nfc_device);
+    supervisor.launch(DeviceKind::Door, door_device);
+    supervisor.launch(DeviceKind::Keypad, keypad_device);
+    supervisor.launch(DeviceKind::Feedback, feedback_device);
+    supervisor.launch(DeviceKind::Scheduler, scheduler_device);
+    watch_doors(board.clone(), doors.clone());
+    watch_leds(board.clone(), &config.feedback);
+
+    // Start server
+    let listener = TcpListener::bind("0.0.0.0:2000").unwrap();
+    println!("Simulated intercom listening on 0.0.0.0:2000");
+    let link = config.link.link();
+    thread::spawn(move || {
+        dispatch::start_server(dispatcher, listener, link);
+    });
+
+    // Scenario file first, then interactive commands
+    let commands = supervisor.clone();
+    thread::spawn(move || {
+        if let Some(path) = env::args().nth(1) {
+            let scenario = fs::read_to_string(&path)
+                .unwrap_or_else(|error| panic!("Unable to read scenario {}: {}", path, error));
+            for line in scenario.lines() {
+                println!("> {}", line);
+                if !run_command(line, &board, &pressed, &reader, &keypad, &doors) {
+                    commands.stop();
+                    return;
+                }
+            }
+        }
+        println!("{}", HELP);
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) if run_command(&line, &board, &pressed, &reader, &keypad, &doors) => (),
+                _ => break,
+            }
+        }
+        commands.stop();
+    });
+
+    supervisor.wait();
+    // Stdin may still be blocked on a read.
+    process::exit(0);
+}
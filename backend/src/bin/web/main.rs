@@ -1,4 +1,5 @@
 use anyhow::Result;
+use common::config::Config;
 use core::convert::Infallible;
 use futures::FutureExt;
 use futures::StreamExt;
@@ -12,7 +13,10 @@ use tokio::time::Duration;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
 use warp::filters::ws::Message;
-use warp::{self, Filter};
+use warp::http::StatusCode;
+use warp::{self, Filter, Reply};
+use web_auth::{Auth, Session, SESSION_COOKIE};
+use web_intercom::Intercom;
 use web_relay::listen_for_web;
 use web_requests::{Commands, WebSocketRequest};
 use web_ws::{Client, Clients};
@@ -35,6 +39,9 @@ use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_local::TrackLocal;
 use webrtc::track::track_remote::TrackRemote;
 use webrtc::util::{Conn, Marshal, Unmarshal};
+mod web_auth;
+mod web_events;
+mod web_intercom;
 mod web_relay;
 mod web_requests;
 mod web_rtp;
@@ -47,6 +54,12 @@ struct WsResult {
     response: String,
 }
 
+#[derive(Serialize, Debug)]
+struct WsError {
+    id: String,
+    error: String,
+}
+
 #[derive(Clone)]
 struct UdpConn {
     conn: Arc<dyn Conn + Send + Sync>,
@@ -55,6 +68,19 @@ struct UdpConn {
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        web_auth::manage_users(&args);
+        return;
+    }
+
+    let config = Config::load();
+    let link = config.link.link();
+    let auth = Auth::new(config.tls.enabled);
+    let intercom = Intercom::new(
+        format!("{}:2000", env::var("INTERCOM_ADDRESS").unwrap()),
+        link.clone(),
+    );
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
 
     let video_track = Arc::new(TrackLocalStaticRTP::new(
@@ -77,20 +103,65 @@ async fn main() {
 
     let rtc_server_handle_video = web_rtp::mainloop(video_track.clone(), 8002);
     let rtc_server_handle_audio = web_rtp::mainloop(audio_track.clone(), 8004);
+    let events_handle = web_events::mainloop(clients.clone(), link);
 
     let ws = warp::path("socket")
         .and(warp::ws())
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and(with_auth(auth.clone()))
         .and(with_clients(clients.clone()))
+        .and(with_intercom(intercom))
         .and(with_track(video_track.clone()))
         .and(with_track(audio_track.clone()))
         .map(
-            |ws: warp::ws::Ws, clients: Clients, video_track: Arc<_>, audio_track: Arc<_>| {
-                ws.on_upgrade(move |socket| {
-                    handle_ws_client(socket, clients, video_track, audio_track)
-                })
+            |ws: warp::ws::Ws,
+             token: Option<String>,
+             auth: Auth,
+             clients: Clients,
+             intercom: Intercom,
+             video_track: Arc<_>,
+             audio_track: Arc<_>|
+             -> Box<dyn Reply> {
+                let session = match token {
+                    Some(token) => auth.session(&token),
+                    None => Ok(None),
+                };
+                match session {
+                    Ok(Some(session)) => Box::new(ws.on_upgrade(move |socket| {
+                        handle_ws_client(
+                            socket,
+                            session,
+                            clients,
+                            intercom,
+                            video_track,
+                            audio_track,
+                        )
+                    })),
+                    Ok(None) => Box::new(warp::reply::with_status(
+                        "Not logged in",
+                        StatusCode::UNAUTHORIZED,
+                    )),
+                    Err(error) => Box::new(warp::reply::with_status(
+                        error.0,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )),
+                }
             },
         );
 
+    let login = warp::path("login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(4096))
+        .and(warp::body::json())
+        .and(with_auth(auth.clone()))
+        .map(web_auth::login);
+
+    let logout = warp::path("logout")
+        .and(warp::post())
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and(with_auth(auth.clone()))
+        .map(web_auth::logout);
+
     let webpage = warp::get()
         .and(warp::path::end())
         .and(warp::fs::file("frontend/index.html"));
@@ -98,21 +169,47 @@ async fn main() {
     let public_files = warp::fs::dir("frontend/");
     let routes = webpage
         .or(ws)
+        .or(login)
+        .or(logout)
         .or(public_files)
         .with(warp::log("warp::filters::fs"));
 
-    println!("Running at http://0.0.0.0:5000");
-
-    warp::serve(routes).run(([0, 0, 0, 0], 5000)).await;
+    if config.tls.enabled {
+        config
+            .tls
+            .ensure_certificate()
+            .unwrap_or_else(|error| panic!("{}", error.0));
+        println!("Running at https://0.0.0.0:5000");
+        warp::serve(routes)
+            .tls()
+            .cert_path(&config.tls.cert)
+            .key_path(&config.tls.key)
+            .run(([0, 0, 0, 0], 5000))
+            .await;
+    } else {
+        println!("Running at http://0.0.0.0:5000");
+        warp::serve(routes).run(([0, 0, 0, 0], 5000)).await;
+    }
 
     rtc_server_handle_video.join().unwrap();
     rtc_server_handle_audio.join().unwrap();
+    events_handle.join().unwrap();
+}
+
+fn with_auth(auth: Auth) -> impl Filter<Extract = (Auth,), Error = Infallible> + Clone {
+    warp::any().map(move || auth.clone())
 }
 
 fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = Infallible> + Clone {
     warp::any().map(move || clients.clone())
 }
 
+fn with_intercom(
+    intercom: Intercom,
+) -> impl Filter<Extract = (Intercom,), Error = Infallible> + Clone {
+    warp::any().map(move || intercom.clone())
+}
+
 fn with_track(
     track: Arc<TrackLocalStaticRTP>,
 ) -> impl Filter<Extract = (Arc<TrackLocalStaticRTP>,), Error = Infallible> + Clone {
@@ -121,7 +218,9 @@ fn with_track(
 
 async fn handle_ws_client(
     websocket: warp::ws::WebSocket,
+    session: Session,
     clients: Clients,
+    intercom: Intercom,
     video_track: Arc<TrackLocalStaticRTP>,
     audio_track: Arc<TrackLocalStaticRTP>,
 ) {
@@ -143,10 +242,11 @@ async fn handle_ws_client(
         Client {
             id: uuid.clone(),
             ws: ws_sender,
+            session: session.clone(),
         },
     );
 
-    println!("ws connected");
+    println!("ws connected: {}", session.name);
     while let Some(result) = receiver.next().await {
         let msg = match result {
             Ok(msg) => msg,
@@ -160,6 +260,7 @@ async fn handle_ws_client(
             &uuid,
             msg,
             &clients,
+            &intercom,
             video_track.clone(),
             audio_track.clone(),
         )
@@ -179,6 +280,15 @@ fn reply(req: WebSocketRequest, client: &Client, msg: String) {
     client.ws.send(Ok(Message::text(response))).unwrap();
 }
 
+fn reply_error(req: WebSocketRequest, client: &Client, error: &str) {
+    let response = serde_json::to_string(&WsError {
+        id: req.id,
+        error: error.to_string(),
+    })
+    .unwrap();
+    client.ws.send(Ok(Message::text(response))).unwrap();
+}
+
 // https://github.com/webrtc-rs/examples/tree/main/examples/rtp-to-webrtc
 async fn start_rtc(
     req: WebSocketRequest,
@@ -509,6 +619,7 @@ async fn client_msg(
     client_id: &str,
     msg: Message,
     clients: &Clients,
+    intercom: &Intercom,
     video_track: Arc<TrackLocalStaticRTP>,
     audio_track: Arc<TrackLocalStaticRTP>,
 ) {
@@ -517,8 +628,10 @@ async fn client_msg(
         Err(_) => return,
     };
 
-    match clients.lock().await.get_mut(client_id) {
-        Some(client) => {
+    // Work on a copy so other clients are not held up while this one waits on the intercom.
+    let mut client = clients.lock().await.get(client_id).cloned();
+    match client {
+        Some(ref mut client) => {
             let req: WebSocketRequest = match from_str(message) {
                 Ok(req) => req,
                 Err(e) => {
@@ -527,19 +640,44 @@ async fn client_msg(
                 }
             };
 
+            if !web_auth::allows(client.session.role, &req.command) {
+                reply_error(req, client, "Not allowed");
+                return;
+            }
+
             match req.command {
                 Commands::Ping => reply(req, client, "pong".to_string()),
+                Commands::Whoami => {
+                    let session = serde_json::to_string(&client.session).unwrap();
+                    reply(req, client, session)
+                }
                 Commands::DoorGet
                 | Commands::DoorSet
                 | Commands::PhoneGet
                 | Commands::PhoneSet
                 | Commands::KeypadSetCode
                 | Commands::KeypadGetCode
+                | Commands::KeypadListCodes
+                | Commands::KeypadAddCode
+                | Commands::KeypadRevokeCode
+                | Commands::KeypadEditCode
                 | Commands::NFCGet
-                | Commands::NFCSet => {
-                    let res = listen_for_web(req.clone()).await;
-                    reply(req, client, res)
-                }
+                | Commands::NFCSet
+                | Commands::NFCRemoveCard
+                | Commands::NFCSetCardEnabled
+                | Commands::NFCRenameCard
+                | Commands::NFCSetCardDoors
+                | Commands::KeypadGetLockout
+                | Commands::KeypadClearLockout
+                | Commands::AuditQuery
+                | Commands::ScheduleList
+                | Commands::ScheduleAddRule
+                | Commands::ScheduleEditRule
+                | Commands::ScheduleRemoveRule
+                | Commands::Capabilities => match listen_for_web(req.clone(), intercom).await {
+                    Ok(res) => reply(req, client, res),
+                    Err(error) => reply_error(req, client, &error.to_string()),
+                },
                 Commands::RtcAudioSession => start_audio_rtc(req, client).await,
                 Commands::RtcSession => start_rtc(req, client, video_track, audio_track).await,
                 _ => {
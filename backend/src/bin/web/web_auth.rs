@@ -0,0 +1,193 @@
+use crate::web_requests::Commands;
+use common::request::Error;
+use common::users::{random_token, Role, UserStore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::Reply;
+
+pub const SESSION_COOKIE: &str = "session";
+const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Session {
+    pub name: String,
+    pub role: Role,
+    #[serde(skip)]
+    expires: Instant,
+}
+
+#[derive(Deserialize)]
+pub struct Login {
+    pub name: String,
+    pub password: String,
+}
+
+fn users_file() -> String {
+    env::var("WEB_USERS").unwrap_or_else(|_| UserStore::DEFAULT_FILE.to_string())
+}
+
+/// Logged in sessions, keyed by the token in the session cookie.
+#[derive(Clone)]
+pub struct Auth {
+    users_file: String,
+    /// Only send the cookie over HTTPS.
+    secure: bool,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Auth {
+    pub fn new(secure: bool) -> Auth {
+        let users_file = users_file();
+        if UserStore::load_from(&users_file).is_empty() {
+            println!(
+                "No users in {}, add one with `server add-user <name> admin`",
+                users_file
+            );
+        }
+        Auth {
+            users_file,
+            secure,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // The file is re-read each time so users added or removed from the command line
+    // take effect without restarting the server.
+    fn users(&self) -> Result<UserStore, Error> {
+        UserStore::read(&self.users_file)
+    }
+
+    fn login(&self, login: &Login) -> Result<Option<(String, Session)>, Error> {
+        let users = self.users()?;
+        let user = match users.authenticate(&login.name, &login.password) {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let token = random_token(32);
+        let session = Session {
+            name: user.name.clone(),
+            role: user.role,
+            expires: Instant::now() + SESSION_LIFETIME,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, x| x.expires > now);
+        sessions.insert(token.clone(), session.clone());
+        Ok(Some((token, session)))
+    }
+
+    /// The session for a cookie, if it has not expired and its user still exists.
+    pub fn session(&self, token: &str) -> Result<Option<Session>, Error> {
+        // Read before locking, so other requests do not wait on the file.
+        let users = self.users()?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut session = match sessions.get(token) {
+            Some(session) => session.clone(),
+            None => return Ok(None),
+        };
+        match users.get(&session.name) {
+            Some(user) if session.expires > Instant::now() => {
+                session.role = user.role;
+                Ok(Some(session))
+            }
+            _ => {
+                sessions.remove(token);
+                Ok(None)
+            }
+        }
+    }
+
+    fn logout(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+}
+
+pub fn login(login: Login, auth: Auth) -> Box<dyn Reply> {
+    match auth.login(&login) {
+        Ok(Some((token, session))) => {
+            let mut cookie = format!(
+                "{}={}; HttpOnly; SameSite=Strict; Path=/; Max-Age={}",
+                SESSION_COOKIE,
+                token,
+                SESSION_LIFETIME.as_secs()
+            );
+            if auth.secure {
+                cookie.push_str("; Secure");
+            }
+            Box::new(warp::reply::with_header(
+                warp::reply::json(&session),
+                "set-cookie",
+                cookie,
+            ))
+        }
+        Ok(None) => Box::new(warp::reply::with_status(
+            "Wrong name or password",
+            StatusCode::UNAUTHORIZED,
+        )),
+        Err(error) => Box::new(warp::reply::with_status(
+            error.0,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn logout(token: Option<String>, auth: Auth) -> Box<dyn Reply> {
+    if let Some(token) = token {
+        auth.logout(&token);
+    }
+    let cookie = format!(
+        "{}=; HttpOnly; SameSite=Strict; Path=/; Max-Age=0",
+        SESSION_COOKIE
+    );
+    Box::new(warp::reply::with_header(
+        StatusCode::NO_CONTENT,
+        "set-cookie",
+        cookie,
+    ))
+}
+
+/// Whether a user with this role may send the command.
+pub fn allows(role: Role, command: &Commands) -> bool {
+    match command {
+        Commands::Ping
+        | Commands::Whoami
+        | Commands::Capabilities
+        | Commands::RtcSession
+        | Commands::DoorGet
+        | Commands::KeypadGetLockout
+        | Commands::AuditQuery => true,
+        Commands::DoorSet | Commands::RtcAudioSession => role != Role::Viewer,
+        _ => role == Role::Admin,
+    }
+}
+
+/// `server add-user <name> <role>` and `server remove-user <name>`.
+pub fn manage_users(args: &[String]) {
+    let path = users_file();
+    let mut users = UserStore::load_from(&path);
+    let result = match args {
+        [command, name, role] if command == "add-user" => role.parse().and_then(|role| {
+            println!("Password for {}:", name);
+            let mut password = String::new();
+            io::stdin()
+                .read_line(&mut password)
+                .expect("Failed to read password");
+            users.set(name, password.trim_end_matches(&['\r', '\n'][..]), role)
+        }),
+        [command, name] if command == "remove-user" => users.remove(name),
+        _ => {
+            println!("usage: server add-user <name> <admin|resident|viewer>");
+            println!("       server remove-user <name>");
+            return;
+        }
+    };
+    match result {
+        Ok(()) => println!("Saved {}", path),
+        Err(error) => println!("{}", error.0),
+    }
+}
@@ -0,0 +1,57 @@
+use crate::web_ws::{broadcast, Clients};
+use common::audit::{AuditEvent, AuditLog};
+use common::device::audit::Subscribe;
+use common::link::Link;
+use common::message;
+use common::request::{BasicSetRequest, ID};
+use common::requests_and_responses::{Requests, Responses};
+use serde::Serialize;
+use std::env;
+use std::marker::PhantomData;
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use warp::filters::ws::Message;
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct WsEvent {
+    event: AuditEvent,
+}
+
+fn relay_events(clients: &Clients, link: &Link, runtime: &Runtime) -> Result<(), message::Error> {
+    let address = format!("{}:2000", env::var("INTERCOM_ADDRESS").unwrap());
+    let mut connection = link.connect(&address)?;
+    let request = Requests::AuditSubscribe(BasicSetRequest::<AuditLog, Subscribe>(
+        ID(0),
+        Subscribe,
+        PhantomData,
+    ));
+    connection.write(&request)?;
+    println!("Subscribed to intercom events");
+    loop {
+        match connection.read() {
+            Ok(Responses::AuditEvent(_, event)) => {
+                let json = serde_json::to_string(&WsEvent { event }).unwrap();
+                runtime.block_on(broadcast(clients.clone(), Message::text(json)));
+            }
+            Ok(_) => {}
+            Err(error) if !error.is_fatal() => println!("Skipping intercom event: {}", error),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// Pushes every intercom event to all websocket clients, reconnecting if the intercom goes away.
+pub fn mainloop(clients: Clients, link: Link) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let runtime = Runtime::new().unwrap();
+        loop {
+            if let Err(error) = relay_events(&clients, &link, &runtime) {
+                println!("Intercom event subscription lost: {}", error);
+            }
+            thread::sleep(RETRY_DELAY);
+        }
+    })
+}
@@ -0,0 +1,171 @@
+use common::device::nfc::NFCdev;
+use common::link::Link;
+use common::message;
+use common::requests_and_responses::{Requests, Responses};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Offline,
+    Timeout,
+    /// The intercom answered with an error.
+    Rejected(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Offline => write!(f, "Intercom offline"),
+            Error::Timeout => write!(f, "Intercom did not respond"),
+            Error::Rejected(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+type Pending = Mutex<HashMap<u128, oneshot::Sender<Responses>>>;
+
+struct Connection {
+    writer: Mutex<message::Connection>,
+    /// Requests waiting for a response on this connection, by ID.
+    pending: Pending,
+}
+
+/// One long-lived connection to the intercom shared by every websocket client.
+/// Requests are written as they come and responses are handed back by ID, so a slow
+/// request (like enrolling a card) does not hold up the others.
+#[derive(Clone)]
+pub struct Intercom {
+    address: String,
+    link: Link,
+    connection: Arc<Mutex<Option<Arc<Connection>>>>,
+}
+
+impl Intercom {
+    pub fn new(address: String, link: Link) -> Intercom {
+        Intercom {
+            address,
+            link,
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The current connection, reconnecting if the last one was lost.
+    fn connection(&self) -> Result<Arc<Connection>, Error> {
+        let mut current = self.connection.lock().unwrap();
+        if let Some(connection) = current.as_ref() {
+            return Ok(connection.clone());
+        }
+        let stream = self
+            .link
+            .connect(&self.address)
+            .map_err(|_| Error::Offline)?;
+        let reader = stream.try_clone().map_err(|_| Error::Offline)?;
+        let connection = Arc::new(Connection {
+            writer: Mutex::new(stream),
+            pending: Mutex::new(HashMap::new()),
+        });
+        println!("Connected to intercom at {}", self.address);
+        let intercom = self.clone();
+        let reading = connection.clone();
+        thread::spawn(move || intercom.read_responses(reader, reading));
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn read_responses(&self, mut stream: message::Connection, connection: Arc<Connection>) {
+        loop {
+            let response = match stream.read::<Responses>() {
+                Ok(response) => response,
+                Err(error) if !error.is_fatal() => {
+                    println!("Skipping intercom response: {}", error);
+                    continue;
+                }
+                Err(error) => {
+                    println!("Lost connection to intercom: {}", error);
+                    break;
+                }
+            };
+            let waiter = connection
+                .pending
+                .lock()
+                .unwrap()
+                .remove(&response.get_id().0);
+            // The request may already have timed out.
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(response);
+            }
+        }
+        self.disconnect(&connection);
+    }
+
+    /// Forgets a broken connection; everything still waiting on it fails as offline.
+    fn disconnect(&self, connection: &Arc<Connection>) {
+        let mut current = self.connection.lock().unwrap();
+        if current
+            .as_ref()
+            .map_or(false, |x| Arc::ptr_eq(x, connection))
+        {
+            *current = None;
+        }
+        connection.writer.lock().unwrap().shutdown();
+        connection.pending.lock().unwrap().clear();
+    }
+
+    fn send(
+        &self,
+        request: &Requests,
+        waiter: oneshot::Sender<Responses>,
+    ) -> Result<Arc<Connection>, Error> {
+        let connection = self.connection()?;
+        let id = request.get_id().0;
+        connection.pending.lock().unwrap().insert(id, waiter);
+        let written = connection.writer.lock().unwrap().write(request);
+        match written {
+            Ok(()) => Ok(connection),
+            // Nothing was sent, so the connection is still good.
+            Err(error @ (message::Error::FrameTooLarge(_) | message::Error::Encode(_))) => {
+                connection.pending.lock().unwrap().remove(&id);
+                Err(Error::Rejected(error.to_string()))
+            }
+            Err(error) => {
+                println!("Could not send to intercom: {}", error);
+                self.disconnect(&connection);
+                Err(Error::Offline)
+            }
+        }
+    }
+
+    fn timeout(request: &Requests) -> Duration {
+        match request {
+            // Answered once a card is tapped or enrolment gives up.
+            Requests::NFCSetID(_) => NFCdev::ENROL_TIMEOUT + REQUEST_TIMEOUT,
+            _ => REQUEST_TIMEOUT,
+        }
+    }
+
+    pub async fn request(&self, request: Requests) -> Result<Responses, Error> {
+        let id = request.get_id().0;
+        let timeout = Intercom::timeout(&request);
+        let (waiter, response) = oneshot::channel();
+        let intercom = self.clone();
+        let connection = tokio::task::spawn_blocking(move || intercom.send(&request, waiter))
+            .await
+            .unwrap()?;
+        match tokio::time::timeout(timeout, response).await {
+            Ok(Ok(Responses::Error(_, error))) => Err(Error::Rejected(error)),
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(Error::Offline),
+            Err(_) => {
+                connection.pending.lock().unwrap().remove(&id);
+                Err(Error::Timeout)
+            }
+        }
+    }
+}
@@ -1,37 +1,38 @@
+use crate::web_intercom::{Error, Intercom};
 use crate::web_requests::*;
-use common::device::door::{Door, DoorState};
-use common::device::keypad::{Code, KeyPad, PhoneNumberText};
-use common::device::nfc::{NFCdev, NFCids};
+use common::audit::{AuditLog, AuditQuery};
+use common::capabilities::{Capabilities, Inventory};
+use common::codes::{CodeEntry, NewCode};
+use common::device::audit::AuditEvents;
+use common::device::door::{DoorCommand, DoorList, Doors};
+use common::device::keypad::{ClearLockout, Code, CodeList, KeyPad, PhoneNumberText, RevokeCode};
+use common::device::nfc::{
+    CardDoors, CardEnabled, CardList, NFCdev, NewCard, RemoveCard, RenameCard,
+};
+use common::device::scheduler::{RemoveRule, RuleList, Scheduler};
 use common::device::terminal::{Terminal, Text};
-use common::message::{read_from_stream, write_to_stream};
-use common::request::*;
+use common::lockout::LockoutState;
+use common::request::{self, *};
 use common::requests_and_responses::{Requests, Responses};
-use std::env;
+use common::schedule::{NewRule, ScheduleRule};
+use serde::de::DeserializeOwned;
 use std::marker::PhantomData;
-use std::net::TcpStream;
-use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-static mut INTERCOM_ID: IDManage = IDManage { id: 0 };
+// Requests share one connection, so every ID must be unique.
+static INTERCOM_ID: AtomicU64 = AtomicU64::new(0);
 
-#[derive(Clone, Copy)]
-struct IDManage {
-    pub id: u128,
-}
-
-impl IDManage {
-    fn get_id(&mut self) -> u128 {
-        let id_num = self.id;
-        self.id += 1;
-        id_num
-    }
+fn parse<T: DeserializeOwned>(msg: &str) -> Result<T, Error> {
+    serde_json::from_str(msg)
+        .map_err(|error| Error::Rejected(format!("Invalid message: {}", error)))
 }
 
 impl Commands {
-    fn set_command(&self, request: WebRequests) -> (Requests, u128) {
+    fn set_command(&self, request: WebRequests) -> Result<(Requests, u128), Error> {
         let msg = request.get_msg().0;
-        let id = unsafe { INTERCOM_ID.get_id() };
+        let id = INTERCOM_ID.fetch_add(1, Ordering::Relaxed) as u128;
 
-        match self {
+        Ok(match self {
             Commands::TerminalGet => (
                 Requests::TerminalGetText(BasicGetRequest::<Terminal, Text>(
                     ID(id),
@@ -49,7 +50,7 @@ impl Commands {
                 id,
             ),
             Commands::DoorGet => (
-                Requests::DoorGetState(BasicGetRequest::<Door, DoorState>(
+                Requests::DoorGetState(BasicGetRequest::<Doors, DoorList>(
                     ID(id),
                     PhantomData,
                     PhantomData,
@@ -57,18 +58,18 @@ impl Commands {
                 id,
             ),
             Commands::DoorSet => {
-                let door_state = serde_json::from_str(&msg).unwrap();
+                let command = parse(&msg)?;
                 (
-                    Requests::DoorSetState(BasicSetRequest::<Door, DoorState>(
+                    Requests::DoorSetState(BasicSetRequest::<Doors, DoorCommand>(
                         ID(id),
-                        door_state,
+                        command,
                         PhantomData,
                     )),
                     id,
                 )
             }
             Commands::NFCGet => (
-                Requests::NFCGetID(BasicGetRequest::<NFCdev, NFCids>(
+                Requests::NFCGetID(BasicGetRequest::<NFCdev, CardList>(
                     ID(id),
                     PhantomData,
                     PhantomData,
@@ -92,15 +93,15 @@ impl Commands {
                 id,
             ),
             Commands::NFCSet => (
-                Requests::NFCSetID(BasicSetRequest::<NFCdev, NFCids>(
+                Requests::NFCSetID(BasicSetRequest::<NFCdev, NewCard>(
                     ID(id),
-                    NFCids(msg.to_string()),
+                    NewCard(msg.to_string()),
                     PhantomData,
                 )),
                 id,
             ),
             Commands::KeypadSetCode => {
-                let code = serde_json::from_str(&msg).unwrap();
+                let code = parse(&msg)?;
                 (
                     Requests::KeyPadSetCode(BasicSetRequest::<KeyPad, Code>(
                         ID(id),
@@ -111,7 +112,7 @@ impl Commands {
                 )
             }
             Commands::PhoneSet => {
-                let phone_number = serde_json::from_str(&msg).unwrap();
+                let phone_number = parse(&msg)?;
                 (
                     Requests::PhoneSet(BasicSetRequest::<KeyPad, PhoneNumberText>(
                         ID(id),
@@ -121,6 +122,169 @@ impl Commands {
                     id,
                 )
             }
+            Commands::KeypadListCodes => (
+                Requests::KeyPadListCodes(BasicGetRequest::<KeyPad, CodeList>(
+                    ID(id),
+                    PhantomData,
+                    PhantomData,
+                )),
+                id,
+            ),
+            Commands::KeypadAddCode => {
+                let code = parse(&msg)?;
+                (
+                    Requests::KeyPadAddCode(BasicSetRequest::<KeyPad, NewCode>(
+                        ID(id),
+                        code,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::KeypadRevokeCode => {
+                let code_id = parse(&msg)?;
+                (
+                    Requests::KeyPadRevokeCode(BasicSetRequest::<KeyPad, RevokeCode>(
+                        ID(id),
+                        RevokeCode(code_id),
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::KeypadEditCode => {
+                let code = parse(&msg)?;
+                (
+                    Requests::KeyPadEditCode(BasicSetRequest::<KeyPad, CodeEntry>(
+                        ID(id),
+                        code,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::ScheduleList => (
+                Requests::ScheduleListRules(BasicGetRequest::<Scheduler, RuleList>(
+                    ID(id),
+                    PhantomData,
+                    PhantomData,
+                )),
+                id,
+            ),
+            Commands::ScheduleAddRule => {
+                let rule = parse(&msg)?;
+                (
+                    Requests::ScheduleAddRule(BasicSetRequest::<Scheduler, NewRule>(
+                        ID(id),
+                        rule,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::ScheduleEditRule => {
+                let rule = parse(&msg)?;
+                (
+                    Requests::ScheduleEditRule(BasicSetRequest::<Scheduler, ScheduleRule>(
+                        ID(id),
+                        rule,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::ScheduleRemoveRule => {
+                let rule_id = parse(&msg)?;
+                (
+                    Requests::ScheduleRemoveRule(BasicSetRequest::<Scheduler, RemoveRule>(
+                        ID(id),
+                        RemoveRule(rule_id),
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::NFCRemoveCard => (
+                Requests::NFCRemoveCard(BasicSetRequest::<NFCdev, RemoveCard>(
+                    ID(id),
+                    RemoveCard(msg.to_string()),
+                    PhantomData,
+                )),
+                id,
+            ),
+            Commands::NFCSetCardEnabled => {
+                let card = parse(&msg)?;
+                (
+                    Requests::NFCSetCardEnabled(BasicSetRequest::<NFCdev, CardEnabled>(
+                        ID(id),
+                        card,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::NFCRenameCard => {
+                let card = parse(&msg)?;
+                (
+                    Requests::NFCRenameCard(BasicSetRequest::<NFCdev, RenameCard>(
+                        ID(id),
+                        card,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::NFCSetCardDoors => {
+                let card = parse(&msg)?;
+                (
+                    Requests::NFCSetCardDoors(BasicSetRequest::<NFCdev, CardDoors>(
+                        ID(id),
+                        card,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
+            Commands::KeypadGetLockout => (
+                Requests::KeyPadGetLockout(BasicGetRequest::<KeyPad, LockoutState>(
+                    ID(id),
+                    PhantomData,
+                    PhantomData,
+                )),
+                id,
+            ),
+            Commands::KeypadClearLockout => (
+                Requests::KeyPadClearLockout(BasicSetRequest::<KeyPad, ClearLockout>(
+                    ID(id),
+                    ClearLockout,
+                    PhantomData,
+                )),
+                id,
+            ),
+            Commands::Capabilities => (
+                Requests::Hello(BasicGetRequest::<Inventory, Capabilities>(
+                    ID(id),
+                    PhantomData,
+                    PhantomData,
+                )),
+                id,
+            ),
+            Commands::AuditQuery => {
+                let query = if msg.is_empty() {
+                    AuditQuery::default()
+                } else {
+                    parse(&msg)?
+                };
+                (
+                    Requests::AuditQuery(BasicQueryRequest::<AuditLog, AuditQuery, AuditEvents>(
+                        ID(id),
+                        query,
+                        PhantomData,
+                        PhantomData,
+                    )),
+                    id,
+                )
+            }
             _ => (
                 Requests::TerminalSetText(BasicSetRequest::<Terminal, Text>(
                     ID(id),
@@ -129,106 +293,155 @@ impl Commands {
                 )),
                 id,
             ),
-        }
+        })
     }
 }
 
-pub fn match_intercom_response(response: Responses, id: u128) -> String {
+pub fn match_intercom_response(response: Responses) -> Result<String, request::Error> {
     let mut message = String::from("");
     match response {
         Responses::TerminalGetText(msg_get) => {
-            assert_eq!(msg_get.get_id().0, id);
-            message = msg_get.get_result().unwrap().0;
+            message = msg_get.get_result()?.0;
         }
         Responses::TerminalSetText(msg_set) => {
-            assert_eq!(msg_set.get_id().0, id);
             let msg = msg_set.get_candidate().clone();
             message = msg.0;
         }
         Responses::DoorGetState(msg_get) => {
-            assert_eq!(msg_get.get_id().0, id);
-            let msg = msg_get.get_result().unwrap();
-            message = serde_json::to_string(&msg).unwrap();
+            let msg = msg_get.get_result()?;
+            message = serde_json::to_string(&msg.0).unwrap();
         }
         Responses::DoorSetState(msg_set) => {
-            assert_eq!(msg_set.get_id().0, id);
             let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
             message = serde_json::to_string(&msg).unwrap();
         }
         Responses::KeyPadGetCode(msg_get) => {
-            assert_eq!(msg_get.get_id().0, id);
-            let msg = msg_get.get_result().unwrap();
+            let msg = msg_get.get_result()?;
             message = serde_json::to_string(&msg).unwrap();
         }
         Responses::KeyPadSetCode(msg_set) => {
-            assert_eq!(msg_set.get_id().0, id);
             let msg = msg_set.get_candidate().clone();
             message = serde_json::to_string(&msg).unwrap();
         }
         Responses::PhoneGet(msg_get) => {
-            assert_eq!(msg_get.get_id().0, id);
-            let msg = msg_get.get_result().unwrap();
+            let msg = msg_get.get_result()?;
             message = serde_json::to_string(&msg).unwrap();
         }
         Responses::PhoneSet(msg_set) => {
-            assert_eq!(msg_set.get_id().0, id);
             let msg = msg_set.get_candidate().clone();
             message = serde_json::to_string(&msg).unwrap();
         }
+        Responses::KeyPadListCodes(msg_get) => {
+            let msg = msg_get.get_result()?;
+            message = serde_json::to_string(&msg.0).unwrap();
+        }
+        Responses::KeyPadAddCode(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        Responses::KeyPadRevokeCode(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg.0).unwrap();
+        }
+        Responses::KeyPadEditCode(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
         Responses::NFCGetID(_) => {
             if let Responses::NFCGetID(msg_get) = response {
-                assert_eq!(msg_get.get_id().0, id);
-                let msg = msg_get.get_result().unwrap();
-                message = serde_json::to_string(&msg).unwrap();
+                let msg = msg_get.get_result()?;
+                message = serde_json::to_string(&msg.0).unwrap();
             }
         }
         Responses::NFCSetID(_) => {
             if let Responses::NFCSetID(msg_set) = response {
-                assert_eq!(msg_set.get_id().0, id);
                 let msg = msg_set.get_candidate().clone();
+                msg_set.get_result()?;
                 message = msg.0;
             }
         }
+        Responses::NFCRemoveCard(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = msg.0;
+        }
+        Responses::NFCSetCardEnabled(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        Responses::NFCRenameCard(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        Responses::NFCSetCardDoors(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        Responses::KeyPadGetLockout(msg_get) => {
+            let msg = msg_get.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        Responses::KeyPadClearLockout(msg_set) => {
+            message = serde_json::to_string(&msg_set.get_result().is_ok()).unwrap();
+        }
+        // Only sent on the event subscription, see `web_events`.
+        Responses::AuditSubscribe(_) | Responses::AuditEvent(..) => {}
+        Responses::Error(_, error) => return Err(request::Error(error)),
+        Responses::ScheduleListRules(msg_get) => {
+            let msg = msg_get.get_result()?;
+            message = serde_json::to_string(&msg.0).unwrap();
+        }
+        Responses::ScheduleAddRule(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        Responses::ScheduleEditRule(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        Responses::ScheduleRemoveRule(msg_set) => {
+            let msg = msg_set.get_candidate().clone();
+            msg_set.get_result()?;
+            message = serde_json::to_string(&msg.0).unwrap();
+        }
+        Responses::AuditQuery(msg_query) => {
+            let msg = msg_query.get_result()?;
+            message = serde_json::to_string(&msg.0).unwrap();
+        }
+        Responses::Hello(msg_get) => {
+            let msg = msg_get.get_result()?;
+            message = serde_json::to_string(&msg).unwrap();
+        }
+        // The web panel cannot stop the intercom; this only answers other clients.
+        Responses::Shutdown(msg_set) => {
+            msg_set.get_result()?;
+        }
     }
-    message
+    Ok(message)
 }
 
 // call from client_msg() in main
 // relays message from web to intercom
-pub async fn listen_for_web(request: WebSocketRequest) -> String {
+pub async fn listen_for_web(
+    request: WebSocketRequest,
+    intercom: &Intercom,
+) -> Result<String, Error> {
     let new_request = WebRequests(DeviceCommand(request.command), Message(request.message));
 
     let request_command = new_request.clone();
-    // let command = Commands::match_command(new_request.get_device_command().0);
     let command = request_command.get_device_command().0;
-    let (setrequest, id): (Requests, u128) = Commands::set_command(&command, request_command);
+    let (setrequest, _): (Requests, u128) = Commands::set_command(&command, request_command)?;
 
     // send command to intercom and get reply
-    let response = send_command_to_intercom(setrequest).await;
-    let reply_to_web = match_intercom_response(response, id);
-
-    reply_to_web
-}
-
-async fn send_command_to_intercom(request: Requests) -> Responses {
-    let message = tokio::task::spawn(async move {
-        match TcpStream::connect(format!("{}:2000", env::var("INTERCOM_ADDRESS").unwrap())) {
-            Ok(mut intercom_stream) => {
-                // send command to intercom
-                write_to_stream(&mut intercom_stream, &request);
-
-                // get reply
-                let reply: Responses = read_from_stream(&mut intercom_stream).unwrap();
-                reply
-            }
-            Err(e) => {
-                println!("Failed to connect: {}", e);
-                process::exit(1);
-            }
-        }
-    })
-    .await
-    .unwrap();
-
-    message
+    let response = intercom.request(setrequest).await?;
+    match_intercom_response(response).map_err(|error| Error::Rejected(error.0))
 }
@@ -37,9 +37,26 @@ pub enum Commands {
     TerminalSet,
     NFCGet,
     NFCSet,
+    NFCRemoveCard,
+    NFCSetCardEnabled,
+    NFCRenameCard,
+    NFCSetCardDoors,
     KeypadSetCode,
     KeypadGetCode,
     PhoneGet,
     PhoneSet,
+    KeypadListCodes,
+    KeypadAddCode,
+    KeypadRevokeCode,
+    KeypadEditCode,
+    KeypadGetLockout,
+    KeypadClearLockout,
+    AuditQuery,
+    ScheduleList,
+    ScheduleAddRule,
+    ScheduleEditRule,
+    ScheduleRemoveRule,
+    Whoami,
+    Capabilities,
     Unknown,
 }
@@ -0,0 +1,264 @@
+//! Append-only log of access events, stored as JSON lines with size-based rotation.
+
+use crate::request::Error;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    Unlock,
+    Lock,
+    FailedCode,
+    UnknownCard,
+    DoorbellRing,
+    KeypadLockout,
+    /// The door was put in lockdown from the web.
+    Lockdown,
+    /// A duress sequence opened the door.
+    Duress,
+    OverrideRequest,
+    /// The door sensor saw the door left open after it locked.
+    DoorAjar,
+    /// The door sensor saw the door opened while it was locked.
+    ForcedEntry,
+    /// Someone pressed the exit button.
+    Exit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    Keypad,
+    Card,
+    Web,
+    Door,
+    Schedule,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub time: DateTime<Local>,
+    pub kind: EventKind,
+    pub source: Source,
+    /// Owner of the code or name of the card, when one was used.
+    pub credential: Option<String>,
+    /// Which door it happened at, for events that concern one.
+    #[serde(default)]
+    pub door: Option<String>,
+    pub result: Result<(), String>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        kind: EventKind,
+        source: Source,
+        credential: Option<String>,
+        result: Result<(), String>,
+    ) -> AuditEvent {
+        AuditEvent {
+            time: Local::now(),
+            kind,
+            source,
+            credential,
+            door: None,
+            result,
+        }
+    }
+
+    pub fn at_door(mut self, door: &str) -> AuditEvent {
+        self.door = Some(door.to_string());
+        self
+    }
+}
+
+/// Filter for the audit log. Unset fields match everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuditQuery {
+    pub from: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub kinds: Vec<EventKind>,
+    pub sources: Vec<Source>,
+    pub credential: Option<String>,
+    pub door: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    const DEFAULT_LIMIT: usize = 100;
+
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.from.map_or(true, |from| event.time >= from)
+            && self.until.map_or(true, |until| event.time < until)
+            && (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && (self.sources.is_empty() || self.sources.contains(&event.source))
+            && self.credential.as_ref().map_or(true, |credential| {
+                event.credential.as_ref() == Some(credential)
+            })
+            && self
+                .door
+                .as_ref()
+                .map_or(true, |door| event.door.as_ref() == Some(door))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub path: String,
+    /// The log is rotated once it grows past this size.
+    pub max_bytes: u64,
+    /// Number of rotated files kept as `<path>.1` (newest) to `<path>.<keep>`.
+    pub keep: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> AuditConfig {
+        AuditConfig {
+            path: "audit.log".to_string(),
+            max_bytes: 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    config: AuditConfig,
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig) -> AuditLog {
+        AuditLog { config }
+    }
+
+    fn rotated(&self, index: u32) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.config.path, index))
+    }
+
+    pub fn append(&self, event: &AuditEvent) -> Result<(), Error> {
+        let line = serde_json::to_string(event).unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .map_err(|_| Error("Could not open audit log".to_string()))?;
+        writeln!(file, "{}", line).map_err(|_| Error("Could not write audit log".to_string()))?;
+        let size = file.metadata().map(|x| x.len()).unwrap_or(0);
+        if size >= self.config.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<(), Error> {
+        let error = |_| Error("Could not rotate audit log".to_string());
+        if self.config.keep == 0 {
+            return fs::remove_file(&self.config.path).map_err(error);
+        }
+        for index in (1..self.config.keep).rev() {
+            if self.rotated(index).exists() {
+                fs::rename(self.rotated(index), self.rotated(index + 1)).map_err(error)?;
+            }
+        }
+        fs::rename(&self.config.path, self.rotated(1)).map_err(error)
+    }
+
+    /// Matching events across the current and rotated files, newest first.
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditEvent> {
+        let mut files: Vec<PathBuf> = (1..=self.config.keep)
+            .rev()
+            .map(|x| self.rotated(x))
+            .collect();
+        files.push(PathBuf::from(&self.config.path));
+        let mut events: Vec<AuditEvent> = files
+            .iter()
+            .filter_map(|x| fs::read_to_string(x).ok())
+            .flat_map(|x| {
+                x.lines()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .collect::<Vec<AuditEvent>>()
+            })
+            .filter(|x| query.matches(x))
+            .collect();
+        events.reverse();
+        events.truncate(query.limit.unwrap_or(AuditQuery::DEFAULT_LIMIT));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_file;
+
+    fn log(name: &str, max_bytes: u64, keep: u32) -> AuditLog {
+        let path = temp_file(&format!("audit-{}.log", name));
+        let log = AuditLog::new(AuditConfig {
+            path: path.to_str().unwrap().to_string(),
+            max_bytes,
+            keep,
+        });
+        for index in 1..=keep {
+            let _ = fs::remove_file(log.rotated(index));
+        }
+        log
+    }
+
+    fn event(kind: EventKind, credential: &str) -> AuditEvent {
+        AuditEvent::new(kind, Source::Keypad, Some(credential.to_string()), Ok(()))
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let log = log("filter", 1024 * 1024, 1);
+        log.append(&event(EventKind::Unlock, "Alice")).unwrap();
+        log.append(&event(EventKind::FailedCode, "Bob")).unwrap();
+        log.append(&event(EventKind::Unlock, "Bob")).unwrap();
+
+        let all = log.query(&AuditQuery::default());
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].credential.as_deref(), Some("Bob"));
+
+        let unlocks = log.query(&AuditQuery {
+            kinds: vec![EventKind::Unlock],
+            credential: Some("Bob".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(unlocks.len(), 1);
+
+        log.append(&event(EventKind::Unlock, "Carol").at_door("Garage"))
+            .unwrap();
+        let garage = log.query(&AuditQuery {
+            door: Some("Garage".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(garage.len(), 1);
+        assert_eq!(garage[0].credential.as_deref(), Some("Carol"));
+
+        let later = log.query(&AuditQuery {
+            from: Some(Local::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        });
+        assert!(later.is_empty());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let log = log("rotate", 1, 2);
+        for name in ["a", "b", "c", "d"] {
+            log.append(&event(EventKind::Unlock, name)).unwrap();
+        }
+        assert!(log.rotated(1).exists());
+        assert!(log.rotated(2).exists());
+        assert!(!log.rotated(3).exists());
+        let events = log.query(&AuditQuery::default());
+        let names: Vec<_> = events
+            .iter()
+            .map(|x| x.credential.clone().unwrap())
+            .collect();
+        assert_eq!(names, vec!["d", "c"]);
+    }
+}
@@ -1,16 +1,26 @@
+use crate::audit::{AuditConfig, AuditLog};
+use crate::capabilities::Inventory;
+use crate::cards::CardStore;
+use crate::codes::CodeStore;
+use crate::device::audit;
 use crate::device::door;
+use crate::device::feedback;
 use crate::device::keypad;
 use crate::device::nfc;
+use crate::device::scheduler;
 use crate::device::terminal;
-use crate::dispatch;
+use crate::feedback::FeedbackConfig;
+use crate::gpio;
+use crate::keyscan::KeypadConfig;
+use crate::lockout::{Lockout, LockoutConfig};
 use crate::message;
 use crate::message::ThreadSender;
-use crate::requests_and_responses::InternalThreadRequest;
-use crate::requests_and_responses::ThreadRequest;
+use crate::notify::Notifier;
+use crate::requests_and_responses::DeviceRequest;
+use crate::schedule::{GuestCodes, ScheduleStore};
 use std::marker::PhantomData;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Instant;
-use sysfs_gpio::{Direction, Pin};
 
 pub trait Build {
     type Result;
@@ -21,7 +31,7 @@ pub trait Build {
 impl Build for terminal::TerminalDevice {
     type Input = ();
     type Result = (
-        message::ThreadSender<ThreadRequest, terminal::Terminal>,
+        message::ThreadSender<DeviceRequest, terminal::Terminal>,
         terminal::TerminalDevice,
     );
     fn build(_: ()) -> Self::Result {
@@ -35,104 +45,276 @@ impl Build for terminal::TerminalDevice {
     }
 }
 
+impl Build for audit::AuditDevice {
+    type Input = AuditConfig;
+    type Result = (audit::AuditSender, audit::AuditDevice);
+    fn build(config: Self::Input) -> Self::Result {
+        let (sender, receiver) = mpsc::channel();
+        let thread_receiver = message::ThreadReceiver(receiver);
+        let tcp_sender = message::TcpSender(None, PhantomData);
+        let audit_device =
+            audit::AuditDevice::new(tcp_sender, thread_receiver, AuditLog::new(config));
+        let audit_channel = message::ThreadSender(sender, PhantomData);
+        (audit_channel, audit_device)
+    }
+}
+
+impl Build for scheduler::SchedulerDevice {
+    type Input = (ThreadSender<DeviceRequest, door::Doors>, GuestCodes);
+    type Result = (
+        message::ThreadSender<DeviceRequest, scheduler::Scheduler>,
+        scheduler::SchedulerDevice,
+    );
+    fn build((door_sender, guest_codes): Self::Input) -> Self::Result {
+        let (sender, receiver) = mpsc::channel();
+        let thread_receiver = message::ThreadReceiver(receiver);
+        let scheduler = scheduler::Scheduler::new(ScheduleStore::load(), guest_codes);
+        let tcp_sender = message::TcpSender(None, PhantomData);
+        let scheduler_device =
+            scheduler::SchedulerDevice::new(tcp_sender, thread_receiver, scheduler, door_sender);
+        let scheduler_channel = message::ThreadSender(sender, PhantomData);
+        (scheduler_channel, scheduler_device)
+    }
+}
+
+impl Build for feedback::FeedbackDevice {
+    type Input = (gpio::Backend, FeedbackConfig);
+    type Result = (feedback::FeedbackSender, feedback::FeedbackDevice);
+    fn build((gpio, config): Self::Input) -> Self::Result {
+        let (sender, receiver) = mpsc::channel();
+        let thread_receiver = message::ThreadReceiver(receiver);
+        let open = |number: Option<u64>| {
+            let pin = match gpio.pin(number?) {
+                Ok(pin) => pin,
+                Err(error) => panic!("Unable to open feedback GPIO pin: {}", error),
+            };
+            match pin.export() {
+                Ok(()) => (),
+                Err(error) => panic!("Got error when exported GPIO pin: {}", error),
+            };
+            match pin.set_direction(gpio::Direction::Out) {
+                Ok(()) => (),
+                Err(error) => panic!("Unable to set feedback GPIO direction: {}", error),
+            };
+            Some(pin)
+        };
+        let feedback = feedback::Feedback::new(
+            open(config.buzzer),
+            open(config.red_led),
+            open(config.green_led),
+        );
+        let tcp_sender = message::TcpSender(None, PhantomData);
+        let feedback_device = feedback::FeedbackDevice::new(tcp_sender, thread_receiver, feedback);
+        let feedback_channel = message::ThreadSender(sender, PhantomData);
+        (feedback_channel, feedback_device)
+    }
+}
+
 impl Build for nfc::NFCDevice {
-    type Input = ThreadSender<InternalThreadRequest, door::Door>;
+    type Input = (
+        ThreadSender<DeviceRequest, door::Doors>,
+        audit::AuditSender,
+        feedback::FeedbackSender,
+        Arc<Mutex<dyn nfc::CardReader>>,
+        Inventory,
+        door::Lockdown,
+        Vec<String>,
+        Vec<String>,
+    );
     type Result = (
-        message::ThreadSender<ThreadRequest, nfc::NFCdev>,
+        message::ThreadSender<DeviceRequest, nfc::NFCdev>,
         nfc::NFCDevice,
     );
-    fn build(nfc_to_door_sender: Self::Input) -> Self::Result {
+    fn build(
+        (
+            nfc_to_door_sender,
+            audit_sender,
+            feedback_sender,
+            reader,
+            inventory,
+            lockdown,
+            doors,
+            door_names,
+        ): Self::Input,
+    ) -> Self::Result {
         let (sender, receiver) = mpsc::channel();
         let thread_receiver = message::ThreadReceiver(receiver);
-        let nfc = nfc::NFCdev::new();
+        let nfc = nfc::NFCdev::new(CardStore::load(), reader, door_names);
         let tcp_sender = message::TcpSender(None, PhantomData);
         let nfc_channel = message::ThreadSender(sender, PhantomData);
-        let nfc_device = nfc::NFCDevice::new(nfc_to_door_sender, tcp_sender, thread_receiver, nfc);
+        let nfc_device = nfc::NFCDevice::new(
+            nfc_to_door_sender,
+            audit_sender,
+            feedback_sender,
+            tcp_sender,
+            thread_receiver,
+            nfc,
+            inventory,
+            lockdown,
+            doors,
+        );
         (nfc_channel, nfc_device)
     }
 }
 
-impl Build for dispatch::Dispatcher {
-    type Result = dispatch::Dispatcher;
-    type Input = (
-        message::ThreadSender<ThreadRequest, terminal::Terminal>,
-        message::ThreadSender<ThreadRequest, door::Door>,
-        message::ThreadSender<ThreadRequest, keypad::KeyPad>,
-        message::ThreadSender<ThreadRequest, nfc::NFCdev>,
-    );
-    fn build(input: Self::Input) -> Self::Result {
-        dispatch::Dispatcher::new(input.0, input.1, input.2, input.3)
+impl Build for door::Door {
+    type Input = (gpio::Backend, door::DoorConfig, door::Lockdown);
+    type Result = door::Door;
+    fn build((gpio, config, lockdown): Self::Input) -> Self::Result {
+        let pin = match gpio.pin(config.pin) {
+            Ok(pin) => pin,
+            Err(error) => panic!("Unable to open {} door GPIO pin: {}", config.name, error),
+        };
+        match pin.export() {
+            Ok(()) => (),
+            Err(error) => panic!("Got error when exported GPIO pin: {}", error),
+        };
+        match pin.set_active_low(config.active_low) {
+            Ok(()) => (),
+            Err(error) => panic!("Unable to set door GPIO active level: {}", error),
+        };
+        match pin.set_direction(gpio::Direction::Out) {
+            Ok(()) => (),
+            Err(error) => panic!("Unable to set door GPIO direction: {}", error),
+        };
+        // The sensor and exit button only wake the door device when they change.
+        let input = |number: u64, active_low: bool| {
+            let input = match gpio.pin(number) {
+                Ok(pin) => pin,
+                Err(error) => panic!("Unable to open door input GPIO pin: {}", error),
+            };
+            let setup = input
+                .export()
+                .and_then(|()| input.set_active_low(active_low))
+                .and_then(|()| input.set_direction(gpio::Direction::In))
+                .and_then(|()| input.set_edge(gpio::Edge::Both));
+            if let Err(error) = setup {
+                panic!("Unable to set up door input GPIO pin: {}", error);
+            }
+            input
+        };
+        let sensor = config.sensor.map(|x| input(x, config.sensor_active_low));
+        let exit_button = config
+            .exit_button
+            .map(|x| input(x, config.exit_button_active_low));
+        door::Door::new(pin, sensor, exit_button, &config, lockdown)
     }
 }
 
 impl Build for door::DoorDevice {
-    type Input = ();
+    type Input = (
+        gpio::Backend,
+        Vec<door::DoorConfig>,
+        audit::AuditSender,
+        door::Lockdown,
+        Notifier,
+    );
     type Result = (
-        message::ThreadSender<ThreadRequest, door::Door>,
-        message::ThreadSender<InternalThreadRequest, door::Door>,
+        message::ThreadSender<DeviceRequest, door::Doors>,
         door::DoorDevice,
     );
-    fn build(_: ()) -> Self::Result {
+    fn build((gpio, configs, audit_sender, lockdown, notifier): Self::Input) -> Self::Result {
         let (sender, receiver) = mpsc::channel();
-        let (internal_sender, internal_receiver) = mpsc::channel();
         let thread_receiver = message::ThreadReceiver(receiver);
-        let pin = Pin::new(door::PIN_NUMBER);
-        if !pin.is_exported() {
-            match pin.export() {
-                Ok(()) => (),
-                Err(error) => panic!("Got error when exported GPIO pin: {}", error),
-            };
-        }
-        match pin.set_direction(Direction::Out) {
-            Ok(()) => (),
-            Err(error) => panic!("Unable to set door GPIO direction: {}", error),
-        };
-        let door = door::Door::new(door::DoorState::Lock, pin, Instant::now());
+        let doors = configs
+            .into_iter()
+            .map(|config| door::Door::build((gpio.clone(), config, lockdown.clone())))
+            .collect();
+        let doors = door::Doors::new(doors);
+        doors.watch(sender.clone());
         let tcp_sender = message::TcpSender(None, PhantomData);
-        let internal_door_receiver = message::ThreadReceiver(internal_receiver);
         let door_device =
-            door::DoorDevice::new(tcp_sender, thread_receiver, door, internal_door_receiver);
+            door::DoorDevice::new(tcp_sender, thread_receiver, doors, audit_sender, notifier);
         let door_channel = message::ThreadSender(sender, PhantomData);
-        let internal_door_sender = message::ThreadSender(internal_sender, PhantomData);
-        (door_channel, internal_door_sender, door_device)
+        (door_channel, door_device)
     }
 }
 
 impl Build for keypad::KeyPadMatrix {
-    type Input = ();
+    type Input = (gpio::Backend, KeypadConfig);
     type Result = keypad::KeyPadMatrix;
-    fn build(_: ()) -> Self::Result {
-        let rows: [Pin; 4] = keypad::KeyPadMatrix::ROW_PINS.map(|x| Pin::new(x));
-        let cols: [Pin; 4] = keypad::KeyPadMatrix::COL_PINS.map(|x| Pin::new(x));
+    fn build((gpio, config): Self::Input) -> Self::Result {
+        if let Err(error) = config.validate() {
+            panic!("Invalid keypad config: {}", error.0);
+        }
+        let open = |x: &u64| match gpio.pin(*x) {
+            Ok(pin) => pin,
+            Err(error) => panic!("Unable to open keypad GPIO pin: {}", error),
+        };
+        let rows: Vec<gpio::Pin> = config.rows.iter().map(open).collect();
+        let cols: Vec<gpio::Pin> = config.cols.iter().map(open).collect();
         rows.iter().chain(cols.iter()).for_each(|x| {
-            if !x.is_exported() {
-                match x.export() {
-                    Ok(()) => (),
-                    Err(error) => panic!("Got error when exported GPIO pin: {}", error),
-                };
-            }
+            match x.export() {
+                Ok(()) => (),
+                Err(error) => panic!("Got error when exported GPIO pin: {}", error),
+            };
         });
-        keypad::KeyPadMatrix::new(rows, cols)
+        keypad::KeyPadMatrix::new(rows, cols, config.layout())
     }
 }
 
 impl Build for keypad::KeyPadDevice {
-    type Input = ThreadSender<InternalThreadRequest, door::Door>;
+    type Input = (
+        ThreadSender<DeviceRequest, door::Doors>,
+        audit::AuditSender,
+        feedback::FeedbackSender,
+        gpio::Backend,
+        LockoutConfig,
+        KeypadConfig,
+        door::Lockdown,
+        Notifier,
+        GuestCodes,
+        Vec<String>,
+        Vec<String>,
+    );
     type Result = (
-        message::ThreadSender<ThreadRequest, keypad::KeyPad>,
+        message::ThreadSender<DeviceRequest, keypad::KeyPad>,
         keypad::KeyPadDevice,
     );
-    fn build(keypad_to_door_sender: Self::Input) -> Self::Result {
+    fn build(
+        (
+            keypad_to_door_sender,
+            audit_sender,
+            feedback_sender,
+            gpio,
+            lockout,
+            config,
+            lockdown,
+            notifier,
+            guest_codes,
+            doors,
+            door_names,
+        ): Self::Input,
+    ) -> Self::Result {
         let (sender, receiver) = mpsc::channel();
         let thread_receiver = message::ThreadReceiver(receiver);
-        let code = keypad::Code::new();
-        let keypad_matrix = keypad::KeyPadMatrix::build(());
+        let codes = CodeStore::load();
+        let keypad_matrix = keypad::KeyPadMatrix::build((gpio, config.clone()));
+        keypad_matrix.watch(sender.clone());
         let candidate_key = keypad::CandidateKey::new(keypad::CandidateKey::INITIAL_CAPACITY);
-        let keypad = keypad::KeyPad::new(code, keypad_matrix, candidate_key, Instant::now());
+        let keypad = keypad::KeyPad::new(
+            codes,
+            keypad_matrix,
+            &config,
+            candidate_key,
+            Instant::now(),
+            Lockout::new(lockout),
+            notifier,
+            guest_codes,
+            door_names,
+        );
         let tcp_sender = message::TcpSender(None, PhantomData);
         let keypad_channel = message::ThreadSender(sender, PhantomData);
-        let keypad_device =
-            keypad::KeyPadDevice::new(keypad_to_door_sender, tcp_sender, thread_receiver, keypad);
+        let keypad_device = keypad::KeyPadDevice::new(
+            keypad_to_door_sender,
+            audit_sender,
+            feedback_sender,
+            tcp_sender,
+            thread_receiver,
+            keypad,
+            lockdown,
+            doors,
+        );
         (keypad_channel, keypad_device)
     }
 }
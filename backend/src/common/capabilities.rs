@@ -0,0 +1,107 @@
+//! What the intercom is running and which of its devices are attached and working.
+
+use crate::message::PROTOCOL_VERSION;
+use crate::request::{Error, Get};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// The camera streams to the web server on its own; the intercom only checks it is plugged in.
+pub const CAMERA_DEVICE: &str = "/dev/video0";
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceKind {
+    Door,
+    Keypad,
+    Nfc,
+    Terminal,
+    Camera,
+    /// The buzzer and status LEDs.
+    Feedback,
+    /// The audit log; not hardware, but a device thread like the others.
+    Audit,
+    /// Applies the door schedule; not hardware either.
+    Scheduler,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Ok,
+    /// Running, but the hardware is not answering.
+    Faulty(String),
+    /// Crashed with this error and waiting to be restarted.
+    Restarting(String),
+    /// Shut down along with the intercom.
+    Stopped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceStatus {
+    pub kind: DeviceKind,
+    pub health: Health,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Set from `INTERCOM_COMMIT` when the intercom was compiled.
+    pub commit: Option<String>,
+    pub arch: String,
+}
+
+impl BuildInfo {
+    pub fn current() -> BuildInfo {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            commit: option_env!("INTERCOM_COMMIT").map(str::to_string),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Capabilities {
+    /// Version agreed on for the connection the request came in on.
+    pub protocol_version: u32,
+    pub build: BuildInfo,
+    /// Only the devices that are attached.
+    pub devices: Vec<DeviceStatus>,
+}
+
+/// The attached devices and their health, shared with the device threads that report it.
+#[derive(Clone, Default)]
+pub struct Inventory(Arc<Mutex<BTreeMap<DeviceKind, Health>>>);
+
+impl Inventory {
+    pub fn set(&self, kind: DeviceKind, health: Health) {
+        let mut devices = match self.0.lock() {
+            Ok(devices) => devices,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        devices.insert(kind, health);
+    }
+
+    pub fn devices(&self) -> Vec<DeviceStatus> {
+        let devices = match self.0.lock() {
+            Ok(devices) => devices,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        devices
+            .iter()
+            .map(|(kind, health)| DeviceStatus {
+                kind: *kind,
+                health: health.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Get<Inventory, Capabilities> for Inventory {
+    fn get(&self) -> Result<Capabilities, Error> {
+        Ok(Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            build: BuildInfo::current(),
+            devices: self.devices(),
+        })
+    }
+}
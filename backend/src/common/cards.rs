@@ -0,0 +1,150 @@
+//! Whitelist of NFC cards allowed to open the door, persisted as JSON.
+
+use crate::request::Error;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn uid_to_hex(uid: &[u8]) -> String {
+    uid.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CardEntry {
+    pub uid: String,
+    pub name: String,
+    pub enrolled: DateTime<Local>,
+    pub enabled: bool,
+    /// Doors the card opens, by name. Empty means every door.
+    #[serde(default)]
+    pub doors: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct CardStore {
+    path: PathBuf,
+    cards: Vec<CardEntry>,
+}
+
+impl CardStore {
+    const FILE: &'static str = "cards.json";
+
+    pub fn load() -> CardStore {
+        CardStore::load_from(CardStore::FILE)
+    }
+
+    pub fn load_from(path: impl AsRef<Path>) -> CardStore {
+        let path = path.as_ref().to_path_buf();
+        let cards = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .unwrap_or_else(|error| panic!("Invalid card store {:?}: {}", path, error)),
+            Err(_) => Vec::new(),
+        };
+        CardStore { path, cards }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(&self.cards).unwrap();
+        fs::write(&self.path, json).map_err(|_| Error("Could not save cards".to_string()))
+    }
+
+    pub fn list(&self) -> &[CardEntry] {
+        &self.cards
+    }
+
+    /// Returns the card if it is enrolled and enabled.
+    pub fn check(&self, uid: &[u8]) -> Option<&CardEntry> {
+        let uid = uid_to_hex(uid);
+        self.cards.iter().find(|x| x.uid == uid && x.enabled)
+    }
+
+    pub fn enrol(&mut self, uid: &[u8], name: &str) -> Result<(), Error> {
+        let uid = uid_to_hex(uid);
+        if self.cards.iter().any(|x| x.uid == uid) {
+            return Err(Error("Card already enrolled".to_string()));
+        }
+        self.cards.push(CardEntry {
+            uid,
+            name: name.to_string(),
+            enrolled: Local::now(),
+            enabled: true,
+            doors: Vec::new(),
+        });
+        self.save()
+    }
+
+    fn find_mut(&mut self, uid: &str) -> Result<&mut CardEntry, Error> {
+        self.cards
+            .iter_mut()
+            .find(|x| x.uid == uid)
+            .ok_or_else(|| Error("No such card".to_string()))
+    }
+
+    pub fn rename(&mut self, uid: &str, name: &str) -> Result<(), Error> {
+        self.find_mut(uid)?.name = name.to_string();
+        self.save()
+    }
+
+    pub fn set_enabled(&mut self, uid: &str, enabled: bool) -> Result<(), Error> {
+        self.find_mut(uid)?.enabled = enabled;
+        self.save()
+    }
+
+    pub fn set_doors(&mut self, uid: &str, doors: &[String]) -> Result<(), Error> {
+        self.find_mut(uid)?.doors = doors.to_vec();
+        self.save()
+    }
+
+    pub fn remove(&mut self, uid: &str) -> Result<(), Error> {
+        let length = self.cards.len();
+        self.cards.retain(|x| x.uid != uid);
+        if self.cards.len() == length {
+            return Err(Error("No such card".to_string()));
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_file;
+
+    fn store(name: &str) -> CardStore {
+        CardStore::load_from(temp_file(&format!("cards-{}.json", name)))
+    }
+
+    #[test]
+    fn test_enrol_and_check() {
+        let mut store = store("enrol");
+        store.enrol(&[0x04, 0xa1, 0xb2], "Alice").unwrap();
+        assert_eq!(store.check(&[0x04, 0xa1, 0xb2]).unwrap().name, "Alice");
+        assert!(store.check(&[0x04, 0xa1]).is_none());
+        assert!(store.enrol(&[0x04, 0xa1, 0xb2], "Bob").is_err());
+    }
+
+    #[test]
+    fn test_disable_rename_remove() {
+        let mut store = store("edit");
+        store.enrol(&[0x0a], "Alice").unwrap();
+        store.set_enabled("0a", false).unwrap();
+        assert!(store.check(&[0x0a]).is_none());
+        store.set_enabled("0a", true).unwrap();
+        store.rename("0a", "Bob").unwrap();
+        assert_eq!(store.check(&[0x0a]).unwrap().name, "Bob");
+        store.set_doors("0a", &["Garage".to_string()]).unwrap();
+        assert_eq!(store.check(&[0x0a]).unwrap().doors, vec!["Garage"]);
+        store.remove("0a").unwrap();
+        assert!(store.check(&[0x0a]).is_none());
+        assert!(store.remove("0a").is_err());
+    }
+
+    #[test]
+    fn test_persisted() {
+        let mut store = store("persist");
+        store.enrol(&[0x01, 0x02], "Alice").unwrap();
+        let reloaded = CardStore::load_from(&store.path);
+        assert_eq!(reloaded.list(), store.list());
+    }
+}
@@ -0,0 +1,319 @@
+//! Keypad codes with owners, validity windows and usage limits, persisted as JSON.
+
+use crate::request::Error;
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const VALID_CHARS: [char; 14] = [
+    'A', 'B', 'C', 'D', '1', '2', '3', '4', '5', '6', '7', '8', '9', '0',
+];
+
+pub fn validate_code(code: &str) -> Result<(), Error> {
+    if !code.is_empty() && code.chars().all(|x| VALID_CHARS.contains(&x)) {
+        return Ok(());
+    }
+    Err(Error("Invalid code format allocation".to_string()))
+}
+
+/// Time of day range; `end` before `start` wraps past midnight.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Restrictions shared by new and existing codes. Unset fields do not restrict.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct CodeRules {
+    pub valid_from: Option<DateTime<Local>>,
+    pub valid_until: Option<DateTime<Local>>,
+    pub weekdays: Vec<Weekday>,
+    pub hours: Option<TimeWindow>,
+    pub max_uses: Option<u32>,
+    /// Guest codes can be turned off by the schedule.
+    pub guest: bool,
+    /// Doors the code opens, by name. Empty means every door.
+    pub doors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CodeEntry {
+    pub id: u32,
+    pub owner: String,
+    pub code: String,
+    #[serde(default)]
+    pub rules: CodeRules,
+    #[serde(default)]
+    pub uses: u32,
+}
+
+impl CodeEntry {
+    pub fn is_valid_at(&self, now: DateTime<Local>) -> bool {
+        let rules = &self.rules;
+        if rules.valid_from.map_or(false, |from| now < from) {
+            return false;
+        }
+        if rules.valid_until.map_or(false, |until| now >= until) {
+            return false;
+        }
+        if !rules.weekdays.is_empty() && !rules.weekdays.contains(&now.weekday()) {
+            return false;
+        }
+        if rules
+            .hours
+            .map_or(false, |hours| !hours.contains(now.time()))
+        {
+            return false;
+        }
+        rules.max_uses.map_or(true, |max_uses| self.uses < max_uses)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewCode {
+    pub owner: String,
+    pub code: String,
+    #[serde(default)]
+    pub rules: CodeRules,
+}
+
+#[derive(Clone)]
+pub struct CodeStore {
+    path: PathBuf,
+    codes: Vec<CodeEntry>,
+}
+
+impl CodeStore {
+    const FILE: &'static str = "codes.json";
+    const LEGACY_FILE: &'static str = "code";
+    const LEGACY_OWNER: &'static str = "Default";
+
+    pub fn load() -> CodeStore {
+        let mut store = CodeStore::load_from(CodeStore::FILE);
+        if !Path::new(CodeStore::FILE).exists() {
+            if let Ok(code) = fs::read_to_string(CodeStore::LEGACY_FILE) {
+                let code = NewCode {
+                    owner: CodeStore::LEGACY_OWNER.to_string(),
+                    code: code.trim().to_string(),
+                    rules: CodeRules::default(),
+                };
+                if let Err(error) = store.add(&code) {
+                    println!("Not migrating code file: {:?}", error);
+                }
+            }
+        }
+        store
+    }
+
+    pub fn load_from(path: impl AsRef<Path>) -> CodeStore {
+        let path = path.as_ref().to_path_buf();
+        let codes = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .unwrap_or_else(|error| panic!("Invalid code store {:?}: {}", path, error)),
+            Err(_) => Vec::new(),
+        };
+        CodeStore { path, codes }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(&self.codes).unwrap();
+        fs::write(&self.path, json).map_err(|_| Error("Could not save codes".to_string()))
+    }
+
+    pub fn list(&self) -> &[CodeEntry] {
+        &self.codes
+    }
+
+    /// Returns the entry the candidate matches if it may be used right now, counting
+    /// the use against its limit.
+    pub fn check(
+        &mut self,
+        candidate: &str,
+        now: DateTime<Local>,
+        allow_guests: bool,
+    ) -> Option<CodeEntry> {
+        let entry = self.codes.iter_mut().find(|x| {
+            x.code == candidate && x.is_valid_at(now) && (allow_guests || !x.rules.guest)
+        })?;
+        entry.uses += 1;
+        let entry = entry.clone();
+        if let Err(error) = self.save() {
+            println!("{:?}", error);
+        }
+        Some(entry)
+    }
+
+    fn ensure_unused(&self, code: &str, id: Option<u32>) -> Result<(), Error> {
+        validate_code(code)?;
+        if self
+            .codes
+            .iter()
+            .any(|x| x.code == code && Some(x.id) != id)
+        {
+            return Err(Error("Code already in use".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, code: &NewCode) -> Result<u32, Error> {
+        self.ensure_unused(&code.code, None)?;
+        let id = self.codes.iter().map(|x| x.id + 1).max().unwrap_or(0);
+        self.codes.push(CodeEntry {
+            id,
+            owner: code.owner.clone(),
+            code: code.code.clone(),
+            rules: code.rules.clone(),
+            uses: 0,
+        });
+        self.save()?;
+        Ok(id)
+    }
+
+    pub fn edit(&mut self, code: &CodeEntry) -> Result<(), Error> {
+        self.ensure_unused(&code.code, Some(code.id))?;
+        let entry = self
+            .codes
+            .iter_mut()
+            .find(|x| x.id == code.id)
+            .ok_or_else(|| Error("No such code".to_string()))?;
+        *entry = code.clone();
+        self.save()
+    }
+
+    pub fn revoke(&mut self, id: u32) -> Result<(), Error> {
+        let length = self.codes.len();
+        self.codes.retain(|x| x.id != id);
+        if self.codes.len() == length {
+            return Err(Error("No such code".to_string()));
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_file;
+    use chrono::{NaiveDate, TimeZone};
+
+    fn store(name: &str) -> CodeStore {
+        CodeStore::load_from(temp_file(&format!("codes-{}.json", name)))
+    }
+
+    fn new_code(code: &str, rules: CodeRules) -> NewCode {
+        NewCode {
+            owner: "Test".to_string(),
+            code: code.to_string(),
+            rules,
+        }
+    }
+
+    // 2022-03-16 was a Wednesday.
+    fn at(hour: u32) -> DateTime<Local> {
+        let date = NaiveDate::from_ymd_opt(2022, 3, 16).unwrap();
+        Local
+            .from_local_datetime(&date.and_hms_opt(hour, 0, 0).unwrap())
+            .unwrap()
+    }
+
+    fn time(hour: u32, minute: u32, second: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, second).unwrap()
+    }
+
+    #[test]
+    fn test_add_and_check() {
+        let mut store = store("add");
+        let id = store.add(&new_code("1234", CodeRules::default())).unwrap();
+        assert_eq!(store.check("1234", at(12), true).unwrap().id, id);
+        assert!(store.check("4321", at(12), true).is_none());
+        assert!(store.add(&new_code("1234", CodeRules::default())).is_err());
+        assert!(store.add(&new_code("12#4", CodeRules::default())).is_err());
+    }
+
+    #[test]
+    fn test_max_uses() {
+        let mut store = store("uses");
+        let rules = CodeRules {
+            max_uses: Some(1),
+            ..Default::default()
+        };
+        store.add(&new_code("1234", rules)).unwrap();
+        assert!(store.check("1234", at(12), true).is_some());
+        assert!(store.check("1234", at(12), true).is_none());
+    }
+
+    #[test]
+    fn test_validity_window() {
+        let mut store = store("window");
+        let rules = CodeRules {
+            valid_from: Some(at(9)),
+            valid_until: Some(at(17)),
+            weekdays: vec![Weekday::Wed],
+            hours: Some(TimeWindow {
+                start: time(10, 0, 0),
+                end: time(16, 0, 0),
+            }),
+            max_uses: None,
+            guest: false,
+            doors: Vec::new(),
+        };
+        store.add(&new_code("1234", rules)).unwrap();
+        assert!(store.check("1234", at(8), true).is_none());
+        assert!(store.check("1234", at(9), true).is_none());
+        assert!(store.check("1234", at(12), true).is_some());
+        assert!(store.check("1234", at(17), true).is_none());
+        assert!(store
+            .check("1234", at(12) + chrono::Duration::days(1), true)
+            .is_none());
+    }
+
+    #[test]
+    fn test_guest_codes() {
+        let mut store = store("guest");
+        let rules = CodeRules {
+            guest: true,
+            ..Default::default()
+        };
+        store.add(&new_code("1234", rules)).unwrap();
+        assert!(store.check("1234", at(12), false).is_none());
+        assert!(store.check("1234", at(12), true).is_some());
+    }
+
+    #[test]
+    fn test_overnight_window() {
+        let window = TimeWindow {
+            start: time(22, 0, 0),
+            end: time(6, 0, 0),
+        };
+        assert!(window.contains(time(23, 0, 0)));
+        assert!(window.contains(time(5, 0, 0)));
+        assert!(!window.contains(time(12, 0, 0)));
+    }
+
+    #[test]
+    fn test_edit_and_revoke() {
+        let mut store = store("edit");
+        let id = store.add(&new_code("1234", CodeRules::default())).unwrap();
+        let mut entry = store.list()[0].clone();
+        entry.code = "5678".to_string();
+        store.edit(&entry).unwrap();
+        assert!(store.check("1234", at(12), true).is_none());
+        assert!(store.check("5678", at(12), true).is_some());
+        store.revoke(id).unwrap();
+        assert!(store.list().is_empty());
+        assert!(store.revoke(id).is_err());
+    }
+}
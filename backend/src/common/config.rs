@@ -0,0 +1,143 @@
+//! Runtime configuration for the intercom and web server, loaded from a JSON file.
+
+use crate::audit::AuditConfig;
+use crate::device::door::DoorConfig;
+use crate::device::nfc::NfcConfig;
+use crate::feedback::FeedbackConfig;
+use crate::gpio::GpioConfig;
+use crate::keyscan::KeypadConfig;
+use crate::link::LinkConfig;
+use crate::lockout::LockoutConfig;
+use crate::request::Error;
+use crate::tls::TlsConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::{env, fs};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub gpio: GpioConfig,
+    /// The only door, for configs written before `doors`. Ignored once `doors` is set.
+    pub door: DoorConfig,
+    pub doors: Vec<DoorConfig>,
+    pub keypad: KeypadConfig,
+    pub nfc: NfcConfig,
+    pub feedback: FeedbackConfig,
+    pub audit: AuditConfig,
+    pub lockout: LockoutConfig,
+    pub link: LinkConfig,
+    pub tls: TlsConfig,
+}
+
+impl Config {
+    const DEFAULT_FILE: &'static str = "config.json";
+
+    /// Reads the file named by `INTERCOM_CONFIG` (or `config.json`), falling back to
+    /// defaults when it does not exist.
+    pub fn load() -> Config {
+        let path = env::var("INTERCOM_CONFIG").unwrap_or_else(|_| Config::DEFAULT_FILE.to_string());
+        let config: Config = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .unwrap_or_else(|error| panic!("Invalid config file {}: {}", path, error)),
+            Err(_) => Config::default(),
+        };
+        if let Err(error) = config.validate() {
+            panic!("Invalid config file {}: {}", path, error.0);
+        }
+        config
+    }
+
+    /// The doors the intercom drives.
+    pub fn doors(&self) -> Vec<DoorConfig> {
+        if self.doors.is_empty() {
+            vec![self.door.clone()]
+        } else {
+            self.doors.clone()
+        }
+    }
+
+    pub fn door_names(&self) -> Vec<String> {
+        self.doors().into_iter().map(|x| x.name).collect()
+    }
+
+    /// The names of the doors a reader limited to `doors` opens.
+    pub fn reader_doors(&self, doors: &[String]) -> Vec<String> {
+        if doors.is_empty() {
+            self.door_names()
+        } else {
+            doors.to_vec()
+        }
+    }
+
+    /// Checks that door names are unique, no GPIO is wired to two door inputs, and the
+    /// keypad and card reader only name doors that exist.
+    pub fn validate(&self) -> Result<(), Error> {
+        let doors = self.doors();
+        let mut names = HashSet::new();
+        let mut pins = HashSet::new();
+        for door in doors.iter() {
+            if door.name.trim().is_empty() {
+                return Err(Error("Doors need a name".to_string()));
+            }
+            if !names.insert(door.name.as_str()) {
+                return Err(Error(format!("Door {} is named twice", door.name)));
+            }
+            let inputs = [Some(door.pin), door.sensor, door.exit_button];
+            for pin in inputs.into_iter().flatten() {
+                if !pins.insert(pin) {
+                    return Err(Error(format!("GPIO {} is used more than once", pin)));
+                }
+            }
+        }
+        let readers = [
+            ("keypad", &self.keypad.doors),
+            ("card reader", &self.nfc.doors),
+        ];
+        for (reader, doors) in readers {
+            if let Some(door) = doors.iter().find(|x| !names.contains(x.as_str())) {
+                return Err(Error(format!("The {} opens unknown door {}", reader, door)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn door(name: &str, pin: u64) -> DoorConfig {
+        DoorConfig {
+            name: name.to_string(),
+            pin,
+            ..DoorConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_doors() {
+        let config = Config::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.reader_doors(&[]), vec!["Front".to_string()]);
+
+        let mut config = Config {
+            doors: vec![door("Front", 50), door("Garage", 53)],
+            ..Config::default()
+        };
+        config.keypad.doors = vec!["Garage".to_string()];
+        assert!(config.validate().is_ok());
+        assert_eq!(config.reader_doors(&[]).len(), 2);
+
+        config.nfc.doors = vec!["Shed".to_string()];
+        assert!(config.validate().is_err());
+        config.nfc.doors.clear();
+        config.doors[1].name = "Front".to_string();
+        assert!(config.validate().is_err());
+        config.doors[1] = DoorConfig {
+            sensor: Some(50),
+            ..door("Garage", 53)
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,41 +1,125 @@
+pub mod audit;
 pub mod door;
+pub mod feedback;
 pub mod keypad;
 pub mod nfc;
+pub mod scheduler;
 pub mod terminal;
 
 use crate::message::{Receive, Send};
+use crate::requests_and_responses::RequestKind;
 use std::{
     marker,
-    thread::{self, JoinHandle},
-    time::Duration,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
-pub fn launch_device<T, U>(device: impl Device<T, U>) -> JoinHandle<()> {
-    thread::spawn(move || {
-        device.run();
-    })
-}
+/// Longest a device waits before checking whether the intercom is stopping.
+const STOP_CHECK: Duration = Duration::from_millis(500);
 
 pub struct Shutdown(pub bool);
 
+/// The requests a device answers. Its channel is registered with the dispatcher under
+/// each of them.
+pub trait Handles {
+    const REQUESTS: &'static [RequestKind];
+}
+
 pub trait Device<T, U>: Receive<T> + Send<U> + Sized + marker::Send + 'static {
     fn handle_command(&mut self, request: T) -> Shutdown;
-    fn get_sleep_duration(&self) -> Option<Duration>;
+    /// How long the device can wait for requests before it has to `step` again, or `None`
+    /// to step only when a request asks it to.
+    fn get_step_delay(&self) -> Option<Duration>;
     fn step(&mut self);
-    fn run(mut self) {
-        loop {
-            let received_message = Self::receive(&mut self);
-            if let Ok(msg) = received_message {
-                let shutdown = self.handle_command(msg);
-                if shutdown.0 {
+    /// Called once the device has stopped, to leave its hardware in a safe state.
+    fn shutdown(&mut self) {}
+    /// Runs until `stopping` is set or a request returns `Shutdown(true)`. Requests are
+    /// handled as soon as they arrive; the thread sleeps in between.
+    fn run(&mut self, stopping: &AtomicBool) {
+        let step_due = |device: &Self| device.get_step_delay().map(|x| Instant::now() + x);
+        let mut next_step = Some(Instant::now());
+        while !stopping.load(Ordering::Relaxed) {
+            let timeout = match next_step {
+                Some(at) => at.saturating_duration_since(Instant::now()).min(STOP_CHECK),
+                None => STOP_CHECK,
+            };
+            if let Ok(msg) = self.receive_timeout(timeout) {
+                if self.handle_command(msg).0 {
                     break;
+                }
+                // The request may have given the device something to do sooner.
+                next_step = match (next_step, step_due(self)) {
+                    (Some(x), Some(y)) => Some(x.min(y)),
+                    (x, y) => x.or(y),
                 };
             }
-            self.step();
-            match self.get_sleep_duration() {
-                Some(duration) => std::thread::sleep(duration),
-                _ => (),
+            if next_step.is_some_and(|at| at <= Instant::now()) {
+                self.step();
+                next_step = step_due(self);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{self, ThreadReceiver};
+    use crate::testing::wait_until;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    /// Records what it handled; steps once a minute.
+    struct Recorder {
+        receiver: ThreadReceiver<u32>,
+        handled: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Receive<u32> for Recorder {
+        fn receive(&mut self) -> Result<u32, message::Error> {
+            self.receiver.receive()
+        }
+        fn receive_timeout(&mut self, timeout: Duration) -> Result<u32, message::Error> {
+            self.receiver.receive_timeout(timeout)
+        }
+    }
+
+    impl Send<()> for Recorder {
+        fn send(&mut self, _: ()) {}
+    }
+
+    impl Device<u32, ()> for Recorder {
+        fn handle_command(&mut self, request: u32) -> Shutdown {
+            self.handled.lock().unwrap().push(request);
+            Shutdown(false)
+        }
+        fn get_step_delay(&self) -> Option<Duration> {
+            Some(Duration::from_secs(60))
+        }
+        fn step(&mut self) {
+            self.handled.lock().unwrap().push(0);
+        }
+    }
+
+    #[test]
+    fn test_requests_do_not_wait_for_step() {
+        let (sender, receiver) = mpsc::channel();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let mut device = Recorder {
+            receiver: ThreadReceiver(receiver),
+            handled: handled.clone(),
+        };
+        let stopping = Arc::new(AtomicBool::new(false));
+        let running = stopping.clone();
+        let thread = thread::spawn(move || device.run(&running));
+
+        let handled_len = || handled.lock().unwrap().len();
+        assert!(wait_until(Duration::from_secs(5), || handled_len() == 1));
+        sender.send(7).unwrap();
+        // Long before the next step is due.
+        assert!(wait_until(Duration::from_secs(5), || handled_len() == 2));
+        assert_eq!(*handled.lock().unwrap(), vec![0, 7]);
+        stopping.store(true, Ordering::Relaxed);
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use super::{Device, Handles, Shutdown};
+use crate::audit::{AuditEvent, AuditLog, AuditQuery};
+use crate::message;
+use crate::message::{Connection, Receive, Send, TcpSender, ThreadReceiver, ThreadSender};
+use crate::request::{Error, Query, QueryRequest, Set, SetRequest, ID};
+use crate::requests_and_responses::{
+    DeviceRequest, Internal, InternalThreadRequest, RequestKind, Requests, Responses, ThreadRequest,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub type AuditSender = ThreadSender<DeviceRequest, AuditLog>;
+
+/// Queues an event for the audit device to write.
+pub fn record(sender: &mut AuditSender, event: AuditEvent) {
+    sender.send(InternalThreadRequest(Internal::AuditRecord(event)));
+}
+
+/// Writes events to the audit log and pushes each one to every subscribed connection.
+pub struct AuditDevice {
+    sender: TcpSender<Responses>,
+    receiver: ThreadReceiver<DeviceRequest>,
+    log: AuditLog,
+    subscribers: Vec<(ID, Connection)>,
+}
+
+impl Send<Responses> for AuditDevice {
+    fn send(&mut self, target: Responses) {
+        self.sender.send(target);
+    }
+}
+
+impl Receive<DeviceRequest> for AuditDevice {
+    fn receive(&mut self) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive()
+    }
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive_timeout(timeout)
+    }
+}
+
+impl Handles for AuditLog {
+    const REQUESTS: &'static [RequestKind] =
+        &[RequestKind::AuditQuery, RequestKind::AuditSubscribe];
+}
+
+impl Device<DeviceRequest, Responses> for AuditDevice {
+    fn handle_command(&mut self, request: DeviceRequest) -> Shutdown {
+        let ThreadRequest(request, stream) = match request {
+            DeviceRequest::Relayed(x) => x,
+            DeviceRequest::Internal(InternalThreadRequest(Internal::AuditRecord(event))) => {
+                self.record(event);
+                return Shutdown(false);
+            }
+            DeviceRequest::Internal(_) => {
+                panic!("Audit device received internal message other than record.")
+            }
+            DeviceRequest::Wake => return Shutdown(false),
+        };
+        if let Requests::AuditSubscribe(x) = &request {
+            if let Ok(subscriber) = stream.try_clone() {
+                self.subscribers.push((x.get_id(), subscriber));
+            }
+        }
+        self.sender.set_stream(stream);
+        match request {
+            Requests::AuditSubscribe(x) => self
+                .sender
+                .send(Responses::AuditSubscribe(x.get_response(&mut self.log))),
+            Requests::AuditQuery(x) => self
+                .sender
+                .send(Responses::AuditQuery(x.get_response(&self.log))),
+            other => self.sender.send(Responses::Error(
+                other.get_id(),
+                format!("Audit device cannot handle {:?}", other.kind()),
+            )),
+        }
+        Shutdown(false)
+    }
+    fn get_step_delay(&self) -> Option<Duration> {
+        None
+    }
+    fn step(&mut self) {}
+}
+
+impl AuditDevice {
+    pub fn new(
+        sender: TcpSender<Responses>,
+        receiver: ThreadReceiver<DeviceRequest>,
+        log: AuditLog,
+    ) -> AuditDevice {
+        AuditDevice {
+            sender,
+            receiver,
+            log,
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, event: AuditEvent) {
+        let result = self.log.set(&event);
+        self.publish(&event);
+        if let Err(error) = result {
+            println!("{}", error.0);
+        }
+    }
+
+    fn publish(&mut self, event: &AuditEvent) {
+        self.subscribers.retain_mut(|(id, connection)| {
+            let message = Responses::AuditEvent(*id, event.clone());
+            connection.write(&message).is_ok()
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvents(pub Vec<AuditEvent>);
+
+/// Asks for every future event to be pushed on this connection as `Responses::AuditEvent`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Subscribe;
+
+// The subscribing stream is kept by the device; there is nothing to store here.
+impl Set<AuditLog, Subscribe> for AuditLog {
+    fn set(&mut self, _: &Subscribe) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Set<AuditLog, AuditEvent> for AuditLog {
+    fn set(&mut self, target: &AuditEvent) -> Result<(), Error> {
+        self.append(target)
+    }
+}
+
+impl Query<AuditLog, AuditQuery, AuditEvents> for AuditLog {
+    fn query(&self, target: &AuditQuery) -> Result<AuditEvents, Error> {
+        Ok(AuditEvents(AuditLog::query(self, target)))
+    }
+}
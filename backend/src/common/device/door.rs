@@ -1,35 +1,265 @@
-use super::{Device, Shutdown};
+use super::audit::{self, AuditSender};
+use super::{Device, Handles, Shutdown};
+use crate::audit::{AuditEvent, EventKind, Source};
+use crate::gpio::{self, Pin};
 use crate::message;
-use crate::message::{Receive, Send, TcpSender, ThreadReceiver};
-use crate::request::{Error, Get, GetRequest, Set, SetRequest};
-use crate::requests_and_responses::{InternalThreadRequest, Requests, Responses, ThreadRequest};
+use crate::message::{Receive, Send, TcpSender, ThreadReceiver, ThreadSender};
+use crate::notify::Notifier;
+use crate::request::{BasicSetRequest, Error, Get, GetRequest, Set, SetRequest, SetResponse, ID};
+use crate::requests_and_responses::{
+    DeviceRequest, Internal, InternalThreadRequest, RequestKind, Requests, Responses, ThreadRequest,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use sysfs_gpio::Pin;
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DoorConfig {
+    /// How requests, credentials and the audit log refer to the door.
+    pub name: String,
+    /// GPIO number of the lock relay.
+    pub pin: u64,
+    /// Set when the relay opens the lock on a low output.
+    pub active_low: bool,
+    /// How long a code, card or plain unlock from the web leaves the door open.
+    pub unlock_secs: u64,
+    /// GPIO number of a reed switch or door contact that reads high while the door is
+    /// open, if one is fitted.
+    pub sensor: Option<u64>,
+    /// Set when the contact reads low while the door is open.
+    pub sensor_active_low: bool,
+    /// How long the door may stay open once it has locked before it counts as ajar.
+    pub ajar_secs: u64,
+    /// GPIO number of a request-to-exit button that reads high while pressed, if one
+    /// is fitted. It unlocks the door for `unlock_secs`.
+    pub exit_button: Option<u64>,
+    /// Set when the button reads low while pressed.
+    pub exit_button_active_low: bool,
+    /// The button has to read the same for this long before a press counts.
+    pub exit_debounce_ms: u64,
+    /// Whether the button still lets people out in lockdown. The door goes back into
+    /// lockdown once they have left.
+    pub exit_in_lockdown: bool,
+}
 
-pub const PIN_NUMBER: u64 = 50;
+impl Default for DoorConfig {
+    fn default() -> DoorConfig {
+        DoorConfig {
+            name: "Front".to_string(),
+            pin: 50,
+            active_low: false,
+            // The first relock check, `elapsed().as_secs() > 3`, held off until four
+            // whole seconds had passed.
+            unlock_secs: 4,
+            sensor: None,
+            sensor_active_low: false,
+            ajar_secs: 30,
+            exit_button: None,
+            exit_button_active_low: false,
+            exit_debounce_ms: 50,
+            exit_in_lockdown: true,
+        }
+    }
+}
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
 pub enum DoorState {
     Lock,
+    /// Unlocked for the door's `unlock_secs`.
     Unlock,
+    /// Unlocked for this many seconds.
+    UnlockFor(u64),
+    /// Unlocked until it is set to another state.
+    HoldOpen,
+    /// Locked, and codes and cards are refused until it is set to another state.
+    Lockdown,
 }
 
 impl DoorState {
+    /// Longest a timed unlock may last.
+    pub const MAX_UNLOCK_SECS: u64 = 24 * 60 * 60;
+
     fn state_to_pin_value(&self) -> u8 {
         match &self {
-            DoorState::Lock => 0,
-            DoorState::Unlock => 1,
+            DoorState::Lock | DoorState::Lockdown => 0,
+            DoorState::Unlock | DoorState::UnlockFor(_) | DoorState::HoldOpen => 1,
         }
     }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.state_to_pin_value() == 1
+    }
 }
 
+/// What the schedule wants the doors to be, or `None` once no rule applies to them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ScheduledState(pub Option<DoorState>);
+
+/// Which doors are in lockdown, shared with the keypad and card reader so they can turn
+/// visitors away before unlocking anything.
+#[derive(Clone, Default)]
+pub struct Lockdown(Arc<Mutex<HashSet<String>>>);
+
+impl Lockdown {
+    pub fn is_active(&self, door: &str) -> bool {
+        self.0.lock().unwrap().contains(door)
+    }
+
+    fn set(&self, door: &str, active: bool) {
+        let mut doors = self.0.lock().unwrap();
+        if active {
+            doors.insert(door.to_string());
+        } else {
+            doors.remove(door);
+        }
+    }
+}
+
+/// Whether a credential or reader limited to `doors` may open the named door. An empty
+/// list allows every door.
+pub fn serves(doors: &[String], name: &str) -> bool {
+    doors.is_empty() || doors.iter().any(|x| x == name)
+}
+
+/// Checks that a credential is only limited to doors that exist, so a misspelt name
+/// does not leave it opening nothing.
+pub fn check_names(doors: &[String], names: &[String]) -> Result<(), Error> {
+    match doors.iter().find(|x| !names.contains(x)) {
+        Some(door) => Err(Error(format!("No door named {}", door))),
+        None => Ok(()),
+    }
+}
+
+/// Asks the door device to change a door the way a code, card or keypad sequence does.
+pub fn set_locally(
+    sender: &mut ThreadSender<DeviceRequest, Doors>,
+    door_id: &str,
+    state: DoorState,
+) {
+    let command = DoorCommand {
+        door_id: door_id.to_string(),
+        state,
+    };
+    sender.send(InternalThreadRequest(Internal::Request(
+        Requests::DoorSetState(BasicSetRequest::<Doors, DoorCommand>(
+            ID(0),
+            command,
+            PhantomData,
+        )),
+    )));
+}
+
+/// How long a reader waits for the door device to say whether a door opened.
+const UNLOCK_REPLY: Duration = Duration::from_secs(1);
+
+/// Unlocks each of a reader's `doors` that a credential limited to `allowed` may open,
+/// unless it is in lockdown, and returns what happened at each of them once the door
+/// device has answered.
+pub fn unlock_for(
+    sender: &mut ThreadSender<DeviceRequest, Doors>,
+    lockdown: &Lockdown,
+    doors: &[String],
+    allowed: &[String],
+) -> Vec<(String, Result<(), String>)> {
+    let doors = doors.iter().filter(|x| serves(allowed, x));
+    doors
+        .map(|name| {
+            let result = if lockdown.is_active(name) {
+                Err("Door is in lockdown".to_string())
+            } else {
+                unlock(sender, name)
+            };
+            (name.clone(), result)
+        })
+        .collect()
+}
+
+fn unlock(sender: &mut ThreadSender<DeviceRequest, Doors>, door_id: &str) -> Result<(), String> {
+    let (reply, result) = mpsc::channel();
+    sender.send(InternalThreadRequest(Internal::DoorUnlock(
+        door_id.to_string(),
+        reply,
+    )));
+    result
+        .recv_timeout(UNLOCK_REPLY)
+        .unwrap_or_else(|_| Err("The door device did not answer".to_string()))
+}
+
+/// What the door sensor saw that someone should hear about.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Alarm {
+    ForcedEntry,
+    Ajar,
+}
+
+impl Alarm {
+    fn kind(&self) -> EventKind {
+        match self {
+            Alarm::ForcedEntry => EventKind::ForcedEntry,
+            Alarm::Ajar => EventKind::DoorAjar,
+        }
+    }
+
+    fn message(&self, door: &str) -> String {
+        match self {
+            Alarm::ForcedEntry => format!("The {} door was opened while it was locked!", door),
+            Alarm::Ajar => format!("The {} door has been left open.", door),
+        }
+    }
+}
+
+/// A request-to-exit button, debounced so a bouncing contact opens the door once.
+#[derive(Clone)]
+struct ExitButton {
+    pin: Pin,
+    debounce: Duration,
+    pressed: bool,
+    /// Since when the button has read differently from `pressed`.
+    changing_since: Option<Instant>,
+}
+
+impl ExitButton {
+    fn new(pin: Pin, debounce: Duration) -> ExitButton {
+        ExitButton {
+            pin,
+            debounce,
+            pressed: false,
+            changing_since: None,
+        }
+    }
+
+    /// Takes one read of the button and returns whether a press has just settled.
+    fn update(&mut self, reads_pressed: bool, now: Instant) -> bool {
+        if reads_pressed == self.pressed {
+            self.changing_since = None;
+            return false;
+        }
+        let since = *self.changing_since.get_or_insert(now);
+        if now.duration_since(since) < self.debounce {
+            return false;
+        }
+        self.pressed = reads_pressed;
+        self.changing_since = None;
+        reads_pressed
+    }
+
+    /// When a change that is settling should be read again.
+    fn settles_at(&self) -> Option<Instant> {
+        self.changing_since.map(|since| since + self.debounce)
+    }
+}
+
+/// Drives every door the intercom has, each with its own relay, sensor and exit button.
 pub struct DoorDevice {
     sender: TcpSender<Responses>,
-    receiver: ThreadReceiver<ThreadRequest>,
-    door: Door,
-    internal_receiver: ThreadReceiver<InternalThreadRequest>,
+    receiver: ThreadReceiver<DeviceRequest>,
+    doors: Doors,
+    audit_sender: AuditSender,
+    notifier: Notifier,
 }
 
 impl Send<Responses> for DoorDevice {
@@ -38,46 +268,140 @@ impl Send<Responses> for DoorDevice {
     }
 }
 
-impl Receive<ThreadRequest> for DoorDevice {
-    fn receive(&mut self) -> Result<ThreadRequest, message::Error> {
+impl Receive<DeviceRequest> for DoorDevice {
+    fn receive(&mut self) -> Result<DeviceRequest, message::Error> {
         self.receiver.receive()
     }
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive_timeout(timeout)
+    }
+}
+
+impl Handles for Doors {
+    const REQUESTS: &'static [RequestKind] =
+        &[RequestKind::DoorGetState, RequestKind::DoorSetState];
+}
+
+fn event_kind(state: &DoorState) -> EventKind {
+    match state {
+        DoorState::Lock => EventKind::Lock,
+        DoorState::Lockdown => EventKind::Lockdown,
+        _ => EventKind::Unlock,
+    }
 }
 
-impl Device<ThreadRequest, Responses> for DoorDevice {
-    fn handle_command(&mut self, request: ThreadRequest) -> Shutdown {
-        let ThreadRequest(request, stream) = request;
+impl Device<DeviceRequest, Responses> for DoorDevice {
+    fn handle_command(&mut self, request: DeviceRequest) -> Shutdown {
+        let ThreadRequest(request, stream) = match request {
+            DeviceRequest::Relayed(x) => x,
+            DeviceRequest::Internal(InternalThreadRequest(request)) => {
+                match request {
+                    Internal::Request(Requests::DoorSetState(x)) => {
+                        let result = self
+                            .doors
+                            .find_mut(&x.1.door_id)
+                            .and_then(|door| door.set_locally(&x.1.state));
+                        if let Err(error) = result {
+                            println!("{}", error.0);
+                        }
+                    }
+                    // The reader records what happened, so it needs to hear back.
+                    Internal::DoorUnlock(door_id, reply) => {
+                        let result = self.doors.unlock(&door_id).map_err(|error| error.0);
+                        let _ = reply.send(result);
+                    }
+                    // Schedule rules apply to every door.
+                    Internal::DoorApplySchedule(state) => {
+                        for door in self.doors.0.iter_mut() {
+                            let before = door.state;
+                            let result = door.set(&state);
+                            if result.is_err() || door.state != before {
+                                let result = result.map_err(|error| error.0);
+                                let kind = event_kind(&door.state);
+                                let event = AuditEvent::new(kind, Source::Schedule, None, result)
+                                    .at_door(&door.name);
+                                audit::record(&mut self.audit_sender, event);
+                            }
+                        }
+                    }
+                    _ => panic!("Door device received internal message it does not handle."),
+                };
+                return Shutdown(false);
+            }
+            DeviceRequest::Wake => {
+                self.step();
+                return Shutdown(false);
+            }
+        };
         self.sender.set_stream(stream);
         match request {
             Requests::DoorGetState(x) => self
                 .sender
-                .send(Responses::DoorGetState(x.get_response(&self.door))),
-            Requests::DoorSetState(x) => self
-                .sender
-                .send(Responses::DoorSetState(x.get_response(&mut self.door))),
-            _ => panic!("Door device received invalid request"),
+                .send(Responses::DoorGetState(x.get_response(&self.doors))),
+            Requests::DoorSetState(x) => {
+                let response = x.get_response(&mut self.doors);
+                let command = response.get_candidate().clone();
+                let result = response.clone().get_result().map_err(|error| error.0);
+                let event = AuditEvent::new(event_kind(&command.state), Source::Web, None, result)
+                    .at_door(&command.door_id);
+                audit::record(&mut self.audit_sender, event);
+                self.sender.send(Responses::DoorSetState(response))
+            }
+            other => self.sender.send(Responses::Error(
+                other.get_id(),
+                format!("Door device cannot handle {:?}", other.kind()),
+            )),
         }
         Shutdown(false)
     }
-    fn get_sleep_duration(&self) -> Option<Duration> {
-        Some(Duration::from_millis(500))
+    fn get_step_delay(&self) -> Option<Duration> {
+        self.doors
+            .0
+            .iter()
+            .flat_map(|door| {
+                let exit_settles_at = door.exit_button.as_ref().and_then(|x| x.settles_at());
+                [door.relock_at, door.ajar_at(), exit_settles_at]
+            })
+            .flatten()
+            .min()
+            .map(|at| at.saturating_duration_since(Instant::now()))
     }
     fn step(&mut self) {
-        let received_internal_message = self.internal_receiver.receive();
-        if let Ok(msg) = received_internal_message {
-            let InternalThreadRequest(request) = msg;
-            match request {
-                Requests::DoorSetState(x) => {
-                    Responses::DoorSetState(x.get_response(&mut self.door))
+        for door in self.doors.0.iter_mut() {
+            if door.relock_at.is_some_and(|at| at <= Instant::now()) {
+                let relock_to = door.relock_to;
+                match door.set(&relock_to) {
+                    Ok(()) => {
+                        let kind = match relock_to {
+                            DoorState::Lockdown => EventKind::Lockdown,
+                            _ => EventKind::Lock,
+                        };
+                        let event =
+                            AuditEvent::new(kind, Source::Door, None, Ok(())).at_door(&door.name);
+                        audit::record(&mut self.audit_sender, event);
+                    }
+                    err => panic!("Unable to lock door after timeout: {:?}", err),
                 }
-                _ => panic!("Door device received internal message other than set state."),
-            };
-        }
-        if self.door.state == DoorState::Unlock && self.door.last_unlocked.elapsed().as_secs() > 3 {
-            match self.door.set(&DoorState::Lock) {
-                Ok(()) => (),
-                err => panic!("Unable to lock door after timeout: {:?}", err),
             }
+            if door.check_exit_button(Instant::now()) {
+                let result = door.exit().map_err(|error| error.0);
+                let event = AuditEvent::new(EventKind::Exit, Source::Door, None, result)
+                    .at_door(&door.name);
+                audit::record(&mut self.audit_sender, event);
+            }
+            if let Some(alarm) = door.check_sensor(Instant::now()) {
+                let message = alarm.message(&door.name);
+                let result = Err(message.clone());
+                let event =
+                    AuditEvent::new(alarm.kind(), Source::Door, None, result).at_door(&door.name);
+                audit::record(&mut self.audit_sender, event);
+                self.notifier.send(message);
+            }
+        }
+    }
+    fn shutdown(&mut self) {
+        for door in self.doors.0.iter_mut() {
+            door.release();
         }
     }
 }
@@ -85,48 +409,325 @@ impl Device<ThreadRequest, Responses> for DoorDevice {
 impl DoorDevice {
     pub fn new(
         sender: TcpSender<Responses>,
-        receiver: ThreadReceiver<ThreadRequest>,
-        door: Door,
-        internal_receiver: ThreadReceiver<InternalThreadRequest>,
+        receiver: ThreadReceiver<DeviceRequest>,
+        doors: Doors,
+        audit_sender: AuditSender,
+        notifier: Notifier,
     ) -> DoorDevice {
         return DoorDevice {
             sender,
             receiver,
-            door,
-            internal_receiver,
+            doors,
+            audit_sender,
+            notifier,
         };
     }
 }
 
+/// Every door the intercom drives, addressed by name.
+#[derive(Clone)]
+pub struct Doors(Vec<Door>);
+
+impl Doors {
+    pub fn new(doors: Vec<Door>) -> Doors {
+        Doors(doors)
+    }
+
+    /// Wakes the door device whenever any door's sensor or exit button changes.
+    pub fn watch(&self, wake: mpsc::Sender<DeviceRequest>) {
+        for door in self.0.iter() {
+            door.watch(wake.clone());
+        }
+    }
+
+    /// Unlocks a door for a code or card, the way `Door::set_locally` does.
+    pub fn unlock(&mut self, name: &str) -> Result<(), Error> {
+        self.find_mut(name)?.set_locally(&DoorState::Unlock)
+    }
+
+    fn find_mut(&mut self, name: &str) -> Result<&mut Door, Error> {
+        self.0
+            .iter_mut()
+            .find(|x| x.name == name)
+            .ok_or_else(|| Error(format!("No door named {}", name)))
+    }
+}
+
+/// Sets one door, named by `door_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DoorCommand {
+    pub door_id: String,
+    pub state: DoorState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DoorStatus {
+    pub door_id: String,
+    pub state: DoorState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DoorList(pub Vec<DoorStatus>);
+
+impl Set<Doors, DoorCommand> for Doors {
+    fn set(&mut self, target: &DoorCommand) -> Result<(), Error> {
+        self.find_mut(&target.door_id)?.set(&target.state)
+    }
+}
+
+impl Get<Doors, DoorList> for Doors {
+    fn get(&self) -> Result<DoorList, Error> {
+        let doors = self.0.iter().map(|door| DoorStatus {
+            door_id: door.name.clone(),
+            state: door.state,
+        });
+        Ok(DoorList(doors.collect()))
+    }
+}
+
 #[derive(Clone)]
 pub struct Door {
+    name: String,
     state: DoorState,
     pin: Pin,
-    last_unlocked: Instant,
+    unlock_for: Duration,
+    /// What the schedule last put the door in.
+    scheduled: Option<DoorState>,
+    /// When a timed unlock ends, and what the door goes back to then.
+    relock_at: Option<Instant>,
+    relock_to: DoorState,
+    lockdown: Lockdown,
+    sensor: Option<Pin>,
+    exit_button: Option<ExitButton>,
+    exit_in_lockdown: bool,
+    watching: Arc<AtomicBool>,
+    ajar_after: Duration,
+    /// When the state was last set, so a door opened while unlocked has `ajar_after`
+    /// to close once it locks again.
+    changed_at: Instant,
+    /// Since when the sensor has read open.
+    open_since: Option<Instant>,
+    ajar_reported: bool,
 }
 
 impl Door {
-    pub fn new(state: DoorState, pin: Pin, last_unlocked: Instant) -> Door {
-        // let curTime = Instant::now();
-        Door {
-            state,
+    pub fn new(
+        pin: Pin,
+        sensor: Option<Pin>,
+        exit_button: Option<Pin>,
+        config: &DoorConfig,
+        lockdown: Lockdown,
+    ) -> Door {
+        let exit_debounce = Duration::from_millis(config.exit_debounce_ms);
+        let mut door = Door {
+            name: config.name.clone(),
+            state: DoorState::Lock,
             pin,
-            last_unlocked,
+            unlock_for: Duration::from_secs(config.unlock_secs),
+            relock_at: None,
+            relock_to: DoorState::Lock,
+            scheduled: None,
+            lockdown,
+            sensor,
+            exit_button: exit_button.map(|x| ExitButton::new(x, exit_debounce)),
+            exit_in_lockdown: config.exit_in_lockdown,
+            watching: Arc::new(AtomicBool::new(false)),
+            ajar_after: Duration::from_secs(config.ajar_secs),
+            changed_at: Instant::now(),
+            open_since: None,
+            ajar_reported: false,
+        };
+        // A door that is already open when the intercom starts was not forced.
+        if door.read_sensor() == Some(true) {
+            door.open_since = Some(door.changed_at);
+        }
+        door
+    }
+
+    /// Wakes the door device whenever the sensor or exit button changes.
+    pub fn watch(&self, wake: mpsc::Sender<DeviceRequest>) {
+        self.watching.store(true, Ordering::Relaxed);
+        let exit_button = self.exit_button.as_ref().map(|x| &x.pin);
+        for input in self.sensor.iter().chain(exit_button) {
+            let wake = wake.clone();
+            gpio::watch(input.clone(), self.watching.clone(), move || {
+                wake.send(DeviceRequest::Wake).is_ok()
+            });
+        }
+    }
+
+    /// Reads the exit button and returns whether it has just been pressed.
+    pub fn check_exit_button(&mut self, now: Instant) -> bool {
+        let button = match &mut self.exit_button {
+            Some(button) => button,
+            None => return false,
+        };
+        match button.pin.get_value() {
+            Ok(value) => button.update(value == 1, now),
+            Err(error) => {
+                println!("Unable to read exit button: {}", error);
+                false
+            }
+        }
+    }
+
+    /// Lets someone out for `unlock_secs`. In lockdown the door goes straight back into
+    /// lockdown afterwards, and codes and cards stay refused meanwhile.
+    pub fn exit(&mut self) -> Result<(), Error> {
+        if !self.lockdown.is_active(&self.name) {
+            return self.set_locally(&DoorState::Unlock);
+        }
+        if !self.exit_in_lockdown {
+            return Err(Error("Exit button is disabled in lockdown".to_string()));
+        }
+        self.set(&DoorState::Unlock)?;
+        self.relock_to = DoorState::Lockdown;
+        self.lockdown.set(&self.name, true);
+        Ok(())
+    }
+
+    /// Whether the sensor reads open, or `None` without a working sensor.
+    fn read_sensor(&self) -> Option<bool> {
+        let sensor = self.sensor.as_ref()?;
+        match sensor.get_value() {
+            Ok(value) => Some(value == 1),
+            Err(error) => {
+                println!("Unable to read door sensor: {}", error);
+                None
+            }
+        }
+    }
+
+    /// When an open door counts as ajar, if it is open while locked and not yet reported.
+    fn ajar_at(&self) -> Option<Instant> {
+        let open_since = self.open_since?;
+        if self.state.is_unlocked() || self.ajar_reported {
+            return None;
+        }
+        Some(open_since.max(self.changed_at) + self.ajar_after)
+    }
+
+    /// Reads the sensor and returns what it saw that needs raising.
+    pub fn check_sensor(&mut self, now: Instant) -> Option<Alarm> {
+        let open = self.read_sensor()?;
+        match (open, self.open_since) {
+            (true, None) => {
+                self.open_since = Some(now);
+                if !self.state.is_unlocked() {
+                    return Some(Alarm::ForcedEntry);
+                }
+            }
+            (false, Some(_)) => {
+                self.open_since = None;
+                self.ajar_reported = false;
+            }
+            _ => (),
+        }
+        if self.ajar_at().is_some_and(|at| at <= now) {
+            self.ajar_reported = true;
+            return Some(Alarm::Ajar);
+        }
+        None
+    }
+
+    /// Changes asked for at the door by a code, card or keypad sequence rather than from
+    /// the web. They cannot open the door in lockdown or take it out of lockdown, and an
+    /// unlock never cuts a longer one short.
+    pub fn set_locally(&mut self, target: &DoorState) -> Result<(), Error> {
+        if self.lockdown.is_active(&self.name) {
+            if target.is_unlocked() {
+                return Err(Error("Door is in lockdown".to_string()));
+            }
+            return Ok(());
+        }
+        if *target == DoorState::Unlock {
+            let relock_at = Instant::now() + self.unlock_for;
+            let longer = match self.state {
+                DoorState::HoldOpen => true,
+                _ => self.relock_at.is_some_and(|at| at > relock_at),
+            };
+            if longer {
+                return Ok(());
+            }
+        }
+        self.set(target)
+    }
+
+    /// Locks the door and gives the pin back to the system.
+    pub fn release(&mut self) {
+        if let Err(error) = self.set(&DoorState::Lock) {
+            println!("Unable to lock door on shutdown: {}", error.0);
+        }
+        if let Err(error) = self.pin.unexport() {
+            println!("Unable to release door GPIO pin: {}", error);
+        }
+        self.watching.store(false, Ordering::Relaxed);
+        let exit_button = self.exit_button.as_ref().map(|x| &x.pin);
+        for input in self.sensor.iter().chain(exit_button) {
+            if let Err(error) = input.unexport() {
+                println!("Unable to release door input GPIO pin: {}", error);
+            }
         }
     }
 }
 
 impl Set<Door, DoorState> for Door {
     fn set(&mut self, target: &DoorState) -> Result<(), Error> {
+        let relock_after = match target {
+            DoorState::Unlock => Some(self.unlock_for),
+            DoorState::UnlockFor(secs) if (1..=DoorState::MAX_UNLOCK_SECS).contains(secs) => {
+                Some(Duration::from_secs(*secs))
+            }
+            DoorState::UnlockFor(_) => {
+                return Err(Error(format!(
+                    "Timed unlocks must last between 1 and {} seconds",
+                    DoorState::MAX_UNLOCK_SECS
+                )))
+            }
+            _ => None,
+        };
         println!("Setting door state to {:?}", target);
         let pin_value = target.state_to_pin_value();
         if let Err(_) = self.pin.set_value(pin_value) {
             return Err(Error("Could not set pin value".to_string()));
         }
         self.state = *target;
-        if *target == DoorState::Unlock {
-            self.last_unlocked = Instant::now();
-        }
+        self.relock_at = relock_after.map(|x| Instant::now() + x);
+        self.relock_to = DoorState::Lock;
+        self.changed_at = Instant::now();
+        self.ajar_reported = false;
+        self.lockdown
+            .set(&self.name, *target == DoorState::Lockdown);
+        Ok(())
+    }
+}
+
+/// The schedule can lock the door down, but cannot open it in a lockdown set from the
+/// web. Once its rules end, the door locks again unless it has been changed since.
+impl Set<Door, ScheduledState> for Door {
+    fn set(&mut self, target: &ScheduledState) -> Result<(), Error> {
+        let state = match target.0 {
+            Some(state) if state.is_unlocked() && self.lockdown.is_active(&self.name) => {
+                return Err(Error("Door is in lockdown".to_string()))
+            }
+            Some(state) => state,
+            None if self.scheduled == Some(self.state) => DoorState::Lock,
+            // Someone is being let out of a scheduled lockdown; the door relocks normally.
+            None if self.scheduled == Some(DoorState::Lockdown)
+                && self.relock_to == DoorState::Lockdown =>
+            {
+                self.relock_to = DoorState::Lock;
+                self.lockdown.set(&self.name, false);
+                self.scheduled = None;
+                return Ok(());
+            }
+            None => {
+                self.scheduled = None;
+                return Ok(());
+            }
+        };
+        self.set(&state)?;
+        self.scheduled = target.0;
         Ok(())
     }
 }
@@ -136,3 +737,264 @@ impl Get<Door, DoorState> for Door {
         Ok(self.state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::{Direction, GpioPin, MockBoard};
+
+    fn door(board: &MockBoard) -> Door {
+        let pin = board.pin(50);
+        pin.set_direction(Direction::Out).unwrap();
+        let config = DoorConfig {
+            unlock_secs: 1,
+            ..DoorConfig::default()
+        };
+        let sensor = board.pin(51);
+        sensor.set_level(0);
+        let exit_button = board.pin(52);
+        exit_button.set_level(0);
+        Door::new(
+            Arc::new(pin),
+            Some(Arc::new(sensor)),
+            Some(Arc::new(exit_button)),
+            &config,
+            Lockdown::default(),
+        )
+    }
+
+    #[test]
+    fn test_timed_unlocks() {
+        let board = MockBoard::default();
+        let mut door = door(&board);
+        door.set(&DoorState::Unlock).unwrap();
+        assert_eq!(board.pin(50).level(), 1);
+        assert!(door.relock_at.unwrap() <= Instant::now() + Duration::from_secs(1));
+
+        // A code does not cut a longer unlock from the web short.
+        door.set(&DoorState::UnlockFor(60)).unwrap();
+        door.set_locally(&DoorState::Unlock).unwrap();
+        assert_eq!(door.get().unwrap(), DoorState::UnlockFor(60));
+        assert!(door.set(&DoorState::UnlockFor(0)).is_err());
+
+        door.set(&DoorState::HoldOpen).unwrap();
+        door.set_locally(&DoorState::Unlock).unwrap();
+        assert_eq!(door.get().unwrap(), DoorState::HoldOpen);
+        assert_eq!(door.relock_at, None);
+        door.set_locally(&DoorState::Lock).unwrap();
+        assert_eq!(board.pin(50).level(), 0);
+    }
+
+    #[test]
+    fn test_lockdown() {
+        let board = MockBoard::default();
+        let mut door = door(&board);
+        let lockdown = door.lockdown.clone();
+        door.set(&DoorState::Lockdown).unwrap();
+        assert!(lockdown.is_active("Front"));
+        assert!(door.set_locally(&DoorState::Unlock).is_err());
+        door.set_locally(&DoorState::Lock).unwrap();
+        assert_eq!(door.get().unwrap(), DoorState::Lockdown);
+        assert_eq!(board.pin(50).level(), 0);
+
+        // Only the web takes it out of lockdown.
+        door.set(&DoorState::Unlock).unwrap();
+        assert!(!lockdown.is_active("Front"));
+    }
+
+    #[test]
+    fn test_forced_entry() {
+        let board = MockBoard::default();
+        let mut door = door(&board);
+        let now = Instant::now();
+        assert_eq!(door.check_sensor(now), None);
+        board.pin(51).set_level(1);
+        assert_eq!(door.check_sensor(now), Some(Alarm::ForcedEntry));
+        assert_eq!(door.check_sensor(now), None);
+        board.pin(51).set_level(0);
+        assert_eq!(door.check_sensor(now), None);
+
+        // Opening it after a code is fine.
+        door.set(&DoorState::Unlock).unwrap();
+        board.pin(51).set_level(1);
+        assert_eq!(door.check_sensor(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_door_ajar() {
+        let board = MockBoard::default();
+        let mut door = door(&board);
+        door.set(&DoorState::HoldOpen).unwrap();
+        board.pin(51).set_level(1);
+        let opened = Instant::now();
+        assert_eq!(door.check_sensor(opened), None);
+        // Held open, so it can stay open as long as it likes.
+        assert_eq!(door.ajar_at(), None);
+
+        door.set(&DoorState::Lock).unwrap();
+        let ajar_at = door.ajar_at().unwrap();
+        assert!(ajar_at >= opened + door.ajar_after);
+        assert_eq!(door.check_sensor(ajar_at - Duration::from_secs(1)), None);
+        assert_eq!(door.check_sensor(ajar_at), Some(Alarm::Ajar));
+        assert_eq!(door.check_sensor(ajar_at), None);
+
+        // Closing it resets the alarm for next time.
+        board.pin(51).set_level(0);
+        assert_eq!(door.check_sensor(ajar_at), None);
+        assert_eq!(door.ajar_at(), None);
+    }
+
+    #[test]
+    fn test_exit_button() {
+        let board = MockBoard::default();
+        let mut door = door(&board);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        // A bounce shorter than the debounce time is not a press.
+        board.pin(52).set_level(1);
+        assert!(!door.check_exit_button(at(0)));
+        board.pin(52).set_level(0);
+        assert!(!door.check_exit_button(at(10)));
+
+        board.pin(52).set_level(1);
+        assert!(!door.check_exit_button(at(20)));
+        assert_eq!(
+            door.exit_button.as_ref().unwrap().settles_at(),
+            Some(at(70))
+        );
+        assert!(door.check_exit_button(at(70)));
+        // Holding it down does not count again.
+        assert!(!door.check_exit_button(at(200)));
+    }
+
+    #[test]
+    fn test_exit_in_lockdown() {
+        let board = MockBoard::default();
+        let mut door = door(&board);
+        door.set(&DoorState::Lockdown).unwrap();
+        door.exit().unwrap();
+        assert_eq!(board.pin(50).level(), 1);
+        assert_eq!(door.relock_to, DoorState::Lockdown);
+        // Codes are still refused while the door is open for someone leaving.
+        assert!(door.lockdown.is_active("Front"));
+        assert!(door.set_locally(&DoorState::Unlock).is_err());
+
+        door.set(&DoorState::Lockdown).unwrap();
+        door.exit_in_lockdown = false;
+        assert!(door.exit().is_err());
+        assert_eq!(board.pin(50).level(), 0);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let board = MockBoard::default();
+        let mut door = door(&board);
+        let scheduled = |x| ScheduledState(Some(x));
+        door.set(&scheduled(DoorState::HoldOpen)).unwrap();
+        assert_eq!(board.pin(50).level(), 1);
+        door.set(&ScheduledState(None)).unwrap();
+        assert_eq!(door.get().unwrap(), DoorState::Lock);
+
+        // A change from the web outlasts the rule that was in force.
+        door.set(&scheduled(DoorState::HoldOpen)).unwrap();
+        door.set(&DoorState::Lockdown).unwrap();
+        door.set(&ScheduledState(None)).unwrap();
+        assert_eq!(door.get().unwrap(), DoorState::Lockdown);
+        assert!(door.set(&scheduled(DoorState::HoldOpen)).is_err());
+        door.set(&scheduled(DoorState::Lockdown)).unwrap();
+        door.set(&ScheduledState(None)).unwrap();
+        assert_eq!(door.get().unwrap(), DoorState::Lock);
+
+        // The lockdown ends while the exit button has the door open.
+        door.set(&scheduled(DoorState::Lockdown)).unwrap();
+        door.exit().unwrap();
+        door.set(&ScheduledState(None)).unwrap();
+        assert_eq!(door.get().unwrap(), DoorState::Unlock);
+        assert_eq!(door.relock_to, DoorState::Lock);
+        assert!(!door.lockdown.is_active("Front"));
+    }
+
+    #[test]
+    fn test_doors() {
+        let board = MockBoard::default();
+        let lockdown = Lockdown::default();
+        let garage_pin = board.pin(53);
+        garage_pin.set_direction(Direction::Out).unwrap();
+        let garage = DoorConfig {
+            name: "Garage".to_string(),
+            pin: 53,
+            ..DoorConfig::default()
+        };
+        let mut front = door(&board);
+        front.lockdown = lockdown.clone();
+        let garage = Door::new(Arc::new(garage_pin), None, None, &garage, lockdown.clone());
+        let mut doors = Doors::new(vec![front, garage]);
+        let command = |door: &str, state| DoorCommand {
+            door_id: door.to_string(),
+            state,
+        };
+
+        doors.set(&command("Garage", DoorState::HoldOpen)).unwrap();
+        assert_eq!(board.pin(53).level(), 1);
+        assert_eq!(board.pin(50).level(), 0);
+        assert!(doors.set(&command("Shed", DoorState::Unlock)).is_err());
+
+        // Locking one door down leaves the others alone.
+        doors.set(&command("Front", DoorState::Lockdown)).unwrap();
+        assert!(lockdown.is_active("Front"));
+        assert!(!lockdown.is_active("Garage"));
+        let states: Vec<_> = doors
+            .get()
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|x| x.state)
+            .collect();
+        assert_eq!(states, vec![DoorState::Lockdown, DoorState::HoldOpen]);
+    }
+
+    #[test]
+    fn test_unlock_reports_door_result() {
+        let board = MockBoard::default();
+        let mut front = door(&board);
+        front.set(&DoorState::Lockdown).unwrap();
+        let mut doors = Doors::new(vec![front]);
+        let (sender, receiver) = mpsc::channel();
+        let answer = std::thread::spawn(move || {
+            for _ in 0..2 {
+                if let Ok(DeviceRequest::Internal(InternalThreadRequest(Internal::DoorUnlock(
+                    name,
+                    reply,
+                )))) = receiver.recv()
+                {
+                    reply.send(doors.unlock(&name).map_err(|x| x.0)).unwrap();
+                }
+            }
+        });
+        let mut sender = ThreadSender(sender, PhantomData);
+        let front = vec!["Front".to_string()];
+
+        // The reader had not heard of the lockdown yet; the door still refuses.
+        let results = unlock_for(&mut sender, &Lockdown::default(), &front, &[]);
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_err());
+        let results = unlock_for(
+            &mut sender,
+            &Lockdown::default(),
+            &["Shed".to_string()],
+            &[],
+        );
+        assert!(results[0].1.is_err());
+        answer.join().unwrap();
+    }
+
+    #[test]
+    fn test_serves() {
+        let doors = vec!["Front".to_string()];
+        assert!(serves(&doors, "Front"));
+        assert!(!serves(&doors, "Garage"));
+        assert!(serves(&[], "Garage"));
+        assert!(check_names(&doors, &doors).is_ok());
+        assert!(check_names(&["Garge".to_string()], &doors).is_err());
+    }
+}
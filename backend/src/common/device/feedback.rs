@@ -0,0 +1,206 @@
+use super::{Device, Handles, Shutdown};
+use crate::feedback::{Outputs, Player, Signal};
+use crate::gpio::Pin;
+use crate::message;
+use crate::message::{Receive, Send, TcpSender, ThreadReceiver, ThreadSender};
+use crate::request::{Error, Set};
+use crate::requests_and_responses::{
+    DeviceRequest, Internal, InternalThreadRequest, RequestKind, Responses, ThreadRequest,
+};
+use std::time::{Duration, Instant};
+
+pub type FeedbackSender = ThreadSender<DeviceRequest, Feedback>;
+
+/// Asks the feedback device to play the pattern for `signal`.
+pub fn signal(sender: &mut FeedbackSender, signal: Signal) {
+    sender.send(InternalThreadRequest(Internal::FeedbackSignal(signal)));
+}
+
+/// Drives the buzzer and LEDs through the pattern of the latest signal.
+pub struct FeedbackDevice {
+    sender: TcpSender<Responses>,
+    receiver: ThreadReceiver<DeviceRequest>,
+    feedback: Feedback,
+}
+
+impl Send<Responses> for FeedbackDevice {
+    fn send(&mut self, target: Responses) {
+        self.sender.send(target);
+    }
+}
+
+impl Receive<DeviceRequest> for FeedbackDevice {
+    fn receive(&mut self) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive()
+    }
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive_timeout(timeout)
+    }
+}
+
+impl Handles for Feedback {
+    const REQUESTS: &'static [RequestKind] = &[];
+}
+
+impl Device<DeviceRequest, Responses> for FeedbackDevice {
+    fn handle_command(&mut self, request: DeviceRequest) -> Shutdown {
+        let ThreadRequest(request, stream) = match request {
+            DeviceRequest::Relayed(x) => x,
+            DeviceRequest::Internal(InternalThreadRequest(request)) => {
+                match request {
+                    Internal::FeedbackSignal(signal) => {
+                        if let Err(error) = self.feedback.set(&signal) {
+                            println!("{}", error.0);
+                        }
+                    }
+                    _ => panic!("Feedback device received internal message other than signal."),
+                }
+                return Shutdown(false);
+            }
+            DeviceRequest::Wake => return Shutdown(false),
+        };
+        self.sender.set_stream(stream);
+        self.sender.send(Responses::Error(
+            request.get_id(),
+            format!("Feedback device cannot handle {:?}", request.kind()),
+        ));
+        Shutdown(false)
+    }
+    fn get_step_delay(&self) -> Option<Duration> {
+        self.feedback
+            .next_change
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+    fn step(&mut self) {
+        if let Err(error) = self.feedback.show() {
+            println!("{}", error.0);
+        }
+    }
+    fn shutdown(&mut self) {
+        self.feedback.release();
+    }
+}
+
+impl FeedbackDevice {
+    pub fn new(
+        sender: TcpSender<Responses>,
+        receiver: ThreadReceiver<DeviceRequest>,
+        feedback: Feedback,
+    ) -> FeedbackDevice {
+        FeedbackDevice {
+            sender,
+            receiver,
+            feedback,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Feedback {
+    buzzer: Option<Pin>,
+    red_led: Option<Pin>,
+    green_led: Option<Pin>,
+    player: Player,
+    shown: Outputs,
+    /// When the pattern moves on to its next step.
+    next_change: Option<Instant>,
+}
+
+impl Feedback {
+    pub fn new(buzzer: Option<Pin>, red_led: Option<Pin>, green_led: Option<Pin>) -> Feedback {
+        Feedback {
+            buzzer,
+            red_led,
+            green_led,
+            player: Player::default(),
+            shown: Outputs::default(),
+            next_change: None,
+        }
+    }
+
+    fn pins(&self) -> impl Iterator<Item = &Pin> {
+        [&self.buzzer, &self.red_led, &self.green_led]
+            .into_iter()
+            .flatten()
+    }
+
+    /// Sets the outputs for the current step of the pattern.
+    fn show(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let (outputs, next) = self.player.outputs(now);
+        self.next_change = next.map(|x| now + x);
+        if outputs == self.shown {
+            return Ok(());
+        }
+        for (pin, on) in [
+            (&self.buzzer, outputs.buzzer),
+            (&self.red_led, outputs.red),
+            (&self.green_led, outputs.green),
+        ] {
+            if let Some(pin) = pin {
+                if pin.set_value(on as u8).is_err() {
+                    return Err(Error(format!(
+                        "Could not set feedback GPIO pin {}",
+                        pin.number()
+                    )));
+                }
+            }
+        }
+        self.shown = outputs;
+        Ok(())
+    }
+
+    /// Turns everything off and gives the pins back to the system.
+    pub fn release(&mut self) {
+        for pin in self.pins() {
+            if let Err(error) = pin.set_value(0).and_then(|()| pin.unexport()) {
+                println!(
+                    "Unable to release feedback GPIO pin {}: {}",
+                    pin.number(),
+                    error
+                );
+            }
+        }
+    }
+}
+
+impl Set<Feedback, Signal> for Feedback {
+    fn set(&mut self, target: &Signal) -> Result<(), Error> {
+        self.player.play(*target, Instant::now());
+        self.show()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::{Direction, MockBoard};
+    use std::sync::Arc;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_pattern_drives_pins() {
+        let board = MockBoard::default();
+        let pin = |number| {
+            let pin: Pin = Arc::new(board.pin(number));
+            pin.export().unwrap();
+            pin.set_direction(Direction::Out).unwrap();
+            pin
+        };
+        let mut feedback = Feedback::new(Some(pin(60)), Some(pin(48)), None);
+        feedback.set(&Signal::Keypress).unwrap();
+        assert_eq!(board.pin(60).level(), 1);
+        assert_eq!(board.pin(48).level(), 0);
+        assert!(feedback.next_change.is_some());
+
+        sleep(Duration::from_millis(40));
+        feedback.show().unwrap();
+        assert_eq!(board.pin(60).level(), 0);
+        assert_eq!(feedback.next_change, None);
+
+        feedback.set(&Signal::Rejected).unwrap();
+        assert_eq!(board.pin(48).level(), 1);
+        feedback.release();
+        assert_eq!(board.pin(48).level(), 0);
+    }
+}
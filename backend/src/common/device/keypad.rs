@@ -1,50 +1,62 @@
-use super::door::DoorState;
-use super::{Device, Shutdown};
-use crate::device::door::Door;
+use super::audit::{self, AuditSender};
+use super::door::{self, DoorState, Doors, Lockdown};
+use super::feedback::{self, FeedbackSender};
+use super::{Device, Handles, Shutdown};
+use crate::audit::{AuditEvent, EventKind, Source};
+use crate::codes::{self, CodeEntry, CodeStore, NewCode};
+use crate::feedback::Signal;
+use crate::gpio::{self, Direction, Edge, Pin};
+use crate::keyscan::{self, Debouncer, KeyAction, KeypadConfig};
+use crate::lockout::{Lockout, LockoutState};
 use crate::message::{self, ThreadSender};
 use crate::message::{Receive, Send, TcpSender, ThreadReceiver};
-use crate::request::{BasicSetRequest, Error, Get, GetRequest, Set, SetRequest, ID};
-use crate::requests_and_responses::{InternalThreadRequest, Requests, Responses, ThreadRequest};
-use openapi::apis::{configuration::Configuration, default_api as twilio_api};
-use phonenumber::PhoneNumber;
+use crate::notify::Notifier;
+use crate::request::{Error, Get, GetRequest, Set, SetRequest};
+use crate::requests_and_responses::{
+    DeviceRequest, RequestKind, Requests, Responses, ThreadRequest,
+};
+use crate::schedule::GuestCodes;
+use crate::sequences::{self, Action, Sequence};
+use chrono::Local;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashSet, VecDeque};
-use std::fs::File;
-use std::io::Write;
-use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
-use std::{env, fs};
-use tokio::runtime::{Builder, Runtime};
-
-use sysfs_gpio::Pin;
 
 pub struct KeyPadDevice {
-    door_sender: ThreadSender<InternalThreadRequest, Door>,
+    door_sender: ThreadSender<DeviceRequest, Doors>,
+    audit_sender: AuditSender,
+    feedback_sender: FeedbackSender,
     sender: TcpSender<Responses>,
-    receiver: ThreadReceiver<ThreadRequest>,
+    receiver: ThreadReceiver<DeviceRequest>,
     keypad: KeyPad,
-    runtime: Runtime,
+    lockdown: Lockdown,
+    /// Names of the doors the keypad opens.
+    doors: Vec<String>,
 }
 
 impl KeyPadDevice {
     pub fn new(
-        door_sender: ThreadSender<InternalThreadRequest, Door>,
+        door_sender: ThreadSender<DeviceRequest, Doors>,
+        audit_sender: AuditSender,
+        feedback_sender: FeedbackSender,
         sender: TcpSender<Responses>,
-        receiver: ThreadReceiver<ThreadRequest>,
+        receiver: ThreadReceiver<DeviceRequest>,
         keypad: KeyPad,
+        lockdown: Lockdown,
+        doors: Vec<String>,
     ) -> KeyPadDevice {
-        let runtime = Builder::new_multi_thread()
-            .worker_threads(1)
-            .enable_all()
-            .build()
-            .unwrap();
         KeyPadDevice {
             door_sender,
+            audit_sender,
+            feedback_sender,
             sender,
             receiver,
             keypad,
-            runtime,
+            lockdown,
+            doors,
         }
     }
 }
@@ -55,15 +67,44 @@ impl Send<Responses> for KeyPadDevice {
     }
 }
 
-impl Receive<ThreadRequest> for KeyPadDevice {
-    fn receive(&mut self) -> Result<ThreadRequest, message::Error> {
+impl Receive<DeviceRequest> for KeyPadDevice {
+    fn receive(&mut self) -> Result<DeviceRequest, message::Error> {
         self.receiver.receive()
     }
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive_timeout(timeout)
+    }
+}
+
+impl Handles for KeyPad {
+    const REQUESTS: &'static [RequestKind] = &[
+        RequestKind::KeyPadGetCode,
+        RequestKind::KeyPadSetCode,
+        RequestKind::PhoneGet,
+        RequestKind::PhoneSet,
+        RequestKind::KeyPadListCodes,
+        RequestKind::KeyPadAddCode,
+        RequestKind::KeyPadRevokeCode,
+        RequestKind::KeyPadEditCode,
+        RequestKind::KeyPadGetLockout,
+        RequestKind::KeyPadClearLockout,
+    ];
 }
 
-impl Device<ThreadRequest, Responses> for KeyPadDevice {
-    fn handle_command(&mut self, request: ThreadRequest) -> Shutdown {
-        let ThreadRequest(request, stream) = request;
+impl Device<DeviceRequest, Responses> for KeyPadDevice {
+    fn handle_command(&mut self, request: DeviceRequest) -> Shutdown {
+        let ThreadRequest(request, stream) = match request {
+            DeviceRequest::Relayed(x) => x,
+            DeviceRequest::Internal(_) => panic!("Keypad device received internal message."),
+            DeviceRequest::Wake => {
+                // Scanning drives the rows, so a held key keeps waking the device; the
+                // scan interval already covers it.
+                if !self.keypad.debouncer.is_active() {
+                    self.step();
+                }
+                return Shutdown(false);
+            }
+        };
         self.sender.set_stream(stream);
         match request {
             Requests::KeyPadGetCode(x) => self
@@ -78,112 +119,294 @@ impl Device<ThreadRequest, Responses> for KeyPadDevice {
             Requests::PhoneSet(x) => self
                 .sender
                 .send(Responses::PhoneSet(x.get_response(&mut self.keypad))),
-            _ => panic!("Keypad device received invalid request"),
+            Requests::KeyPadListCodes(x) => self
+                .sender
+                .send(Responses::KeyPadListCodes(x.get_response(&self.keypad))),
+            Requests::KeyPadAddCode(x) => self
+                .sender
+                .send(Responses::KeyPadAddCode(x.get_response(&mut self.keypad))),
+            Requests::KeyPadRevokeCode(x) => self.sender.send(Responses::KeyPadRevokeCode(
+                x.get_response(&mut self.keypad),
+            )),
+            Requests::KeyPadEditCode(x) => self
+                .sender
+                .send(Responses::KeyPadEditCode(x.get_response(&mut self.keypad))),
+            Requests::KeyPadGetLockout(x) => self
+                .sender
+                .send(Responses::KeyPadGetLockout(x.get_response(&self.keypad))),
+            Requests::KeyPadClearLockout(x) => self.sender.send(Responses::KeyPadClearLockout(
+                x.get_response(&mut self.keypad),
+            )),
+            other => self.sender.send(Responses::Error(
+                other.get_id(),
+                format!("Keypad device cannot handle {:?}", other.kind()),
+            )),
         }
         Shutdown(false)
     }
-    fn get_sleep_duration(&self) -> Option<Duration> {
-        Some(Duration::from_millis(50))
+    fn get_step_delay(&self) -> Option<Duration> {
+        self.keypad.next_scan()
     }
     fn step(&mut self) {
-        self.keypad.add_keys();
+        if self.keypad.add_keys() > 0 {
+            feedback::signal(&mut self.feedback_sender, Signal::Keypress);
+        }
         let keypad_code = self.keypad.check_candidates();
         match keypad_code {
-            CodeType::Code => {
-                let internal_request = InternalThreadRequest(Requests::DoorSetState(
-                    BasicSetRequest::<Door, DoorState>(ID(0), DoorState::Unlock, PhantomData),
-                ));
-                self.door_sender.send(internal_request);
-            }
-            CodeType::Ring if self.keypad.last_rang.elapsed() >= KeyPad::RING_TIMER => {
-                self.keypad.last_rang = Instant::now();
-                self.runtime.spawn(send_notification(self.keypad.phonenumber.to_string()));
+            CodeType::Code(entry) => self.unlock(entry.owner, &entry.rules.doors),
+            CodeType::Sequence(action) => self.run_action(action),
+            CodeType::Invalid => {
+                let result = Err("Invalid code".to_string());
+                let event = AuditEvent::new(EventKind::FailedCode, Source::Keypad, None, result);
+                audit::record(&mut self.audit_sender, event);
+                if let Some(backoff) = self.keypad.lockout.record_failure(Instant::now()) {
+                    println!("Too many wrong codes, locking keypad for {:?}", backoff);
+                    feedback::signal(&mut self.feedback_sender, Signal::LockedOut);
+                    let result = Err(format!("Locked for {}s", backoff.as_secs()));
+                    let event =
+                        AuditEvent::new(EventKind::KeypadLockout, Source::Keypad, None, result);
+                    audit::record(&mut self.audit_sender, event);
+                    self.keypad
+                        .notifier
+                        .send(KeyPad::LOCKOUT_MESSAGE.to_string());
+                } else {
+                    feedback::signal(&mut self.feedback_sender, Signal::Rejected);
+                }
             }
+            CodeType::Ignored => feedback::signal(&mut self.feedback_sender, Signal::LockedOut),
             _ => (),
         }
         if self.keypad.last_pressed.elapsed() >= KeyPad::RESET_TIMER {
             self.keypad.reset_input_keys();
         }
     }
+    fn shutdown(&mut self) {
+        self.keypad.matrix.release();
+    }
 }
 
+impl KeyPadDevice {
+    /// Opens the keypad's doors that a correct code may open, except those in lockdown.
+    fn unlock(&mut self, owner: String, allowed: &[String]) {
+        self.keypad.lockout.record_success();
+        let results = door::unlock_for(&mut self.door_sender, &self.lockdown, &self.doors, allowed);
+        if results.is_empty() {
+            let result = Err("Code does not open this door".to_string());
+            let credential = Some(owner.clone());
+            let event = AuditEvent::new(EventKind::Unlock, Source::Keypad, credential, result);
+            audit::record(&mut self.audit_sender, event);
+        }
+        let opened = results.iter().any(|(_, result)| result.is_ok());
+        for (name, result) in results {
+            let credential = Some(owner.clone());
+            let event = AuditEvent::new(EventKind::Unlock, Source::Keypad, credential, result)
+                .at_door(&name);
+            audit::record(&mut self.audit_sender, event);
+        }
+        let signal = if opened {
+            Signal::Accepted
+        } else {
+            Signal::Rejected
+        };
+        feedback::signal(&mut self.feedback_sender, signal);
+    }
+
+    fn record(&mut self, kind: EventKind, credential: Option<String>) {
+        let event = AuditEvent::new(kind, Source::Keypad, credential, Ok(()));
+        audit::record(&mut self.audit_sender, event);
+    }
+
+    /// Carries out a sequence from the keypad's table.
+    fn run_action(&mut self, action: Action) {
+        // Anything that texts someone is limited like the doorbell.
+        if matches!(
+            action,
+            Action::Ring | Action::RingResident { .. } | Action::AdminOverride
+        ) {
+            if self.keypad.last_rang.elapsed() < KeyPad::RING_TIMER {
+                return;
+            }
+            self.keypad.last_rang = Instant::now();
+        }
+        let notifier = self.keypad.notifier.clone();
+        match action {
+            Action::Ring => {
+                feedback::signal(&mut self.feedback_sender, Signal::Ringing);
+                notifier.send(KeyPad::RING_MESSAGE.to_string());
+                self.record(EventKind::DoorbellRing, None);
+            }
+            Action::RingResident { name, phone } => {
+                feedback::signal(&mut self.feedback_sender, Signal::Ringing);
+                notifier.send_to(phone, KeyPad::RING_MESSAGE.to_string());
+                self.record(EventKind::DoorbellRing, Some(name));
+            }
+            Action::Duress { name } => {
+                self.unlock(name.clone(), &[]);
+                notifier.send(format!("{} ({})", KeyPad::DURESS_MESSAGE, name));
+                self.record(EventKind::Duress, Some(name));
+            }
+            Action::Lock => {
+                for name in self.doors.iter() {
+                    door::set_locally(&mut self.door_sender, name, DoorState::Lock);
+                    let event = AuditEvent::new(EventKind::Lock, Source::Keypad, None, Ok(()))
+                        .at_door(name);
+                    audit::record(&mut self.audit_sender, event);
+                }
+                feedback::signal(&mut self.feedback_sender, Signal::Accepted);
+            }
+            Action::AdminOverride => {
+                feedback::signal(&mut self.feedback_sender, Signal::Ringing);
+                notifier.send(KeyPad::OVERRIDE_MESSAGE.to_string());
+                self.record(EventKind::OverrideRequest, None);
+            }
+        }
+    }
+}
+
+/// Rows are held low while idle so that any key pulls its column down and wakes the
+/// keypad through an edge interrupt; the matrix is only scanned after that.
 #[derive(Clone)]
 pub struct KeyPadMatrix {
-    rows: [Pin; 4],
-    cols: [Pin; 4],
+    rows: Vec<Pin>,
+    cols: Vec<Pin>,
+    layout: Vec<Vec<char>>,
+    /// Cleared when the pins are released, which stops the edge watchers.
+    watching: Arc<AtomicBool>,
 }
 
 impl KeyPadMatrix {
-    pub const COL_PINS: [u64; 4] = [66, 67, 69, 68];
-    pub const ROW_PINS: [u64; 4] = [3, 2, 15, 115];
-    const POS_TO_CHAR: [[char; 4]; 4] = [
-        ['1', '2', '3', 'A'],
-        ['4', '5', '6', 'B'],
-        ['7', '8', '9', 'C'],
-        ['*', '0', '#', 'D'],
-    ];
-    pub fn new(rows: [Pin; 4], cols: [Pin; 4]) -> KeyPadMatrix {
-        for pin in rows {
-            pin.set_direction(sysfs_gpio::Direction::In).unwrap();
+    const DIAGNOSE_INTERVAL: Duration = Duration::from_millis(1);
+
+    /// `layout` gives the key at each row and column, checked by `KeypadConfig::validate`.
+    pub fn new(rows: Vec<Pin>, cols: Vec<Pin>, layout: Vec<Vec<char>>) -> KeyPadMatrix {
+        for pin in rows.iter() {
             pin.set_active_low(false).unwrap();
         }
-        for pin in cols {
-            pin.set_direction(sysfs_gpio::Direction::In).unwrap();
+        for pin in cols.iter() {
+            pin.set_direction(Direction::In).unwrap();
             pin.set_active_low(false).unwrap();
+            pin.set_edge(Edge::Falling).unwrap();
         }
+        let matrix = KeyPadMatrix {
+            rows,
+            cols,
+            layout,
+            watching: Arc::new(AtomicBool::new(false)),
+        };
+        matrix.idle();
         sleep(Duration::from_millis(500));
-        KeyPadMatrix { rows, cols }
+        matrix
+    }
+    fn idle(&self) {
+        for row_pin in self.rows.iter() {
+            row_pin.set_direction(Direction::Out).unwrap();
+            row_pin.set_value(0).unwrap();
+        }
     }
     pub fn get_keys_pressed(&self) -> HashSet<char> {
+        self.get_positions_pressed()
+            .into_iter()
+            .map(|(i, j)| self.layout[i][j])
+            .collect()
+    }
+    /// Rows and columns of the keys that are down.
+    pub fn get_positions_pressed(&self) -> HashSet<(usize, usize)> {
         let mut set = HashSet::new();
+        // Only the row being read may pull the columns down.
+        for row_pin in self.rows.iter() {
+            row_pin.set_direction(Direction::In).unwrap();
+        }
         for (i, row_pin) in self.rows.iter().enumerate() {
-            row_pin.set_direction(sysfs_gpio::Direction::Out).unwrap();
+            row_pin.set_direction(Direction::Out).unwrap();
             row_pin.set_value(0).unwrap();
             for (j, col_pin) in self.cols.iter().enumerate() {
                 if col_pin.get_value().unwrap() == 0 {
-                    set.insert(KeyPadMatrix::POS_TO_CHAR[i][j]);
+                    set.insert((i, j));
                 }
             }
-            row_pin.set_direction(sysfs_gpio::Direction::In).unwrap();
-            row_pin.set_active_low(false).unwrap();
+            row_pin.set_direction(Direction::In).unwrap();
         }
+        self.idle();
         set
     }
+
+    /// Prints each physical position as it goes down or up, without debouncing, until
+    /// `stopping` returns true. Used to check a keypad's wiring against its config.
+    pub fn diagnose(&self, stopping: impl Fn() -> bool) {
+        let started = Instant::now();
+        let mut last = HashSet::new();
+        while !stopping() {
+            let pressed = self.get_positions_pressed();
+            let changes = pressed
+                .difference(&last)
+                .map(|x| ("down", x))
+                .chain(last.difference(&pressed).map(|x| ("up", x)));
+            for (action, &(i, j)) in changes {
+                println!(
+                    "{:>8}ms  row {} (GPIO {}), column {} (GPIO {}) {}: '{}'",
+                    started.elapsed().as_millis(),
+                    i + 1,
+                    self.rows[i].number(),
+                    j + 1,
+                    self.cols[j].number(),
+                    action,
+                    self.layout[i][j]
+                );
+            }
+            last = pressed;
+            sleep(KeyPadMatrix::DIAGNOSE_INTERVAL);
+        }
+    }
+
+    /// Sends `DeviceRequest::Wake` whenever a key goes down, until the pins are released.
+    pub fn watch(&self, wake: mpsc::Sender<DeviceRequest>) {
+        self.watching.store(true, Ordering::Relaxed);
+        for col_pin in self.cols.iter() {
+            let wake = wake.clone();
+            gpio::watch(col_pin.clone(), self.watching.clone(), move || {
+                wake.send(DeviceRequest::Wake).is_ok()
+            });
+        }
+    }
+
+    /// Gives the row and column pins back to the system.
+    pub fn release(&self) {
+        self.watching.store(false, Ordering::Relaxed);
+        for pin in self.rows.iter().chain(self.cols.iter()) {
+            if let Err(error) = pin.unexport() {
+                println!(
+                    "Unable to release keypad GPIO pin {}: {}",
+                    pin.number(),
+                    error
+                );
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct CandidateKey {
     data: VecDeque<char>,
-    previous_pressed_keys: HashSet<char>,
-    is_pressed_keys_initialized: bool,
     capacity: usize,
 }
 
 impl CandidateKey {
-    const ENDING_KEY: char = '#';
+    const ENDING_KEY: char = keyscan::ENTER_KEY;
     pub const INITIAL_CAPACITY: usize = 256;
     pub fn new(capacity: usize) -> CandidateKey {
         CandidateKey {
             data: VecDeque::new(),
-            previous_pressed_keys: HashSet::new(),
-            is_pressed_keys_initialized: false,
             capacity,
         }
     }
-    pub fn add_keys(&mut self, keys: HashSet<char>) {
-        if !self.is_pressed_keys_initialized {
-            self.is_pressed_keys_initialized = true;
-            self.previous_pressed_keys = keys;
-            return;
-        }
-        let unpressed_keys = self.previous_pressed_keys.difference(&keys);
-        for ch in unpressed_keys {
-            self.data.push_back(ch.clone());
-            if self.data.len() == self.capacity {
-                self.data.pop_front();
-            }
+    pub fn add_key(&mut self, key: char) {
+        self.data.push_back(key);
+        if self.data.len() == self.capacity {
+            self.data.pop_front();
         }
-        self.previous_pressed_keys = keys;
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
     pub fn get_candidate_keys(&mut self) -> Vec<String> {
         let candidates = self
@@ -203,78 +426,111 @@ impl CandidateKey {
     }
     pub fn clear(&mut self) {
         self.data.clear();
-        self.previous_pressed_keys.clear();
-        self.is_pressed_keys_initialized = false;
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub enum CodeType {
-    Code,
-    Ring,
+    /// A valid code, carrying its entry.
+    Code(CodeEntry),
+    /// One of the keypad's special sequences.
+    Sequence(Action),
     Invalid,
+    NoInput,
+    /// Input while the keypad is locked out.
+    Ignored,
 }
 
 #[derive(Clone)]
 pub struct KeyPad {
-    code: Code,
+    codes: CodeStore,
     matrix: KeyPadMatrix,
+    debouncer: Debouncer,
+    scan_interval: Duration,
     potential_key: CandidateKey,
     last_pressed: Instant,
     last_rang: Instant,
-    phonenumber: PhoneNumber,
+    notifier: Notifier,
+    lockout: Lockout,
+    sequences: Vec<Sequence>,
+    guest_codes: GuestCodes,
+    /// Names of every door, which codes may be limited to.
+    door_names: Vec<String>,
 }
 
 impl KeyPad {
     pub const RESET_TIMER: Duration = Duration::from_secs(5);
     pub const RING_TIMER: Duration = Duration::from_secs(5);
-    const RING: &'static str = "***";
+    const RING_MESSAGE: &'static str = "Someone is ringing the bell!";
+    const DURESS_MESSAGE: &'static str = "A duress code was entered at the door.";
+    const OVERRIDE_MESSAGE: &'static str =
+        "Someone at the door is asking to be let in from the web interface.";
+    const LOCKOUT_MESSAGE: &'static str = "The keypad was locked after too many wrong codes.";
     pub fn new(
-        code: Code,
+        codes: CodeStore,
         matrix: KeyPadMatrix,
+        config: &KeypadConfig,
         potential_key: CandidateKey,
         last_pressed: Instant,
+        lockout: Lockout,
+        notifier: Notifier,
+        guest_codes: GuestCodes,
+        door_names: Vec<String>,
     ) -> KeyPad {
         KeyPad {
-            code,
+            codes,
             matrix,
+            debouncer: Debouncer::new(config.debounce()),
+            scan_interval: config.scan_interval(),
             potential_key,
             last_pressed,
             last_rang: Instant::now() - KeyPad::RING_TIMER,
-            phonenumber: phonenumber::parse(
-                None,
-                env::var("TO_NUMBER").expect("Failed to parse 'to' number"),
-            )
-            .unwrap(),
+            notifier,
+            lockout,
+            sequences: config.sequences.clone(),
+            guest_codes,
+            door_names,
         }
     }
-    pub fn add_keys(&mut self) {
+    /// Reads the matrix and returns how many keys were newly pressed.
+    pub fn add_keys(&mut self) -> usize {
         let keys = self.matrix.get_keys_pressed();
-        if keys.len() != 0 {
-            self.last_pressed = Instant::now();
+        let mut pressed = 0;
+        for event in self.debouncer.update(&keys, Instant::now()) {
+            if event.action == KeyAction::Press {
+                self.last_pressed = event.at;
+                self.potential_key.add_key(event.key);
+                pressed += 1;
+            }
+        }
+        pressed
+    }
+    /// Keeps scanning while a key is down, then only wakes to forget unfinished input.
+    pub fn next_scan(&self) -> Option<Duration> {
+        if self.debouncer.is_active() {
+            Some(self.scan_interval)
+        } else if !self.potential_key.is_empty() {
+            Some(KeyPad::RESET_TIMER.saturating_sub(self.last_pressed.elapsed()))
+        } else {
+            None
         }
-        self.potential_key.add_keys(keys);
     }
     pub fn check_candidates(&mut self) -> CodeType {
         let candidates = self.potential_key.get_candidate_keys();
-        let v: Vec<CodeType> = candidates
-            .iter()
-            .rev()
-            .take(1)
-            .map(|x| {
-                if self.code.is_candidate_valid(x) {
-                    CodeType::Code
-                } else if x == KeyPad::RING {
-                    CodeType::Ring
-                } else {
-                    CodeType::Invalid
-                }
-            })
-            .collect();
-        if v.is_empty() {
-            CodeType::Invalid
+        let candidate = match candidates.last() {
+            Some(candidate) => candidate,
+            None => return CodeType::NoInput,
+        };
+        if self.lockout.is_locked(Instant::now()) {
+            return CodeType::Ignored;
+        }
+        let allow_guests = !self.guest_codes.are_disabled();
+        if let Some(entry) = self.codes.check(candidate, Local::now(), allow_guests) {
+            CodeType::Code(entry)
+        } else if let Some(action) = sequences::find(&self.sequences, candidate) {
+            CodeType::Sequence(action.clone())
         } else {
-            v[0]
+            CodeType::Invalid
         }
     }
     pub fn get_last_pressed(&self) -> Instant {
@@ -291,70 +547,110 @@ pub struct Code {
 }
 
 impl Code {
-    const VALID_CHARS: [char; 14] = [
-        'A', 'B', 'C', 'D', '1', '2', '3', '4', '5', '6', '7', '8', '9', '0',
-    ];
-    const START_UP_CODE_FILE: &'static str = "code";
-    fn validate_string(string: &str) -> bool {
-        string
-            .chars()
-            .map(|x| Code::VALID_CHARS.contains(&x))
-            .all(|x| x)
-    }
     pub fn from_string(string: &str) -> Result<Code, Error> {
-        if Code::validate_string(string) {
-            return Ok(Code {
-                data: string.to_string(),
-            });
-        }
-        Err(Error("Invalid code format allocation".to_string()))
-    }
-    pub fn is_candidate_valid(&self, candidate: &str) -> bool {
-        candidate == self.data
-    }
-    pub fn new() -> Code {
-        let code = fs::read_to_string(Code::START_UP_CODE_FILE)
-            .unwrap()
-            .trim()
-            .to_owned();
-        Code { data: code }
+        codes::validate_code(string)?;
+        Ok(Code {
+            data: string.to_string(),
+        })
     }
 }
 
+// `Code` addresses the first code in the store, which is what the single-code
+// panel shows and edits.
 impl Get<KeyPad, Code> for KeyPad {
     fn get(&self) -> Result<Code, Error> {
-        Ok(self.code.clone())
+        match self.codes.list().first() {
+            Some(entry) => Ok(Code {
+                data: entry.code.clone(),
+            }),
+            None => Err(Error("No codes configured".to_string())),
+        }
     }
 }
 
 impl Set<KeyPad, Code> for KeyPad {
     fn set(&mut self, target: &Code) -> Result<(), Error> {
-        let new_code_result = Code::from_string(&target.data);
-        match new_code_result {
-            Ok(new_code) => {
-                self.code = new_code;
-                let mut file = File::create(Code::START_UP_CODE_FILE).unwrap();
-                file.write_all(self.code.data.as_bytes()).unwrap();
-                Ok(())
+        let new_code = Code::from_string(&target.data)?;
+        match self.codes.list().first() {
+            Some(entry) => {
+                let mut entry = entry.clone();
+                entry.code = new_code.data;
+                self.codes.edit(&entry)
             }
-            Err(error) => Err(error),
+            None => self
+                .codes
+                .add(&NewCode {
+                    owner: "Default".to_string(),
+                    code: new_code.data,
+                    rules: Default::default(),
+                })
+                .map(|_| ()),
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CodeList(pub Vec<CodeEntry>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevokeCode(pub u32);
+
+impl Get<KeyPad, CodeList> for KeyPad {
+    fn get(&self) -> Result<CodeList, Error> {
+        Ok(CodeList(self.codes.list().to_vec()))
+    }
+}
+
+impl Set<KeyPad, NewCode> for KeyPad {
+    fn set(&mut self, target: &NewCode) -> Result<(), Error> {
+        door::check_names(&target.rules.doors, &self.door_names)?;
+        self.codes.add(target).map(|_| ())
+    }
+}
+
+impl Set<KeyPad, RevokeCode> for KeyPad {
+    fn set(&mut self, target: &RevokeCode) -> Result<(), Error> {
+        self.codes.revoke(target.0)
+    }
+}
+
+impl Set<KeyPad, CodeEntry> for KeyPad {
+    fn set(&mut self, target: &CodeEntry) -> Result<(), Error> {
+        door::check_names(&target.rules.doors, &self.door_names)?;
+        self.codes.edit(target)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClearLockout;
+
+impl Get<KeyPad, LockoutState> for KeyPad {
+    fn get(&self) -> Result<LockoutState, Error> {
+        Ok(self.lockout.state(Instant::now()))
+    }
+}
+
+impl Set<KeyPad, ClearLockout> for KeyPad {
+    fn set(&mut self, _: &ClearLockout) -> Result<(), Error> {
+        self.lockout.clear();
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PhoneNumberText(pub String);
 
 impl Get<KeyPad, PhoneNumberText> for KeyPad {
     fn get(&self) -> Result<PhoneNumberText, Error> {
-        Ok(PhoneNumberText(self.phonenumber.to_string()))
+        Ok(PhoneNumberText(self.notifier.number().to_string()))
     }
 }
 
 impl Set<KeyPad, PhoneNumberText> for KeyPad {
     fn set(&mut self, target: &PhoneNumberText) -> Result<(), Error> {
         if phonenumber::is_viable(&target.0) {
-            self.phonenumber = phonenumber::parse(None, &target.0).unwrap();
+            self.notifier
+                .set_number(phonenumber::parse(None, &target.0).unwrap());
             Ok(())
         } else {
             Err(Error("Invalid phonenumber".to_string()))
@@ -362,42 +658,100 @@ impl Set<KeyPad, PhoneNumberText> for KeyPad {
     }
 }
 
-async fn send_notification(to: String) {
-    let account_sid = env::var("TWILIO_ACCOUNT_SID").expect("Failed to parse Account SID");
-    let api_key = env::var("TWILIO_API_KEY").expect("Failed to parse API Key");
-    let api_key_secret = env::var("TWILIO_API_KEY_SECRET").expect("Failed to parse API Key Secret");
-    let from = env::var("TWILIO_PHONE_NUMBER").expect("Failed to parse 'from' number");
-
-    let mut twilio_config = Configuration::default();
-    twilio_config.basic_auth = Some((api_key, Some(api_key_secret)));
-
-    let message = twilio_api::create_message(
-        &twilio_config,
-        &account_sid,
-        &to,
-        None,
-        None,
-        None,
-        Some("Someone is ringing the bell!"),
-        None,
-        None,
-        Some(&from),
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-    )
-    .await;
-
-    let _result = match message {
-        Ok(result) => result,
-        Err(error) => panic!("Something went wrong, {:?}", error),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::{Backend, MockBoard};
+    use std::sync::Mutex;
+
+    type Pressed = Arc<Mutex<HashSet<(usize, usize)>>>;
+
+    /// A pressed key connects its row and column, so the column reads low while that
+    /// row is driven low.
+    fn mock_matrix(board: &MockBoard, pressed: &Pressed) -> KeyPadMatrix {
+        let config = KeypadConfig::default();
+        for (j, col) in config.cols.iter().enumerate() {
+            let rows: Vec<_> = config.rows.iter().map(|x| board.pin(*x)).collect();
+            let pressed = pressed.clone();
+            board.pin(*col).set_input(Arc::new(move || {
+                let pressed = pressed.lock().unwrap();
+                let connected = rows.iter().enumerate().any(|(i, row)| {
+                    pressed.contains(&(i, j))
+                        && row.direction() == Direction::Out
+                        && row.level() == 0
+                });
+                !connected as u8
+            }));
+        }
+        let backend = Backend::Mock(board.clone());
+        let open = |x: &u64| backend.pin(*x).unwrap();
+        KeyPadMatrix::new(
+            config.rows.iter().map(open).collect(),
+            config.cols.iter().map(open).collect(),
+            config.layout(),
+        )
+    }
+
+    #[test]
+    fn test_scan_matrix() {
+        let board = MockBoard::default();
+        let pressed = Pressed::default();
+        let matrix = mock_matrix(&board, &pressed);
+        assert!(matrix.get_keys_pressed().is_empty());
+
+        pressed.lock().unwrap().extend([(1, 2), (3, 0)]);
+        assert_eq!(matrix.get_keys_pressed(), HashSet::from(['6', '*']));
+        // Back to idle, ready for the next key to pull a column down.
+        for row in KeypadConfig::default().rows {
+            assert_eq!(board.pin(row).direction(), Direction::Out);
+            assert_eq!(board.pin(row).level(), 0);
+        }
+    }
+
+    #[test]
+    fn test_key_wakes_watcher() {
+        let board = MockBoard::default();
+        let pressed = Pressed::default();
+        let matrix = mock_matrix(&board, &pressed);
+        let (sender, receiver) = mpsc::channel();
+        matrix.watch(sender);
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+
+        pressed.lock().unwrap().insert((0, 3));
+        let woken = receiver.recv_timeout(Duration::from_secs(1));
+        assert!(matches!(woken, Ok(DeviceRequest::Wake)));
+        assert_eq!(matrix.get_keys_pressed(), HashSet::from(['A']));
+        matrix.release();
+    }
+
+    #[test]
+    fn test_fast_typing() {
+        let board = MockBoard::default();
+        let pressed = Pressed::default();
+        let matrix = mock_matrix(&board, &pressed);
+        let mut debouncer = Debouncer::new(Duration::from_millis(20));
+        let mut candidate = CandidateKey::new(CandidateKey::INITIAL_CAPACITY);
+        let start = Instant::now();
+        // Each key goes down before the previous one is let go.
+        let typing = [
+            (0, vec![(0, 0)]),
+            (30, vec![(0, 0), (0, 1)]),
+            (60, vec![(0, 1), (3, 2)]),
+            (90, vec![(3, 2)]),
+            (120, vec![]),
+        ];
+        for (ms, keys) in typing {
+            *pressed.lock().unwrap() = keys.into_iter().collect();
+            for tick in 0..6 {
+                let now = start + Duration::from_millis(ms + tick * 5);
+                for event in debouncer.update(&matrix.get_keys_pressed(), now) {
+                    if event.action == KeyAction::Press {
+                        candidate.add_key(event.key);
+                    }
+                }
+            }
+        }
+        assert_eq!(candidate.get_candidate_keys(), vec!["12".to_string()]);
+        assert!(!debouncer.is_active());
+    }
 }
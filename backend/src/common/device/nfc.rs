@@ -1,17 +1,26 @@
 use i2cdev::core::I2CDevice;
 use i2cdev::linux::LinuxI2CDevice;
+use std::collections::VecDeque;
 use std::io::Result as IOResult;
-use std::marker::PhantomData;
 use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
-
-use super::{Device, Shutdown};
-use crate::device::door::{Door, DoorState};
+use std::time::{Duration, Instant};
+
+use super::audit::{self, AuditSender};
+use super::feedback::{self, FeedbackSender};
+use super::{Device, Handles, Shutdown};
+use crate::audit::{AuditEvent, EventKind, Source};
+use crate::capabilities::{DeviceKind, Health, Inventory};
+use crate::cards::{self, CardEntry, CardStore};
+use crate::device::door::{self, Doors, Lockdown};
+use crate::feedback::Signal;
 use crate::message::{self, ThreadSender};
 use crate::message::{Receive, Send, TcpSender, ThreadReceiver};
-use crate::request::{BasicSetRequest, Error, Get, GetRequest, Set, SetRequest, ID};
-use crate::requests_and_responses::{InternalThreadRequest, Requests, Responses, ThreadRequest};
+use crate::request::{Error, Get, GetRequest, Set, SetRequest};
+use crate::requests_and_responses::{
+    DeviceRequest, RequestKind, Requests, Responses, ThreadRequest,
+};
 use serde::{Deserialize, Serialize};
 
 const PN532_ADDRESS: u8 = 0x48 >> 1;
@@ -62,16 +71,38 @@ enum CardTypes {
     Jewel = 0x04,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct NfcConfig {
+    /// Doors the card reader opens, by name. Empty means every door.
+    pub doors: Vec<String>,
+}
+
+pub trait CardReader: std::marker::Send {
+    fn get_uid(&mut self) -> IOResult<Vec<Vec<u8>>>;
+}
+
 #[derive(Clone)]
 pub struct NFCdev {
-    ids: Vec<Vec<u8>>,
+    cards: CardStore,
+    reader: Arc<Mutex<dyn CardReader>>,
+    /// Names of every door, which cards may be limited to.
+    door_names: Vec<String>,
 }
 
 pub struct NFCDevice {
-    door_sender: ThreadSender<InternalThreadRequest, Door>,
+    door_sender: ThreadSender<DeviceRequest, Doors>,
+    audit_sender: AuditSender,
+    feedback_sender: FeedbackSender,
     sender: TcpSender<Responses>,
-    receiver: ThreadReceiver<ThreadRequest>,
+    receiver: ThreadReceiver<DeviceRequest>,
     nfc: NFCdev,
+    inventory: Inventory,
+    lockdown: Lockdown,
+    /// Names of the doors the reader opens.
+    doors: Vec<String>,
+    /// Cards are not read again until then, so one tap is not counted twice.
+    next_read: Instant,
 }
 
 impl Send<Responses> for NFCDevice {
@@ -80,51 +111,134 @@ impl Send<Responses> for NFCDevice {
     }
 }
 
-impl Receive<ThreadRequest> for NFCDevice {
-    fn receive(&mut self) -> Result<ThreadRequest, message::Error> {
+impl Receive<DeviceRequest> for NFCDevice {
+    fn receive(&mut self) -> Result<DeviceRequest, message::Error> {
         self.receiver.receive()
     }
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive_timeout(timeout)
+    }
+}
+
+impl Handles for NFCdev {
+    const REQUESTS: &'static [RequestKind] = &[
+        RequestKind::NFCGetID,
+        RequestKind::NFCSetID,
+        RequestKind::NFCRemoveCard,
+        RequestKind::NFCSetCardEnabled,
+        RequestKind::NFCRenameCard,
+        RequestKind::NFCSetCardDoors,
+    ];
 }
 
-impl Device<ThreadRequest, Responses> for NFCDevice {
-    fn handle_command(&mut self, request: ThreadRequest) -> Shutdown {
-        let ThreadRequest(request, stream) = request;
+impl Device<DeviceRequest, Responses> for NFCDevice {
+    fn handle_command(&mut self, request: DeviceRequest) -> Shutdown {
+        let ThreadRequest(request, stream) = match request {
+            DeviceRequest::Relayed(x) => x,
+            DeviceRequest::Internal(_) => panic!("NFC device received internal message."),
+            DeviceRequest::Wake => return Shutdown(false),
+        };
         self.sender.set_stream(stream);
         match request {
             Requests::NFCGetID(x) => self
                 .sender
                 .send(Responses::NFCGetID(x.get_response(&self.nfc))),
-            Requests::NFCSetID(x) => self
+            Requests::NFCSetID(x) => {
+                let response = x.get_response(&mut self.nfc);
+                // The new card is likely still on the reader.
+                self.next_read = Instant::now() + NFCDevice::CARD_HOLD;
+                self.sender.send(Responses::NFCSetID(response))
+            }
+            Requests::NFCRemoveCard(x) => self
                 .sender
-                .send(Responses::NFCSetID(x.get_response(&mut self.nfc))),
-            _ => panic!("NFC device received invalid request"),
+                .send(Responses::NFCRemoveCard(x.get_response(&mut self.nfc))),
+            Requests::NFCSetCardEnabled(x) => self
+                .sender
+                .send(Responses::NFCSetCardEnabled(x.get_response(&mut self.nfc))),
+            Requests::NFCRenameCard(x) => self
+                .sender
+                .send(Responses::NFCRenameCard(x.get_response(&mut self.nfc))),
+            Requests::NFCSetCardDoors(x) => self
+                .sender
+                .send(Responses::NFCSetCardDoors(x.get_response(&mut self.nfc))),
+            other => self.sender.send(Responses::Error(
+                other.get_id(),
+                format!("NFC device cannot handle {:?}", other.kind()),
+            )),
         }
         Shutdown(false)
     }
-    fn get_sleep_duration(&self) -> Option<Duration> {
-        Some(Duration::from_millis(200))
+    fn get_step_delay(&self) -> Option<Duration> {
+        let paused = self.next_read.saturating_duration_since(Instant::now());
+        Some(paused.max(NFCDevice::POLL_INTERVAL))
     }
     fn step(&mut self) {
+        // A poll that fell due while a request was handled can still land inside the hold.
+        if Instant::now() < self.next_read {
+            return;
+        }
         let uid = self.nfc.get_uid();
+        let health = match &uid {
+            Ok(_) => Health::Ok,
+            Err(error) => Health::Faulty(format!("Reader not responding: {}", error)),
+        };
+        self.inventory.set(DeviceKind::Nfc, health);
         let uid = match uid {
-            Ok(id) => id,
-            Err(_) => {
+            Ok(id) if !id.is_empty() => id,
+            _ => {
                 return;
             }
         };
 
-        let ids = self.nfc.ids.clone();
-        for data in ids {
-            if data == uid[0] {
-                println!("Card Authenticattion Succeeded. Opening lock.");
-                // Unlock door
-                let internal_request = InternalThreadRequest(Requests::DoorSetState(
-                    BasicSetRequest::<Door, DoorState>(ID(0), DoorState::Unlock, PhantomData),
-                ));
-                self.door_sender.send(internal_request);
-                sleep(Duration::from_millis(1000));
+        if let Some(card) = self.nfc.cards.check(&uid[0]).cloned() {
+            let results = door::unlock_for(
+                &mut self.door_sender,
+                &self.lockdown,
+                &self.doors,
+                &card.doors,
+            );
+            if results.is_empty() {
+                let result = Err("Card does not open this door".to_string());
+                let event = AuditEvent::new(
+                    EventKind::Unlock,
+                    Source::Card,
+                    Some(card.name.clone()),
+                    result,
+                );
+                audit::record(&mut self.audit_sender, event);
             }
+            let opened = results.iter().any(|(_, result)| result.is_ok());
+            for (name, result) in results {
+                match &result {
+                    Ok(()) => println!(
+                        "Card Authentication Succeeded for {}. Opening {}.",
+                        card.name, name
+                    ),
+                    Err(error) => println!("Card {} refused at {}: {}", card.name, name, error),
+                }
+                let event = AuditEvent::new(
+                    EventKind::Unlock,
+                    Source::Card,
+                    Some(card.name.clone()),
+                    result,
+                )
+                .at_door(&name);
+                audit::record(&mut self.audit_sender, event);
+            }
+            let signal = if opened {
+                Signal::Accepted
+            } else {
+                Signal::Rejected
+            };
+            feedback::signal(&mut self.feedback_sender, signal);
+        } else {
+            feedback::signal(&mut self.feedback_sender, Signal::Rejected);
+            let uid = cards::uid_to_hex(&uid[0]);
+            let result = Err("Unknown card".to_string());
+            let event = AuditEvent::new(EventKind::UnknownCard, Source::Card, Some(uid), result);
+            audit::record(&mut self.audit_sender, event);
         }
+        self.next_read = Instant::now() + NFCDevice::CARD_HOLD;
     }
 }
 
@@ -137,44 +251,77 @@ fn enable_bus() -> IOResult<Output> {
 }
 
 impl NFCDevice {
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+    /// How long a card that was just read is ignored.
+    const CARD_HOLD: Duration = Duration::from_millis(1000);
+
     pub fn new(
-        door_sender: ThreadSender<InternalThreadRequest, Door>,
+        door_sender: ThreadSender<DeviceRequest, Doors>,
+        audit_sender: AuditSender,
+        feedback_sender: FeedbackSender,
         sender: TcpSender<Responses>,
-        receiver: ThreadReceiver<ThreadRequest>,
+        receiver: ThreadReceiver<DeviceRequest>,
         nfc: NFCdev,
+        inventory: Inventory,
+        lockdown: Lockdown,
+        doors: Vec<String>,
     ) -> NFCDevice {
         return NFCDevice {
             door_sender,
+            audit_sender,
+            feedback_sender,
             sender,
             receiver,
             nfc,
+            inventory,
+            lockdown,
+            doors,
+            next_read: Instant::now(),
         };
     }
 }
 
 impl NFCdev {
+    pub const ENROL_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(
+        cards: CardStore,
+        reader: Arc<Mutex<dyn CardReader>>,
+        door_names: Vec<String>,
+    ) -> Self {
+        Self {
+            cards,
+            reader,
+            door_names,
+        }
+    }
+
+    pub fn get_uid(&mut self) -> IOResult<Vec<Vec<u8>>> {
+        self.reader.lock().unwrap().get_uid()
+    }
+}
+
+/// PN532 reader on the BeagleBone's I2C bus 2.
+pub struct Pn532;
+
+impl Pn532 {
     pub fn new() -> Self {
         match enable_bus() {
             _ => (),
         }
-        let ids_vec = Vec::new();
-        let mut nfc = Self { ids: ids_vec };
+        let mut nfc = Self;
         match nfc.init_nfc() {
             _ => (),
         }
         nfc
     }
 
-    fn push(&mut self, new_id: Vec<u8>) {
-        self.ids.push(new_id);
-    }
-
     pub fn init_nfc(&mut self) -> IOResult<()> {
         self.send_command_to_nfcdev(&[Commands::SAMConfiguration as u8, 0x01])?;
         self.sync_packets()
     }
 
-    pub fn get_uid(&mut self) -> IOResult<Vec<Vec<u8>>> {
+    fn read_uid(&mut self) -> IOResult<Vec<Vec<u8>>> {
         self.send_command_to_nfcdev(&[
             Commands::InListPassiveTarget as u8,
             0x01,
@@ -338,33 +485,163 @@ impl NFCdev {
     }
 }
 
+impl CardReader for Pn532 {
+    fn get_uid(&mut self) -> IOResult<Vec<Vec<u8>>> {
+        self.read_uid()
+    }
+}
+
+/// Reader with no hardware behind it; cards are "tapped" by pushing their UID.
+#[derive(Clone, Default)]
+pub struct MockCardReader(Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+impl MockCardReader {
+    pub fn tap(&self, uid: Vec<u8>) {
+        self.0.lock().unwrap().push_back(uid);
+    }
+}
+
+impl CardReader for MockCardReader {
+    fn get_uid(&mut self) -> IOResult<Vec<Vec<u8>>> {
+        match self.0.lock().unwrap().pop_front() {
+            Some(uid) => Ok(vec![uid]),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Holder name for the next card tapped on the reader.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewCard(pub String);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CardList(pub Vec<CardEntry>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoveCard(pub String);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CardEnabled {
+    pub uid: String,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenameCard {
+    pub uid: String,
+    pub name: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct NFCids(pub String);
+pub struct CardDoors {
+    pub uid: String,
+    /// Empty lets the card open every door.
+    pub doors: Vec<String>,
+}
 
-impl Set<NFCdev, NFCids> for NFCdev {
-    fn set(&mut self, _target: &NFCids) -> Result<(), Error> {
+// Blocks until a card is tapped or the enrolment times out.
+impl Set<NFCdev, NewCard> for NFCdev {
+    fn set(&mut self, target: &NewCard) -> Result<(), Error> {
         println!("Scanning for new card...");
+        let started = Instant::now();
         loop {
+            if started.elapsed() >= NFCdev::ENROL_TIMEOUT {
+                return Err(Error("No card was scanned".to_string()));
+            }
             let uid = self.get_uid();
             let uid = match uid {
-                Ok(id) => id,
-                Err(_) => {
+                Ok(id) if !id.is_empty() => id,
+                _ => {
+                    sleep(Duration::from_millis(50));
                     continue;
                 }
             };
-            self.push(uid[0].clone());
+            self.cards.enrol(&uid[0], &target.0)?;
             println!("Added new card id");
-            sleep(Duration::from_millis(1000));
-            break;
+            return Ok(());
         }
-        Ok(())
     }
 }
 
-// Blocking
-impl Get<NFCdev, NFCids> for NFCdev {
-    fn get(&self) -> Result<NFCids, Error> {
-        let str = format!("ids = {:x?}", self.ids);
-        Ok(NFCids(str))
+impl Get<NFCdev, CardList> for NFCdev {
+    fn get(&self) -> Result<CardList, Error> {
+        Ok(CardList(self.cards.list().to_vec()))
+    }
+}
+
+impl Set<NFCdev, RemoveCard> for NFCdev {
+    fn set(&mut self, target: &RemoveCard) -> Result<(), Error> {
+        self.cards.remove(&target.0)
+    }
+}
+
+impl Set<NFCdev, CardEnabled> for NFCdev {
+    fn set(&mut self, target: &CardEnabled) -> Result<(), Error> {
+        self.cards.set_enabled(&target.uid, target.enabled)
+    }
+}
+
+impl Set<NFCdev, RenameCard> for NFCdev {
+    fn set(&mut self, target: &RenameCard) -> Result<(), Error> {
+        self.cards.rename(&target.uid, &target.name)
+    }
+}
+
+impl Set<NFCdev, CardDoors> for NFCdev {
+    fn set(&mut self, target: &CardDoors) -> Result<(), Error> {
+        door::check_names(&target.doors, &self.door_names)?;
+        self.cards.set_doors(&target.uid, &target.doors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Connection, Encoding, PROTOCOL_VERSION};
+    use crate::request::{BasicSetRequest, ID};
+    use crate::testing::temp_file;
+    use std::marker::PhantomData;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_enrolled_card_does_not_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = listener.accept().unwrap().0;
+        let connection = Connection::new(stream, Encoding::Json, PROTOCOL_VERSION);
+
+        let reader = MockCardReader::default();
+        let nfc = NFCdev::new(
+            CardStore::load_from(temp_file("cards-nfc.json")),
+            Arc::new(Mutex::new(reader.clone())),
+            vec!["Front".to_string()],
+        );
+        let (door_sender, door_receiver) = mpsc::channel();
+        let (audit_sender, _audit) = mpsc::channel();
+        let (feedback_sender, _feedback) = mpsc::channel();
+        let (_sender, receiver) = mpsc::channel();
+        let mut device = NFCDevice::new(
+            ThreadSender(door_sender, PhantomData),
+            ThreadSender(audit_sender, PhantomData),
+            ThreadSender(feedback_sender, PhantomData),
+            TcpSender(None, PhantomData),
+            ThreadReceiver(receiver),
+            nfc,
+            Inventory::default(),
+            Lockdown::default(),
+            vec!["Front".to_string()],
+        );
+
+        // Enrolment reads the tap; the card is still on the reader for the next poll.
+        reader.tap(vec![0x0a]);
+        reader.tap(vec![0x0a]);
+        let request = BasicSetRequest(ID(1), NewCard("Alice".to_string()), PhantomData);
+        device.handle_command(DeviceRequest::Relayed(ThreadRequest(
+            Requests::NFCSetID(request),
+            connection,
+        )));
+        device.step();
+        assert!(door_receiver.try_recv().is_err());
     }
 }
@@ -0,0 +1,175 @@
+use super::door::{Doors, ScheduledState};
+use super::{Device, Handles, Shutdown};
+use crate::message;
+use crate::message::{Receive, Send, TcpSender, ThreadReceiver, ThreadSender};
+use crate::request::{Error, Get, GetRequest, Set, SetRequest};
+use crate::requests_and_responses::{
+    DeviceRequest, Internal, InternalThreadRequest, RequestKind, Requests, Responses, ThreadRequest,
+};
+use crate::schedule::{self, GuestCodes, Modes, NewRule, ScheduleRule, ScheduleStore};
+use chrono::{Local, Timelike};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Applies the schedule's rules as they start and end. They are checked at the start of
+/// every minute against the wall clock, so the door catches up after a reboot or once
+/// the clock has been set.
+pub struct SchedulerDevice {
+    sender: TcpSender<Responses>,
+    receiver: ThreadReceiver<DeviceRequest>,
+    scheduler: Scheduler,
+    door_sender: ThreadSender<DeviceRequest, Doors>,
+}
+
+impl Send<Responses> for SchedulerDevice {
+    fn send(&mut self, target: Responses) {
+        self.sender.send(target);
+    }
+}
+
+impl Receive<DeviceRequest> for SchedulerDevice {
+    fn receive(&mut self) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive()
+    }
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive_timeout(timeout)
+    }
+}
+
+impl Handles for Scheduler {
+    const REQUESTS: &'static [RequestKind] = &[
+        RequestKind::ScheduleListRules,
+        RequestKind::ScheduleAddRule,
+        RequestKind::ScheduleEditRule,
+        RequestKind::ScheduleRemoveRule,
+    ];
+}
+
+impl Device<DeviceRequest, Responses> for SchedulerDevice {
+    fn handle_command(&mut self, request: DeviceRequest) -> Shutdown {
+        let ThreadRequest(request, stream) = match request {
+            DeviceRequest::Relayed(x) => x,
+            DeviceRequest::Internal(_) => {
+                panic!("Scheduler received an internal message.")
+            }
+            DeviceRequest::Wake => return Shutdown(false),
+        };
+        self.sender.set_stream(stream);
+        match request {
+            Requests::ScheduleListRules(x) => self.sender.send(Responses::ScheduleListRules(
+                x.get_response(&self.scheduler),
+            )),
+            Requests::ScheduleAddRule(x) => {
+                let response = x.get_response(&mut self.scheduler);
+                self.step();
+                self.sender.send(Responses::ScheduleAddRule(response))
+            }
+            Requests::ScheduleEditRule(x) => {
+                let response = x.get_response(&mut self.scheduler);
+                self.step();
+                self.sender.send(Responses::ScheduleEditRule(response))
+            }
+            Requests::ScheduleRemoveRule(x) => {
+                let response = x.get_response(&mut self.scheduler);
+                self.step();
+                self.sender.send(Responses::ScheduleRemoveRule(response))
+            }
+            other => self.sender.send(Responses::Error(
+                other.get_id(),
+                format!("Scheduler cannot handle {:?}", other.kind()),
+            )),
+        }
+        Shutdown(false)
+    }
+    fn get_step_delay(&self) -> Option<Duration> {
+        let second = Local::now().second() as u64;
+        Some(Duration::from_secs(60 - second.min(59)))
+    }
+    fn step(&mut self) {
+        let modes = schedule::modes_at(self.scheduler.store.list(), Local::now().naive_local());
+        let applied = self.scheduler.applied;
+        if applied == Some(modes) {
+            return;
+        }
+        self.scheduler
+            .guest_codes
+            .set_disabled(modes.guest_codes_disabled);
+        // The doors start out locked, so there is nothing to undo on the first check.
+        let door_changed = match applied {
+            Some(applied) => applied.door != modes.door,
+            None => modes.door.is_some(),
+        };
+        if door_changed {
+            self.door_sender
+                .send(InternalThreadRequest(Internal::DoorApplySchedule(
+                    ScheduledState(modes.door),
+                )));
+        }
+        println!("Schedule now wants {:?}", modes);
+        self.scheduler.applied = Some(modes);
+    }
+}
+
+impl SchedulerDevice {
+    pub fn new(
+        sender: TcpSender<Responses>,
+        receiver: ThreadReceiver<DeviceRequest>,
+        scheduler: Scheduler,
+        door_sender: ThreadSender<DeviceRequest, Doors>,
+    ) -> SchedulerDevice {
+        SchedulerDevice {
+            sender,
+            receiver,
+            scheduler,
+            door_sender,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    store: ScheduleStore,
+    guest_codes: GuestCodes,
+    /// What the rules asked for when last checked.
+    applied: Option<Modes>,
+}
+
+impl Scheduler {
+    pub fn new(store: ScheduleStore, guest_codes: GuestCodes) -> Scheduler {
+        Scheduler {
+            store,
+            guest_codes,
+            applied: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleList(pub Vec<ScheduleRule>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoveRule(pub u32);
+
+impl Get<Scheduler, RuleList> for Scheduler {
+    fn get(&self) -> Result<RuleList, Error> {
+        Ok(RuleList(self.store.list().to_vec()))
+    }
+}
+
+impl Set<Scheduler, NewRule> for Scheduler {
+    fn set(&mut self, target: &NewRule) -> Result<(), Error> {
+        self.store.add(target).map(|_| ())
+    }
+}
+
+impl Set<Scheduler, ScheduleRule> for Scheduler {
+    fn set(&mut self, target: &ScheduleRule) -> Result<(), Error> {
+        self.store.edit(target)
+    }
+}
+
+impl Set<Scheduler, RemoveRule> for Scheduler {
+    fn set(&mut self, target: &RemoveRule) -> Result<(), Error> {
+        self.store.remove(target.0)
+    }
+}
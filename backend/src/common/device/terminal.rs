@@ -1,15 +1,17 @@
-use super::{Device, Shutdown};
+use super::{Device, Handles, Shutdown};
 use crate::message;
 use crate::message::{Receive, Send, TcpSender, ThreadReceiver};
 use crate::request::{Error, Get, GetRequest, Set, SetRequest};
-use crate::requests_and_responses::{Requests, Responses, ThreadRequest};
+use crate::requests_and_responses::{
+    DeviceRequest, RequestKind, Requests, Responses, ThreadRequest,
+};
 use serde::{Deserialize, Serialize};
 use std::io;
 use std::time::Duration;
 
 pub struct TerminalDevice {
     sender: TcpSender<Responses>,
-    receiver: ThreadReceiver<ThreadRequest>,
+    receiver: ThreadReceiver<DeviceRequest>,
     terminal: Terminal,
 }
 
@@ -19,15 +21,27 @@ impl Send<Responses> for TerminalDevice {
     }
 }
 
-impl Receive<ThreadRequest> for TerminalDevice {
-    fn receive(&mut self) -> Result<ThreadRequest, message::Error> {
+impl Receive<DeviceRequest> for TerminalDevice {
+    fn receive(&mut self) -> Result<DeviceRequest, message::Error> {
         self.receiver.receive()
     }
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<DeviceRequest, message::Error> {
+        self.receiver.receive_timeout(timeout)
+    }
+}
+
+impl Handles for Terminal {
+    const REQUESTS: &'static [RequestKind] =
+        &[RequestKind::TerminalGetText, RequestKind::TerminalSetText];
 }
 
-impl Device<ThreadRequest, Responses> for TerminalDevice {
-    fn handle_command(&mut self, request: ThreadRequest) -> Shutdown {
-        let ThreadRequest(request, stream) = request;
+impl Device<DeviceRequest, Responses> for TerminalDevice {
+    fn handle_command(&mut self, request: DeviceRequest) -> Shutdown {
+        let ThreadRequest(request, stream) = match request {
+            DeviceRequest::Relayed(x) => x,
+            DeviceRequest::Internal(_) => panic!("Terminal device received internal message."),
+            DeviceRequest::Wake => return Shutdown(false),
+        };
         self.sender.set_stream(stream);
         match request {
             Requests::TerminalGetText(x) => self
@@ -37,12 +51,15 @@ impl Device<ThreadRequest, Responses> for TerminalDevice {
             Requests::TerminalSetText(x) => self.sender.send(Responses::TerminalSetText(
                 x.get_response(&mut self.terminal),
             )),
-            _ => panic!("Terminal device received invalid request"),
+            other => self.sender.send(Responses::Error(
+                other.get_id(),
+                format!("Terminal device cannot handle {:?}", other.kind()),
+            )),
         }
         Shutdown(false)
     }
-    fn get_sleep_duration(&self) -> Option<Duration> {
-        Some(Duration::from_millis(250))
+    fn get_step_delay(&self) -> Option<Duration> {
+        None
     }
     fn step(&mut self) {}
 }
@@ -50,7 +67,7 @@ impl Device<ThreadRequest, Responses> for TerminalDevice {
 impl TerminalDevice {
     pub fn new(
         sender: TcpSender<Responses>,
-        receiver: ThreadReceiver<ThreadRequest>,
+        receiver: ThreadReceiver<DeviceRequest>,
         terminal: Terminal,
     ) -> TerminalDevice {
         return TerminalDevice {
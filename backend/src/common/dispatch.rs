@@ -1,102 +1,241 @@
-use crate::device::door::Door;
-use crate::device::keypad::KeyPad;
-use crate::device::nfc::NFCdev;
-use crate::device::terminal::Terminal;
-use crate::message::{read_from_stream, ThreadSender};
-use crate::requests_and_responses::{Requests, ThreadRequest};
+use crate::device::Handles;
+use crate::link::Link;
+use crate::message::{Connection, Encoding, Error, ThreadSender};
+use crate::request::{GetRequest, SetRequest, ID};
+use crate::requests_and_responses::{
+    DeviceRequest, RequestKind, Requests, Responses, ThreadRequest,
+};
+use crate::supervisor::Supervisor;
+use serde::de::{Deserializer, IgnoredAny, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
 use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
 use std::thread;
 
+/// Routes each request to the device that registered for its kind.
 #[derive(Clone)]
 pub struct Dispatcher {
-    terminal_channel: ThreadSender<ThreadRequest, Terminal>,
-    nfc_channel: ThreadSender<ThreadRequest, NFCdev>,
-    door_channel: ThreadSender<ThreadRequest, Door>,
-    keypad_channel: ThreadSender<ThreadRequest, KeyPad>,
+    routes: HashMap<RequestKind, mpsc::Sender<DeviceRequest>>,
+    supervisor: Supervisor,
 }
 
 impl Dispatcher {
-    pub fn dispatch(&self, request: Requests, stream: TcpStream) {
-        match request {
-            Requests::TerminalGetText(_) => self
-                .terminal_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::TerminalSetText(_) => self
-                .terminal_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::NFCGetID(_) => self
-                .nfc_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::NFCSetID(_) => self
-                .nfc_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::DoorGetState(_) => self
-                .door_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::DoorSetState(_) => self
-                .door_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::KeyPadGetCode(_) => self
-                .keypad_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::KeyPadSetCode(_) => self
-                .keypad_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::PhoneGet(_) => self
-                .keypad_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
-            Requests::PhoneSet(_) => self
-                .keypad_channel
-                .0
-                .send(ThreadRequest(request, stream))
-                .unwrap(),
+    pub fn new(supervisor: Supervisor) -> Dispatcher {
+        Dispatcher {
+            routes: HashMap::new(),
+            supervisor,
         }
     }
-    pub fn new(
-        terminal_channel: ThreadSender<ThreadRequest, Terminal>,
-        door_channel: ThreadSender<ThreadRequest, Door>,
-        keypad_channel: ThreadSender<ThreadRequest, KeyPad>,
-        nfc_channel: ThreadSender<ThreadRequest, NFCdev>,
-    ) -> Dispatcher {
-        Dispatcher {
-            terminal_channel,
-            door_channel,
-            keypad_channel,
-            nfc_channel,
+
+    /// Sends every request the device handles to its channel.
+    pub fn register<T: Handles>(&mut self, channel: &ThreadSender<DeviceRequest, T>) {
+        for kind in T::REQUESTS {
+            if self.routes.insert(*kind, channel.0.clone()).is_some() {
+                panic!("{:?} is handled by more than one device", kind);
+            }
+        }
+    }
+
+    pub fn dispatch(&self, request: Requests, mut stream: Connection) {
+        // A failed write below means the client is gone; there is no one left to tell.
+        let request = match request {
+            Requests::Hello(x) => {
+                let mut response = x.get_response(self.supervisor.inventory());
+                if let Ok(capabilities) = &mut response.1 {
+                    capabilities.protocol_version = stream.version();
+                }
+                let _ = stream.write(&Responses::Hello(response));
+                return;
+            }
+            Requests::Shutdown(x) => {
+                println!("Shutdown requested");
+                let response = x.get_response(&mut self.supervisor.clone());
+                let _ = stream.write(&Responses::Shutdown(response));
+                return;
+            }
+            request => request,
+        };
+        let id = request.get_id();
+        let kind = request.kind();
+        let error = match self.routes.get(&kind) {
+            Some(route) => match route.send(ThreadRequest(request, stream).into()) {
+                Ok(()) => return,
+                Err(mpsc::SendError(returned)) => {
+                    let DeviceRequest::Relayed(ThreadRequest(_, returned)) = returned else {
+                        unreachable!("Sent a relayed request");
+                    };
+                    stream = returned;
+                    format!("The device handling {:?} has stopped", kind)
+                }
+            },
+            None => format!("No device handles {:?}", kind),
+        };
+        let _ = stream.write(&Responses::Error(id, error));
+    }
+}
+
+/// The ID leading the fields of a request, with the rest ignored.
+struct LeadingId(ID);
+
+impl<'de> Deserialize<'de> for LeadingId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LeadingId, D::Error> {
+        struct LeadingIdVisitor;
+
+        impl<'de> Visitor<'de> for LeadingIdVisitor {
+            type Value = LeadingId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "request fields starting with an ID")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LeadingId, A::Error> {
+                let id = seq.next_element()?.unwrap_or(ID(0));
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+                Ok(LeadingId(id))
+            }
         }
+
+        deserializer.deserialize_seq(LeadingIdVisitor)
     }
 }
 
-pub fn start_server(dispatcher: Dispatcher, listener: TcpListener) {
-    for stream in listener.incoming() {
-        if let Err(_) = stream {
-            continue;
+/// Best effort at finding the ID of a request that failed to decode, so the error can be
+/// matched to it. Requests are encoded as `{"Variant": [id, ...]}`.
+fn request_id(frame: &[u8], encoding: Encoding) -> ID {
+    encoding
+        .decode::<HashMap<String, LeadingId>>(frame)
+        .ok()
+        .and_then(|x| x.into_values().next())
+        .map_or(ID(0), |x| x.0)
+}
+
+fn serve_connection(dispatcher: Dispatcher, stream: TcpStream, link: Link) {
+    let peer = stream
+        .peer_addr()
+        .map_or_else(|_| "unknown peer".to_string(), |x| x.to_string());
+    let mut connection = match link.accept(stream) {
+        Ok(connection) => connection,
+        Err(error) => {
+            println!("Rejected connection from {}: {}", peer, error);
+            return;
         }
-        let mut stream = stream.unwrap();
-        {
-            let dispatcher = dispatcher.clone();
-            thread::spawn(move || {
-                let request = read_from_stream(&mut stream).unwrap();
-                dispatcher.dispatch(request, stream);
-            });
+    };
+    loop {
+        let frame = match connection.read_frame() {
+            Ok(frame) => frame,
+            Err(Error::Disconnected) => return,
+            Err(error) => {
+                println!("Dropping connection from {}: {}", peer, error);
+                return;
+            }
+        };
+        let mut reply = match connection.try_clone() {
+            Ok(reply) => reply,
+            Err(_) => return,
+        };
+        match connection.decode::<Requests>(&frame) {
+            Ok(request) => dispatcher.dispatch(request, reply),
+            Err(error) => {
+                println!("Invalid request from {}: {}", peer, error);
+                let id = request_id(&frame, connection.encoding());
+                if reply
+                    .write(&Responses::Error(id, error.to_string()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
         }
     }
 }
+
+/// Serves requests, first authenticating each connection when a link key is set and
+/// agreeing on the protocol version and encoding. A connection stays open for any number
+/// of requests; each response carries the ID of its request, so they may arrive out of
+/// order.
+pub fn start_server(dispatcher: Dispatcher, listener: TcpListener, link: Link) {
+    for stream in listener.incoming().flatten() {
+        let dispatcher = dispatcher.clone();
+        let link = link.clone();
+        thread::spawn(move || serve_connection(dispatcher, stream, link));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::Inventory;
+    use crate::device::door::{DoorList, Doors};
+    use crate::device::terminal::{Terminal, Text};
+    use crate::message::PROTOCOL_VERSION;
+    use crate::request::{BasicGetRequest, BasicSetRequest};
+    use std::marker::PhantomData;
+
+    fn connection_pair() -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let open = |x| Connection::new(x, Encoding::Json, PROTOCOL_VERSION);
+        (open(client), open(server))
+    }
+
+    #[test]
+    fn test_routes() {
+        let (sender, receiver) = mpsc::channel();
+        let mut dispatcher = Dispatcher::new(Supervisor::new(Inventory::default()));
+        dispatcher.register(&ThreadSender::<DeviceRequest, Doors>(sender, PhantomData));
+        let (mut client, server) = connection_pair();
+
+        let request = BasicGetRequest::<Doors, DoorList>(ID(1), PhantomData, PhantomData);
+        dispatcher.dispatch(Requests::DoorGetState(request), server.try_clone().unwrap());
+        let DeviceRequest::Relayed(ThreadRequest(routed, _)) = receiver.try_recv().unwrap() else {
+            panic!("Expected a relayed request");
+        };
+        assert_eq!(routed.kind(), RequestKind::DoorGetState);
+
+        let request = BasicSetRequest::<Terminal, Text>(ID(2), Text(String::new()), PhantomData);
+        dispatcher.dispatch(
+            Requests::TerminalSetText(request),
+            server.try_clone().unwrap(),
+        );
+        assert!(matches!(
+            client.read::<Responses>().unwrap(),
+            Responses::Error(ID(2), _)
+        ));
+
+        drop(receiver);
+        let request = BasicGetRequest::<Doors, DoorList>(ID(3), PhantomData, PhantomData);
+        dispatcher.dispatch(Requests::DoorGetState(request), server);
+        assert!(matches!(
+            client.read::<Responses>().unwrap(),
+            Responses::Error(ID(3), _)
+        ));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_register_twice() {
+        let (sender, _receiver) = mpsc::channel();
+        let channel = ThreadSender::<DeviceRequest, Doors>(sender, PhantomData);
+        let mut dispatcher = Dispatcher::new(Supervisor::new(Inventory::default()));
+        dispatcher.register(&channel);
+        dispatcher.register(&channel);
+    }
+
+    #[test]
+    fn test_request_id() {
+        let json = br#"{"DoorGetState":[42,null,null]}"#;
+        assert_eq!(request_id(json, Encoding::Json), ID(42));
+        assert_eq!(
+            request_id(br#"{"NoSuchRequest":[7]}"#, Encoding::Json),
+            ID(7)
+        );
+        assert_eq!(request_id(b"not json", Encoding::Json), ID(0));
+        let mut fields = HashMap::new();
+        fields.insert("NoSuchRequest", (ID(9), "anything"));
+        let cbor = Encoding::Cbor.encode(&fields).unwrap();
+        assert_eq!(request_id(&cbor, Encoding::Cbor), ID(9));
+    }
+}
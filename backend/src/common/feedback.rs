@@ -0,0 +1,161 @@
+//! Patterns played on the buzzer and status LEDs by the keypad, so visitors can tell
+//! whether their code or card was accepted.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FeedbackConfig {
+    /// GPIO numbers of an active buzzer and the two LEDs; outputs left unset are skipped.
+    pub buzzer: Option<u64>,
+    pub red_led: Option<u64>,
+    pub green_led: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Signal {
+    Keypress,
+    /// A code or card opened the door.
+    Accepted,
+    Rejected,
+    Ringing,
+    /// The keypad stopped taking codes, or got some while it was locked.
+    LockedOut,
+}
+
+/// What the buzzer and LEDs show during one step of a pattern.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Outputs {
+    pub buzzer: bool,
+    pub red: bool,
+    pub green: bool,
+}
+
+const OFF: Outputs = Outputs {
+    buzzer: false,
+    red: false,
+    green: false,
+};
+const BEEP: Outputs = Outputs {
+    buzzer: true,
+    ..OFF
+};
+const RED: Outputs = Outputs { red: true, ..OFF };
+const RED_BEEP: Outputs = Outputs {
+    buzzer: true,
+    red: true,
+    ..OFF
+};
+const GREEN: Outputs = Outputs { green: true, ..OFF };
+const GREEN_BEEP: Outputs = Outputs {
+    buzzer: true,
+    green: true,
+    ..OFF
+};
+
+impl Signal {
+    /// Each step's outputs and how long they last in milliseconds.
+    fn pattern(&self) -> &'static [(Outputs, u64)] {
+        match self {
+            Signal::Keypress => &[(BEEP, 30)],
+            Signal::Accepted => &[(GREEN_BEEP, 150), (GREEN, 1850)],
+            Signal::Rejected => &[
+                (RED_BEEP, 100),
+                (RED, 100),
+                (RED_BEEP, 100),
+                (RED, 100),
+                (RED_BEEP, 100),
+                (RED, 500),
+            ],
+            Signal::Ringing => &[(GREEN_BEEP, 400), (OFF, 200), (GREEN_BEEP, 400)],
+            Signal::LockedOut => &[(RED_BEEP, 1000), (RED, 2000)],
+        }
+    }
+
+    fn length(&self) -> Duration {
+        Duration::from_millis(self.pattern().iter().map(|(_, ms)| ms).sum())
+    }
+}
+
+/// Plays one pattern at a time; a new signal replaces the one playing.
+#[derive(Debug, Clone, Default)]
+pub struct Player {
+    playing: Option<(Signal, Instant)>,
+}
+
+impl Player {
+    /// Keypresses do not cut other patterns short, so the result of a code is not lost
+    /// when the visitor carries on typing.
+    pub fn play(&mut self, signal: Signal, now: Instant) {
+        if let Some((current, started)) = self.playing {
+            if signal == Signal::Keypress
+                && current != Signal::Keypress
+                && now.duration_since(started) < current.length()
+            {
+                return;
+            }
+        }
+        self.playing = Some((signal, now));
+    }
+
+    /// What the outputs show at `now` and how long until that changes, or `None` once
+    /// the pattern is over.
+    pub fn outputs(&mut self, now: Instant) -> (Outputs, Option<Duration>) {
+        let (signal, started) = match self.playing {
+            Some(playing) => playing,
+            None => return (OFF, None),
+        };
+        let elapsed = now.duration_since(started);
+        let mut end = Duration::ZERO;
+        for (outputs, ms) in signal.pattern() {
+            end += Duration::from_millis(*ms);
+            if elapsed < end {
+                return (*outputs, Some(end - elapsed));
+            }
+        }
+        self.playing = None;
+        (OFF, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_steps() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut player = Player::default();
+        assert_eq!(player.outputs(at(0)), (OFF, None));
+        player.play(Signal::Rejected, at(0));
+        assert_eq!(
+            player.outputs(at(0)),
+            (RED_BEEP, Some(Duration::from_millis(100)))
+        );
+        assert_eq!(
+            player.outputs(at(150)),
+            (RED, Some(Duration::from_millis(50)))
+        );
+        assert_eq!(player.outputs(at(999)).0, RED);
+        assert_eq!(player.outputs(at(1000)), (OFF, None));
+    }
+
+    #[test]
+    fn test_keypress_does_not_interrupt() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut player = Player::default();
+        player.play(Signal::Accepted, at(0));
+        player.play(Signal::Keypress, at(500));
+        assert_eq!(player.outputs(at(500)).0, GREEN);
+
+        // Once it is over, keypresses beep again.
+        player.play(Signal::Keypress, at(2000));
+        assert_eq!(player.outputs(at(2000)).0, BEEP);
+        // Anything else takes over straight away.
+        player.play(Signal::LockedOut, at(2010));
+        assert_eq!(player.outputs(at(2010)).0, RED_BEEP);
+    }
+}
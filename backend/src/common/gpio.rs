@@ -0,0 +1,569 @@
+//! Hardware abstraction over GPIO lines so devices are not tied to sysfs.
+
+use gpio_cdev::{Chip, EventRequestFlags, Line, LineEventHandle, LineHandle, LineRequestFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Error(pub String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<sysfs_gpio::Error> for Error {
+    fn from(error: sysfs_gpio::Error) -> Error {
+        Error(error.to_string())
+    }
+}
+
+impl From<gpio_cdev::Error> for Error {
+    fn from(error: gpio_cdev::Error) -> Error {
+        Error(error.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Which changes of an input's value `wait_for_edge` reports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Edge {
+    None,
+    Rising,
+    Falling,
+    Both,
+}
+
+impl Edge {
+    fn matches(&self, from: u8, to: u8) -> bool {
+        match self {
+            Edge::None => false,
+            Edge::Rising => from == 0 && to == 1,
+            Edge::Falling => from == 1 && to == 0,
+            Edge::Both => from != to,
+        }
+    }
+}
+
+pub trait GpioPin: std::marker::Send + Sync {
+    fn number(&self) -> u64;
+    fn export(&self) -> Result<()>;
+    fn unexport(&self) -> Result<()>;
+    fn set_direction(&self, direction: Direction) -> Result<()>;
+    fn set_active_low(&self, active_low: bool) -> Result<()>;
+    fn get_value(&self) -> Result<u8>;
+    fn set_value(&self, value: u8) -> Result<()>;
+    /// Arms an input to interrupt on `edge`.
+    fn set_edge(&self, edge: Edge) -> Result<()>;
+    /// Blocks until the armed edge happens or `timeout` passes, returning whether it
+    /// happened. May wake spuriously, so callers should read the pin to see what changed.
+    fn wait_for_edge(&self, timeout: Duration) -> Result<bool>;
+}
+
+pub type Pin = Arc<dyn GpioPin>;
+
+/// Longest a watcher blocks before checking whether it should stop.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Calls `on_edge` from a new thread each time the pin's armed edge happens, until
+/// `watching` is cleared or `on_edge` returns false.
+pub fn watch(
+    pin: Pin,
+    watching: Arc<AtomicBool>,
+    on_edge: impl Fn() -> bool + std::marker::Send + 'static,
+) {
+    thread::spawn(move || {
+        while watching.load(Ordering::Relaxed) {
+            match pin.wait_for_edge(WATCH_TIMEOUT) {
+                Ok(true) => {
+                    if !on_edge() {
+                        break;
+                    }
+                }
+                Ok(false) => (),
+                Err(error) => {
+                    println!("Unable to watch GPIO pin {}: {}", pin.number(), error);
+                    thread::sleep(WATCH_TIMEOUT);
+                }
+            }
+        }
+    });
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Sysfs,
+    Cdev,
+    Mock,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GpioConfig {
+    pub backend: BackendKind,
+    pub chip_prefix: String,
+    pub lines_per_chip: u64,
+}
+
+impl Default for GpioConfig {
+    fn default() -> GpioConfig {
+        GpioConfig {
+            backend: BackendKind::Sysfs,
+            chip_prefix: "/dev/gpiochip".to_string(),
+            lines_per_chip: 32,
+        }
+    }
+}
+
+/// Hands out pins for the backend chosen in the config. Pin numbers are always the
+/// global sysfs numbers; the cdev backend maps them onto chip and line offset.
+#[derive(Clone)]
+pub enum Backend {
+    Sysfs,
+    Cdev {
+        chip_prefix: String,
+        lines_per_chip: u64,
+    },
+    Mock(MockBoard),
+}
+
+impl Backend {
+    pub fn from_config(config: &GpioConfig) -> Backend {
+        match config.backend {
+            BackendKind::Sysfs => Backend::Sysfs,
+            BackendKind::Cdev => Backend::Cdev {
+                chip_prefix: config.chip_prefix.clone(),
+                lines_per_chip: config.lines_per_chip,
+            },
+            BackendKind::Mock => Backend::Mock(MockBoard::default()),
+        }
+    }
+
+    pub fn pin(&self, number: u64) -> Result<Pin> {
+        match self {
+            Backend::Sysfs => Ok(Arc::new(SysfsPin::new(number))),
+            Backend::Cdev {
+                chip_prefix,
+                lines_per_chip,
+            } => {
+                let mut chip = Chip::new(format!("{}{}", chip_prefix, number / lines_per_chip))?;
+                let line = chip.get_line((number % lines_per_chip) as u32)?;
+                Ok(Arc::new(CdevPin::new(number, line)))
+            }
+            Backend::Mock(board) => Ok(Arc::new(board.pin(number))),
+        }
+    }
+}
+
+pub struct SysfsPin {
+    pin: sysfs_gpio::Pin,
+    /// Kept open between waits so edges are not missed in between.
+    poller: Mutex<Option<sysfs_gpio::PinPoller>>,
+}
+
+impl SysfsPin {
+    fn new(number: u64) -> SysfsPin {
+        SysfsPin {
+            pin: sysfs_gpio::Pin::new(number),
+            poller: Mutex::new(None),
+        }
+    }
+}
+
+impl GpioPin for SysfsPin {
+    fn number(&self) -> u64 {
+        self.pin.get_pin_num()
+    }
+    fn export(&self) -> Result<()> {
+        self.pin.export().map_err(Error::from)
+    }
+    fn unexport(&self) -> Result<()> {
+        *self.poller.lock().unwrap() = None;
+        self.pin.unexport().map_err(Error::from)
+    }
+    fn set_direction(&self, direction: Direction) -> Result<()> {
+        let direction = match direction {
+            Direction::In => sysfs_gpio::Direction::In,
+            Direction::Out => sysfs_gpio::Direction::Out,
+        };
+        self.pin.set_direction(direction).map_err(Error::from)
+    }
+    fn set_active_low(&self, active_low: bool) -> Result<()> {
+        self.pin.set_active_low(active_low).map_err(Error::from)
+    }
+    fn get_value(&self) -> Result<u8> {
+        self.pin.get_value().map_err(Error::from)
+    }
+    fn set_value(&self, value: u8) -> Result<()> {
+        self.pin.set_value(value).map_err(Error::from)
+    }
+    fn set_edge(&self, edge: Edge) -> Result<()> {
+        let edge = match edge {
+            Edge::None => sysfs_gpio::Edge::NoInterrupt,
+            Edge::Rising => sysfs_gpio::Edge::RisingEdge,
+            Edge::Falling => sysfs_gpio::Edge::FallingEdge,
+            Edge::Both => sysfs_gpio::Edge::BothEdges,
+        };
+        self.pin.set_edge(edge)?;
+        *self.poller.lock().unwrap() = Some(self.pin.get_poller()?);
+        Ok(())
+    }
+    fn wait_for_edge(&self, timeout: Duration) -> Result<bool> {
+        let mut poller = self.poller.lock().unwrap();
+        match poller.as_mut() {
+            Some(poller) => Ok(poller.poll(timeout.as_millis() as isize)?.is_some()),
+            None => Err(Error(format!("GPIO {} has no edge set", self.number()))),
+        }
+    }
+}
+
+struct CdevState {
+    handle: Option<LineHandle>,
+    /// Used instead of `handle` while an input has an edge set.
+    events: Option<LineEventHandle>,
+    direction: Direction,
+    active_low: bool,
+    value: u8,
+    edge: Edge,
+}
+
+/// A line on a `/dev/gpiochipN` character device. The kernel fixes direction and
+/// polarity when a line is requested, so changing either re-requests the line.
+pub struct CdevPin {
+    number: u64,
+    line: Line,
+    state: Mutex<CdevState>,
+}
+
+impl CdevPin {
+    const CONSUMER: &'static str = "cautious-axela";
+    fn new(number: u64, line: Line) -> CdevPin {
+        CdevPin {
+            number,
+            line,
+            state: Mutex::new(CdevState {
+                handle: None,
+                events: None,
+                direction: Direction::In,
+                active_low: false,
+                value: 0,
+                edge: Edge::None,
+            }),
+        }
+    }
+    fn request(&self, state: &mut CdevState) -> Result<()> {
+        // The old handle must be released before the line can be requested again.
+        state.handle = None;
+        state.events = None;
+        let mut flags = match state.direction {
+            Direction::In => LineRequestFlags::INPUT,
+            Direction::Out => LineRequestFlags::OUTPUT,
+        };
+        if state.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+        let edges = match state.edge {
+            Edge::None => EventRequestFlags::empty(),
+            Edge::Rising => EventRequestFlags::RISING_EDGE,
+            Edge::Falling => EventRequestFlags::FALLING_EDGE,
+            Edge::Both => EventRequestFlags::BOTH_EDGES,
+        };
+        if state.direction == Direction::In && !edges.is_empty() {
+            state.events = Some(self.line.events(flags, edges, CdevPin::CONSUMER)?);
+        } else {
+            state.handle = Some(self.line.request(flags, state.value, CdevPin::CONSUMER)?);
+        }
+        Ok(())
+    }
+}
+
+impl GpioPin for CdevPin {
+    fn number(&self) -> u64 {
+        self.number
+    }
+    fn export(&self) -> Result<()> {
+        Ok(())
+    }
+    fn unexport(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.handle = None;
+        state.events = None;
+        Ok(())
+    }
+    fn set_direction(&self, direction: Direction) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.direction = direction;
+        self.request(&mut state)
+    }
+    fn set_active_low(&self, active_low: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.active_low = active_low;
+        self.request(&mut state)
+    }
+    fn get_value(&self) -> Result<u8> {
+        let mut state = self.state.lock().unwrap();
+        if state.handle.is_none() && state.events.is_none() {
+            self.request(&mut state)?;
+        }
+        match (&state.handle, &state.events) {
+            (Some(handle), _) => handle.get_value().map_err(Error::from),
+            (_, Some(events)) => events.get_value().map_err(Error::from),
+            _ => unreachable!(),
+        }
+    }
+    fn set_value(&self, value: u8) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.direction != Direction::Out {
+            return Err(Error(format!("GPIO {} is not an output", self.number)));
+        }
+        state.value = value;
+        if state.handle.is_none() {
+            return self.request(&mut state);
+        }
+        state
+            .handle
+            .as_ref()
+            .unwrap()
+            .set_value(value)
+            .map_err(Error::from)
+    }
+    fn set_edge(&self, edge: Edge) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.edge = edge;
+        self.request(&mut state)
+    }
+    fn wait_for_edge(&self, timeout: Duration) -> Result<bool> {
+        // The lock is not held while waiting so the value can still be read meanwhile.
+        let fd = match &self.state.lock().unwrap().events {
+            Some(events) => events.as_raw_fd(),
+            None => return Err(Error(format!("GPIO {} has no edge set", self.number))),
+        };
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            return Err(Error(std::io::Error::last_os_error().to_string()));
+        }
+        if ready == 0 {
+            return Ok(false);
+        }
+        // The line may have been re-requested while waiting; then there is nothing to read.
+        if let Some(events) = self.state.lock().unwrap().events.as_mut() {
+            if events.as_raw_fd() == fd {
+                events.get_event()?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+pub type MockInput = Arc<dyn Fn() -> u8 + std::marker::Send + Sync>;
+
+struct MockState {
+    exported: bool,
+    direction: Direction,
+    active_low: bool,
+    level: u8,
+    input: Option<MockInput>,
+    edge: Edge,
+}
+
+/// An in-memory pin. Outputs record the level they drive; inputs read a level set by
+/// the test or simulator, either directly or through a closure.
+#[derive(Clone)]
+pub struct MockPin {
+    number: u64,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockPin {
+    const SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+    fn new(number: u64) -> MockPin {
+        MockPin {
+            number,
+            state: Arc::new(Mutex::new(MockState {
+                exported: false,
+                direction: Direction::In,
+                active_low: false,
+                level: 1,
+                input: None,
+                edge: Edge::None,
+            })),
+        }
+    }
+    pub fn is_exported(&self) -> bool {
+        self.state.lock().unwrap().exported
+    }
+    pub fn direction(&self) -> Direction {
+        self.state.lock().unwrap().direction
+    }
+    /// Physical level of the line, ignoring `active_low`.
+    pub fn level(&self) -> u8 {
+        let state = self.state.lock().unwrap();
+        match (state.direction, &state.input) {
+            (Direction::In, Some(input)) => {
+                let input = input.clone();
+                drop(state);
+                input()
+            }
+            _ => state.level,
+        }
+    }
+    pub fn set_level(&self, level: u8) {
+        self.state.lock().unwrap().level = level;
+    }
+    pub fn set_input(&self, input: MockInput) {
+        self.state.lock().unwrap().input = Some(input);
+    }
+}
+
+impl GpioPin for MockPin {
+    fn number(&self) -> u64 {
+        self.number
+    }
+    fn export(&self) -> Result<()> {
+        self.state.lock().unwrap().exported = true;
+        Ok(())
+    }
+    fn unexport(&self) -> Result<()> {
+        self.state.lock().unwrap().exported = false;
+        Ok(())
+    }
+    fn set_direction(&self, direction: Direction) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // Like sysfs, switching to an output drives the line low.
+        if direction == Direction::Out && state.direction == Direction::In {
+            state.level = state.active_low as u8;
+        }
+        state.direction = direction;
+        Ok(())
+    }
+    fn set_active_low(&self, active_low: bool) -> Result<()> {
+        self.state.lock().unwrap().active_low = active_low;
+        Ok(())
+    }
+    fn get_value(&self) -> Result<u8> {
+        let active_low = self.state.lock().unwrap().active_low;
+        Ok(self.level() ^ active_low as u8)
+    }
+    fn set_value(&self, value: u8) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.direction != Direction::Out {
+            return Err(Error(format!("GPIO {} is not an output", self.number)));
+        }
+        state.level = value ^ state.active_low as u8;
+        Ok(())
+    }
+    fn set_edge(&self, edge: Edge) -> Result<()> {
+        self.state.lock().unwrap().edge = edge;
+        Ok(())
+    }
+    // Inputs may be closures, so there is nothing to be told of; sample the value instead.
+    fn wait_for_edge(&self, timeout: Duration) -> Result<bool> {
+        let edge = self.state.lock().unwrap().edge;
+        let started = Instant::now();
+        let mut last = self.get_value()?;
+        while started.elapsed() < timeout {
+            thread::sleep(MockPin::SAMPLE_INTERVAL);
+            let value = self.get_value()?;
+            if edge.matches(last, value) {
+                return Ok(true);
+            }
+            last = value;
+        }
+        Ok(false)
+    }
+}
+
+/// Shared set of mock pins, so whoever builds the devices can also poke their inputs
+/// and watch their outputs.
+#[derive(Clone, Default)]
+pub struct MockBoard(Arc<Mutex<HashMap<u64, MockPin>>>);
+
+impl MockBoard {
+    pub fn pin(&self, number: u64) -> MockPin {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(number)
+            .or_insert_with(|| MockPin::new(number))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_output() {
+        let board = MockBoard::default();
+        let backend = Backend::Mock(board.clone());
+        let pin = backend.pin(50).unwrap();
+        pin.export().unwrap();
+        assert!(pin.set_value(1).is_err());
+        pin.set_direction(Direction::Out).unwrap();
+        pin.set_value(1).unwrap();
+        assert_eq!(board.pin(50).level(), 1);
+        assert!(board.pin(50).is_exported());
+    }
+
+    #[test]
+    fn test_mock_active_low() {
+        let board = MockBoard::default();
+        let pin = Backend::Mock(board.clone()).pin(3).unwrap();
+        pin.set_active_low(true).unwrap();
+        board.pin(3).set_level(0);
+        assert_eq!(pin.get_value().unwrap(), 1);
+        pin.set_direction(Direction::Out).unwrap();
+        pin.set_value(1).unwrap();
+        assert_eq!(board.pin(3).level(), 0);
+    }
+
+    #[test]
+    fn test_mock_edge() {
+        let board = MockBoard::default();
+        let pin = Backend::Mock(board.clone()).pin(66).unwrap();
+        pin.set_edge(Edge::Falling).unwrap();
+        assert!(!pin.wait_for_edge(Duration::from_millis(20)).unwrap());
+        let input = board.pin(66);
+        let falling = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            input.set_level(0);
+        });
+        assert!(pin.wait_for_edge(Duration::from_secs(1)).unwrap());
+        falling.join().unwrap();
+    }
+
+    #[test]
+    fn test_mock_input() {
+        let board = MockBoard::default();
+        let driver = board.pin(2);
+        let reader = board.pin(66);
+        reader.set_input(Arc::new(move || driver.level()));
+        let pin = Backend::Mock(board.clone()).pin(66).unwrap();
+        board.pin(2).set_level(0);
+        assert_eq!(pin.get_value().unwrap(), 0);
+        board.pin(2).set_level(1);
+        assert_eq!(pin.get_value().unwrap(), 1);
+    }
+}
@@ -0,0 +1,315 @@
+//! Keypad wiring and layout, and turning raw reads of the matrix into debounced,
+//! timestamped presses and releases.
+
+use crate::request::Error;
+use crate::sequences::{self, Sequence};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Codes are entered by pressing this key, so every layout needs one.
+pub const ENTER_KEY: char = '#';
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KeypadConfig {
+    /// GPIO numbers of the rows, top to bottom, and of the columns, left to right.
+    pub rows: Vec<u64>,
+    pub cols: Vec<u64>,
+    /// One string per row giving the key at each column.
+    pub keys: Vec<String>,
+    /// What the keypad does for inputs other than codes.
+    pub sequences: Vec<Sequence>,
+    /// A key has to read the same for this long before a press or release counts.
+    pub debounce_ms: u64,
+    /// How often the matrix is read while a key is down; it is idle otherwise.
+    pub scan_interval_ms: u64,
+    /// Doors the keypad opens, by name. Empty means every door.
+    pub doors: Vec<String>,
+}
+
+impl Default for KeypadConfig {
+    /// The 4x4 membrane keypad on the original board.
+    fn default() -> KeypadConfig {
+        KeypadConfig {
+            rows: vec![3, 2, 15, 115],
+            cols: vec![66, 67, 69, 68],
+            keys: ["123A", "456B", "789C", "*0#D"]
+                .map(str::to_string)
+                .to_vec(),
+            sequences: sequences::default_sequences(),
+            debounce_ms: 20,
+            scan_interval_ms: 5,
+            doors: Vec::new(),
+        }
+    }
+}
+
+impl KeypadConfig {
+    /// The key at each row and column.
+    pub fn layout(&self) -> Vec<Vec<char>> {
+        self.keys.iter().map(|row| row.chars().collect()).collect()
+    }
+
+    /// The row and column of `key`, if it is on the keypad.
+    pub fn position(&self, key: char) -> Option<(usize, usize)> {
+        self.layout()
+            .iter()
+            .enumerate()
+            .find_map(|(i, row)| row.iter().position(|x| *x == key).map(|j| (i, j)))
+    }
+
+    /// Checks that the key map matches the matrix and no pin or key is used twice.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.rows.is_empty() || self.cols.is_empty() {
+            return Err(Error(
+                "The keypad needs at least one row and column".to_string(),
+            ));
+        }
+        let mut pins = HashSet::new();
+        for pin in self.rows.iter().chain(self.cols.iter()) {
+            if !pins.insert(pin) {
+                return Err(Error(format!("GPIO {} is used more than once", pin)));
+            }
+        }
+        let layout = self.layout();
+        if layout.len() != self.rows.len() {
+            return Err(Error(format!(
+                "{} rows of keys for {} row pins",
+                layout.len(),
+                self.rows.len()
+            )));
+        }
+        let mut keys = HashSet::new();
+        for (i, row) in layout.iter().enumerate() {
+            if row.len() != self.cols.len() {
+                return Err(Error(format!(
+                    "Row {} has {} keys for {} column pins",
+                    i + 1,
+                    row.len(),
+                    self.cols.len()
+                )));
+            }
+            for key in row {
+                if !keys.insert(*key) {
+                    return Err(Error(format!("Key '{}' appears more than once", key)));
+                }
+            }
+        }
+        if !keys.contains(&ENTER_KEY) {
+            return Err(Error(format!("There is no '{}' key", ENTER_KEY)));
+        }
+        sequences::validate(&self.sequences, &keys)?;
+        if self.scan_interval_ms == 0 {
+            return Err(Error("The scan interval cannot be zero".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+
+    pub fn scan_interval(&self) -> Duration {
+        Duration::from_millis(self.scan_interval_ms)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyAction {
+    Press,
+    Release,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: char,
+    pub action: KeyAction,
+    /// When the change was first read, before it had settled.
+    pub at: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct KeyState {
+    down: bool,
+    /// Since when the key has read differently from `down`.
+    changing_since: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    debounce: Duration,
+    /// Keys that are down or settling; the rest are up.
+    keys: BTreeMap<char, KeyState>,
+}
+
+impl Debouncer {
+    pub fn new(debounce: Duration) -> Debouncer {
+        Debouncer {
+            debounce,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Takes one read of the keys that are down and returns the changes that have settled,
+    /// oldest first.
+    pub fn update(&mut self, down: &HashSet<char>, now: Instant) -> Vec<KeyEvent> {
+        for key in down {
+            self.keys.entry(*key).or_insert(KeyState {
+                down: false,
+                changing_since: None,
+            });
+        }
+        let mut events = Vec::new();
+        for (key, state) in self.keys.iter_mut() {
+            let reads_down = down.contains(key);
+            if reads_down == state.down {
+                state.changing_since = None;
+                continue;
+            }
+            let since = *state.changing_since.get_or_insert(now);
+            if now.duration_since(since) >= self.debounce {
+                state.down = reads_down;
+                state.changing_since = None;
+                events.push(KeyEvent {
+                    key: *key,
+                    action: if reads_down {
+                        KeyAction::Press
+                    } else {
+                        KeyAction::Release
+                    },
+                    at: since,
+                });
+            }
+        }
+        self.keys
+            .retain(|_, state| state.down || state.changing_since.is_some());
+        events.sort_by_key(|event| event.at);
+        events
+    }
+
+    /// Whether any key is down or settling, so the matrix needs to be read again.
+    pub fn is_active(&self) -> bool {
+        !self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &str) -> HashSet<char> {
+        keys.chars().collect()
+    }
+
+    fn actions(events: &[KeyEvent]) -> Vec<(char, KeyAction)> {
+        events.iter().map(|x| (x.key, x.action)).collect()
+    }
+
+    #[test]
+    fn test_press_and_release_settle() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = Debouncer::new(Duration::from_millis(20));
+        assert!(debouncer.update(&keys("1"), at(0)).is_empty());
+        assert!(debouncer.is_active());
+        let events = debouncer.update(&keys("1"), at(20));
+        assert_eq!(actions(&events), vec![('1', KeyAction::Press)]);
+        assert_eq!(events[0].at, at(0));
+
+        assert!(debouncer.update(&keys(""), at(100)).is_empty());
+        let events = debouncer.update(&keys(""), at(125));
+        assert_eq!(actions(&events), vec![('1', KeyAction::Release)]);
+        assert_eq!(events[0].at, at(100));
+        assert!(!debouncer.is_active());
+    }
+
+    #[test]
+    fn test_bounces_are_ignored() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = Debouncer::new(Duration::from_millis(20));
+        // A glitch shorter than the debounce time never becomes a press.
+        debouncer.update(&keys("5"), at(0));
+        assert!(debouncer.update(&keys(""), at(5)).is_empty());
+        assert!(!debouncer.is_active());
+
+        // Contacts bouncing on release do not produce a second press.
+        debouncer.update(&keys("5"), at(10));
+        debouncer.update(&keys("5"), at(30));
+        debouncer.update(&keys(""), at(40));
+        debouncer.update(&keys("5"), at(45));
+        debouncer.update(&keys(""), at(50));
+        let events = debouncer.update(&keys(""), at(70));
+        assert_eq!(actions(&events), vec![('5', KeyAction::Release)]);
+    }
+
+    #[test]
+    fn test_overlapping_keys_keep_order() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = Debouncer::new(Duration::from_millis(20));
+        // Fast typing: 2 goes down before 1 is let go.
+        debouncer.update(&keys("1"), at(0));
+        debouncer.update(&keys("12"), at(10));
+        assert_eq!(
+            actions(&debouncer.update(&keys("12"), at(30))),
+            vec![('1', KeyAction::Press), ('2', KeyAction::Press)]
+        );
+        debouncer.update(&keys("2"), at(40));
+        assert_eq!(
+            actions(&debouncer.update(&keys(""), at(60))),
+            vec![('1', KeyAction::Release)]
+        );
+        assert_eq!(
+            actions(&debouncer.update(&keys(""), at(80))),
+            vec![('2', KeyAction::Release)]
+        );
+    }
+
+    #[test]
+    fn test_validate_layout() {
+        assert!(KeypadConfig::default().validate().is_ok());
+        let phone = KeypadConfig {
+            rows: vec![3, 2, 15, 115],
+            cols: vec![66, 67, 69],
+            keys: ["123", "456", "789", "*0#"].map(str::to_string).to_vec(),
+            ..KeypadConfig::default()
+        };
+        assert!(phone.validate().is_ok());
+        assert_eq!(phone.position('0'), Some((3, 1)));
+        assert_eq!(phone.position('A'), None);
+
+        let short_row = KeypadConfig {
+            keys: ["123", "456", "789", "*0#"].map(str::to_string).to_vec(),
+            ..KeypadConfig::default()
+        };
+        assert!(short_row.validate().is_err());
+        let shared_pin = KeypadConfig {
+            cols: vec![66, 67, 69, 3],
+            ..KeypadConfig::default()
+        };
+        assert!(shared_pin.validate().is_err());
+        let no_enter = KeypadConfig {
+            keys: ["123A", "456B", "789C", "*0ED"]
+                .map(str::to_string)
+                .to_vec(),
+            ..KeypadConfig::default()
+        };
+        assert!(no_enter.validate().is_err());
+        let repeated = KeypadConfig {
+            keys: ["123A", "456B", "789C", "*0#1"]
+                .map(str::to_string)
+                .to_vec(),
+            ..KeypadConfig::default()
+        };
+        assert!(repeated.validate().is_err());
+    }
+
+    #[test]
+    fn test_no_debounce() {
+        let mut debouncer = Debouncer::new(Duration::ZERO);
+        let events = debouncer.update(&keys("#"), Instant::now());
+        assert_eq!(actions(&events), vec![('#', KeyAction::Press)]);
+    }
+}
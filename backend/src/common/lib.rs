@@ -1,6 +1,24 @@
+pub mod audit;
 pub mod build;
+pub mod capabilities;
+pub mod cards;
+pub mod codes;
+pub mod config;
 pub mod device;
 pub mod dispatch;
+pub mod feedback;
+pub mod gpio;
+pub mod keyscan;
+pub mod link;
+pub mod lockout;
 pub mod message;
+pub mod notify;
 pub mod request;
 pub mod requests_and_responses;
+pub mod schedule;
+pub mod sequences;
+pub mod supervisor;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod users;
@@ -0,0 +1,442 @@
+//! Opening connections to the intercom's request port.
+//!
+//! With a pre-shared key, the intercom sends a random challenge, the client answers with
+//! its own nonce and an HMAC-SHA256 over both, and the intercom then proves it knows the
+//! key the same way. Both ends then derive a key for each direction from the link key and
+//! the two nonces, and every later frame is encrypted with AES-256-GCM under a nonce
+//! counting the frames sent, so codes cannot be read off the wire and a frame that is
+//! altered, replayed, dropped or injected fails to open and closes the connection.
+//!
+//! The client then offers the newest protocol version it speaks and the encodings it
+//! accepts, and the intercom answers with the ones both sides will use.
+
+use crate::message::{
+    read_frame, write_frame, Connection, Encoding, Error, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const NONCE_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LinkConfig {
+    /// File holding the key shared by the intercom and the web server. When unset, anyone
+    /// who can reach port 2000 may send requests and read them, codes included.
+    pub key_file: Option<String>,
+    /// Encodings this end accepts, most preferred first.
+    pub encodings: Vec<Encoding>,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            key_file: None,
+            encodings: vec![Encoding::Cbor, Encoding::Json],
+        }
+    }
+}
+
+impl LinkConfig {
+    /// Reads the key file, if any.
+    pub fn link(&self) -> Link {
+        let key = self.key_file.as_ref().map(|path| {
+            let key = fs::read_to_string(path)
+                .unwrap_or_else(|error| panic!("Could not read link key {}: {}", path, error));
+            key.trim().as_bytes().to_vec()
+        });
+        if key.is_none() {
+            println!("No link.key_file set, the intercom link is not authenticated or encrypted");
+        }
+        Link::new(key, self.encodings.clone())
+    }
+}
+
+/// Sent by the client once authenticated.
+#[derive(Serialize, Deserialize, Debug)]
+struct Offer {
+    version: u32,
+    encodings: Vec<Encoding>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Answer {
+    Accept { version: u32, encoding: Encoding },
+    Refuse(String),
+}
+
+/// Everything needed to open or accept a connection on either end.
+#[derive(Debug, Clone)]
+pub struct Link {
+    key: Option<Vec<u8>>,
+    encodings: Vec<Encoding>,
+}
+
+fn mac(key: &[u8], role: &[u8], server_nonce: &[u8], client_nonce: &[u8]) -> Vec<u8> {
+    let key = PKey::hmac(key).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    signer.update(role).unwrap();
+    signer.update(server_nonce).unwrap();
+    signer.update(client_nonce).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    rand_bytes(&mut nonce).expect("Could not generate random bytes");
+    nonce
+}
+
+/// Frames one way on a sealed connection.
+struct Direction {
+    key: Vec<u8>,
+    /// Frames sealed or opened so far, which makes up the nonce of the next one.
+    count: u64,
+}
+
+impl Direction {
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.count.to_be_bytes());
+        self.count += 1;
+        nonce
+    }
+}
+
+/// Encrypts the frames one end sends and opens the ones it receives, once both ends have
+/// proven they know the link key.
+pub struct Seal {
+    sending: Mutex<Direction>,
+    receiving: Mutex<Direction>,
+}
+
+impl Seal {
+    fn new(key: &[u8], client: bool, server_nonce: &[u8], client_nonce: &[u8]) -> Seal {
+        let direction = |role: &[u8]| {
+            Mutex::new(Direction {
+                key: mac(key, role, server_nonce, client_nonce),
+                count: 0,
+            })
+        };
+        let (to_server, to_client) = (direction(b"to server"), direction(b"to client"));
+        if client {
+            Seal {
+                sending: to_server,
+                receiving: to_client,
+            }
+        } else {
+            Seal {
+                sending: to_client,
+                receiving: to_server,
+            }
+        }
+    }
+
+    // The lock is held until the frame is written so frames reach the wire in the order
+    // they were numbered, whichever clone of the connection sends them.
+    pub fn write_frame(&self, stream: &mut impl Write, payload: &[u8]) -> Result<(), Error> {
+        let mut sending = self.sending.lock().unwrap();
+        let nonce = sending.next_nonce();
+        let mut tag = [0; TAG_LEN];
+        let cipher = Cipher::aes_256_gcm();
+        let mut frame = encrypt_aead(cipher, &sending.key, Some(&nonce), &[], payload, &mut tag)
+            .map_err(|error| Error::Encode(error.to_string()))?;
+        frame.extend_from_slice(&tag);
+        write_frame(stream, &frame)
+    }
+
+    pub fn read_frame(&self, stream: &mut impl Read) -> Result<Vec<u8>, Error> {
+        let mut receiving = self.receiving.lock().unwrap();
+        let frame = read_frame(stream)?;
+        if frame.len() < TAG_LEN {
+            return Err(Error::Tampered);
+        }
+        let (data, tag) = frame.split_at(frame.len() - TAG_LEN);
+        let nonce = receiving.next_nonce();
+        let cipher = Cipher::aes_256_gcm();
+        decrypt_aead(cipher, &receiving.key, Some(&nonce), &[], data, tag)
+            .map_err(|_| Error::Tampered)
+    }
+}
+
+/// Reads a handshake message, which is JSON as no encoding has been agreed on yet.
+fn read_message<T>(stream: &mut TcpStream, seal: &Option<Seal>) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let frame = match seal {
+        Some(seal) => seal.read_frame(stream)?,
+        None => read_frame(stream)?,
+    };
+    Encoding::Json.decode(&frame)
+}
+
+fn write_message(
+    stream: &mut TcpStream,
+    seal: &Option<Seal>,
+    target: &impl Serialize,
+) -> Result<(), Error> {
+    let payload = Encoding::Json.encode(target)?;
+    match seal {
+        Some(seal) => seal.write_frame(stream, &payload),
+        None => write_frame(stream, &payload),
+    }
+}
+
+fn with_timeout<T>(
+    stream: &mut TcpStream,
+    steps: impl FnOnce(&mut TcpStream) -> Result<T, Error>,
+) -> Result<T, Error> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let result = steps(stream)?;
+    stream.set_read_timeout(None)?;
+    Ok(result)
+}
+
+fn prove_client(stream: &mut TcpStream, key: &[u8]) -> Result<Seal, Error> {
+    let server_nonce = nonce();
+    stream.write_all(&server_nonce)?;
+    let mut client_nonce = [0; NONCE_LEN];
+    let mut client_mac = [0; MAC_LEN];
+    stream.read_exact(&mut client_nonce)?;
+    stream.read_exact(&mut client_mac)?;
+    if !memcmp::eq(
+        &client_mac,
+        &mac(key, b"client", &server_nonce, &client_nonce),
+    ) {
+        return Err(Error::Unauthenticated);
+    }
+    stream.write_all(&mac(key, b"server", &server_nonce, &client_nonce))?;
+    Ok(Seal::new(key, false, &server_nonce, &client_nonce))
+}
+
+fn prove_server(stream: &mut TcpStream, key: &[u8]) -> Result<Seal, Error> {
+    let mut server_nonce = [0; NONCE_LEN];
+    stream.read_exact(&mut server_nonce)?;
+    let client_nonce = nonce();
+    stream.write_all(&client_nonce)?;
+    stream.write_all(&mac(key, b"client", &server_nonce, &client_nonce))?;
+    let mut server_mac = [0; MAC_LEN];
+    stream.read_exact(&mut server_mac)?;
+    if !memcmp::eq(
+        &server_mac,
+        &mac(key, b"server", &server_nonce, &client_nonce),
+    ) {
+        return Err(Error::Unauthenticated);
+    }
+    Ok(Seal::new(key, true, &server_nonce, &client_nonce))
+}
+
+impl Link {
+    pub fn new(key: Option<Vec<u8>>, encodings: Vec<Encoding>) -> Link {
+        Link { key, encodings }
+    }
+
+    /// What the intercom answers to an offer.
+    fn answer(&self, offer: &Offer) -> Answer {
+        let version = offer.version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            return Answer::Refuse(format!(
+                "Protocol version {} is older than {}",
+                offer.version, MIN_PROTOCOL_VERSION
+            ));
+        }
+        match offer.encodings.iter().find(|x| self.encodings.contains(x)) {
+            Some(&encoding) => Answer::Accept { version, encoding },
+            None => Answer::Refuse(format!(
+                "No common encoding, expected one of {:?}",
+                self.encodings
+            )),
+        }
+    }
+
+    /// Run by the intercom on each new connection before reading any request.
+    pub fn accept(&self, mut stream: TcpStream) -> Result<Connection, Error> {
+        let (answer, seal) = with_timeout(&mut stream, |stream| {
+            let seal = match &self.key {
+                Some(key) => Some(prove_client(stream, key)?),
+                None => None,
+            };
+            let offer: Offer = read_message(stream, &seal)?;
+            let answer = self.answer(&offer);
+            write_message(stream, &seal, &answer)?;
+            Ok((answer, seal))
+        })?;
+        match answer {
+            Answer::Accept { version, encoding } => {
+                Ok(Connection::new(stream, encoding, version).sealed(seal))
+            }
+            Answer::Refuse(error) => Err(Error::Incompatible(error)),
+        }
+    }
+
+    /// Opens a connection to the intercom, authenticating both ends when a key is set.
+    pub fn connect(&self, address: &str) -> Result<Connection, Error> {
+        let address = address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut x| x.next())
+            .ok_or(Error::Io(std::io::ErrorKind::NotFound))?;
+        let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+        let (answer, seal) = with_timeout(&mut stream, |stream| {
+            let seal = match &self.key {
+                Some(key) => Some(prove_server(stream, key)?),
+                None => None,
+            };
+            let offer = Offer {
+                version: PROTOCOL_VERSION,
+                encodings: self.encodings.clone(),
+            };
+            write_message(stream, &seal, &offer)?;
+            Ok((read_message(stream, &seal)?, seal))
+        })?;
+        match answer {
+            Answer::Accept { version, encoding }
+                if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+                    && self.encodings.contains(&encoding) =>
+            {
+                Ok(Connection::new(stream, encoding, version).sealed(seal))
+            }
+            Answer::Accept { version, encoding } => Err(Error::Incompatible(format!(
+                "Intercom chose version {} with {:?}",
+                version, encoding
+            ))),
+            Answer::Refuse(error) => Err(Error::Incompatible(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn serve(link: Link) -> (String, thread::JoinHandle<Result<Connection, Error>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            link.accept(stream)
+        });
+        (address, handle)
+    }
+
+    fn link(key: Option<&[u8]>, encodings: &[Encoding]) -> Link {
+        Link::new(key.map(|x| x.to_vec()), encodings.to_vec())
+    }
+
+    #[test]
+    fn test_matching_keys() {
+        let (address, server) = serve(link(Some(b"secret"), &[Encoding::Json]));
+        assert!(link(Some(b"secret"), &[Encoding::Json])
+            .connect(&address)
+            .is_ok());
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_sealed_frames() {
+        let (address, server) = serve(link(Some(b"secret"), &[Encoding::Json]));
+        let mut client = link(Some(b"secret"), &[Encoding::Json])
+            .connect(&address)
+            .unwrap();
+        let mut server = server.join().unwrap().unwrap();
+        client.write(&"1234").unwrap();
+        assert_eq!(server.read::<String>().unwrap(), "1234");
+
+        let client = Seal::new(b"secret", true, &[1; NONCE_LEN], &[2; NONCE_LEN]);
+        let server = Seal::new(b"secret", false, &[1; NONCE_LEN], &[2; NONCE_LEN]);
+        let mut wire = Vec::new();
+        client.write_frame(&mut wire, b"\"1234\"").unwrap();
+        assert!(!wire.windows(4).any(|x| x == b"1234"));
+        assert_eq!(
+            server.read_frame(&mut wire.as_slice()).unwrap(),
+            b"\"1234\""
+        );
+        // The same frame again is a replay.
+        assert_eq!(
+            server.read_frame(&mut wire.as_slice()),
+            Err(Error::Tampered)
+        );
+
+        let client = Seal::new(b"secret", true, &[1; NONCE_LEN], &[2; NONCE_LEN]);
+        let server = Seal::new(b"secret", false, &[1; NONCE_LEN], &[2; NONCE_LEN]);
+        let mut wire = Vec::new();
+        client.write_frame(&mut wire, b"\"1234\"").unwrap();
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+        assert_eq!(
+            server.read_frame(&mut wire.as_slice()),
+            Err(Error::Tampered)
+        );
+    }
+
+    #[test]
+    fn test_wrong_key_is_rejected() {
+        let (address, server) = serve(link(Some(b"secret"), &[Encoding::Json]));
+        assert!(link(Some(b"guess"), &[Encoding::Json])
+            .connect(&address)
+            .is_err());
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(Error::Unauthenticated)
+        ));
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let (address, server) = serve(link(None, &[Encoding::Json, Encoding::Cbor]));
+        let mut client = link(None, &[Encoding::Cbor, Encoding::Json])
+            .connect(&address)
+            .unwrap();
+        let mut server = server.join().unwrap().unwrap();
+        assert_eq!(client.encoding(), Encoding::Cbor);
+        assert_eq!(server.encoding(), Encoding::Cbor);
+        assert_eq!(client.version(), PROTOCOL_VERSION);
+        client.write(&("Hello there", 42)).unwrap();
+        assert_eq!(server.read::<(String, u32)>().unwrap().1, 42);
+
+        let (address, server) = serve(link(None, &[Encoding::Json]));
+        let client = link(None, &[Encoding::Cbor]).connect(&address);
+        assert!(matches!(client, Err(Error::Incompatible(_))));
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(Error::Incompatible(_))
+        ));
+    }
+
+    #[test]
+    fn test_protocol_versions() {
+        let link = link(None, &[Encoding::Json]);
+        let offer = |version| Offer {
+            version,
+            encodings: vec![Encoding::Json],
+        };
+        assert!(matches!(
+            link.answer(&offer(PROTOCOL_VERSION + 1)),
+            Answer::Accept {
+                version: PROTOCOL_VERSION,
+                ..
+            }
+        ));
+        assert!(matches!(
+            link.answer(&offer(MIN_PROTOCOL_VERSION - 1)),
+            Answer::Refuse(_)
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+//! Brute-force protection for the keypad: too many wrong codes lock it for a while,
+//! doubling the lock each time it is triggered again.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LockoutConfig {
+    /// Wrong codes allowed within `window_secs` before the keypad locks.
+    pub max_attempts: u32,
+    pub window_secs: u64,
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> LockoutConfig {
+        LockoutConfig {
+            max_attempts: 5,
+            window_secs: 60,
+            backoff_secs: 30,
+            max_backoff_secs: 15 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockoutState {
+    pub locked: bool,
+    pub remaining_secs: u64,
+    pub recent_failures: u32,
+    /// Lockouts since the last correct code or manual clear.
+    pub lockouts: u32,
+}
+
+#[derive(Clone)]
+pub struct Lockout {
+    config: LockoutConfig,
+    failures: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+    lockouts: u32,
+}
+
+impl Lockout {
+    pub fn new(config: LockoutConfig) -> Lockout {
+        Lockout {
+            config,
+            failures: VecDeque::new(),
+            locked_until: None,
+            lockouts: 0,
+        }
+    }
+
+    pub fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.map_or(false, |until| now < until)
+    }
+
+    fn forget_old_failures(&mut self, now: Instant) {
+        let window = Duration::from_secs(self.config.window_secs);
+        while let Some(first) = self.failures.front() {
+            if now.duration_since(*first) < window {
+                break;
+            }
+            self.failures.pop_front();
+        }
+    }
+
+    /// Counts a wrong code and returns the lock duration if this one triggered a lockout.
+    pub fn record_failure(&mut self, now: Instant) -> Option<Duration> {
+        self.forget_old_failures(now);
+        self.failures.push_back(now);
+        if (self.failures.len() as u32) < self.config.max_attempts {
+            return None;
+        }
+        let backoff = self
+            .config
+            .backoff_secs
+            .saturating_mul(1 << self.lockouts.min(16))
+            .min(self.config.max_backoff_secs);
+        let backoff = Duration::from_secs(backoff);
+        self.failures.clear();
+        self.lockouts += 1;
+        self.locked_until = Some(now + backoff);
+        Some(backoff)
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures.clear();
+        self.lockouts = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.record_success();
+        self.locked_until = None;
+    }
+
+    pub fn state(&self, now: Instant) -> LockoutState {
+        let remaining = self
+            .locked_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+        LockoutState {
+            locked: self.is_locked(now),
+            remaining_secs: remaining.as_secs(),
+            recent_failures: self.failures.len() as u32,
+            lockouts: self.lockouts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout() -> Lockout {
+        Lockout::new(LockoutConfig {
+            max_attempts: 3,
+            window_secs: 60,
+            backoff_secs: 10,
+            max_backoff_secs: 30,
+        })
+    }
+
+    fn fail(lockout: &mut Lockout, now: Instant, times: u32) -> Option<Duration> {
+        (0..times).fold(None, |_, _| lockout.record_failure(now))
+    }
+
+    #[test]
+    fn test_locks_after_max_attempts() {
+        let mut lockout = lockout();
+        let now = Instant::now();
+        assert_eq!(fail(&mut lockout, now, 2), None);
+        assert!(!lockout.is_locked(now));
+        assert_eq!(lockout.record_failure(now), Some(Duration::from_secs(10)));
+        assert!(lockout.is_locked(now + Duration::from_secs(9)));
+        assert!(!lockout.is_locked(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_failures_outside_window_are_forgotten() {
+        let mut lockout = lockout();
+        let now = Instant::now();
+        fail(&mut lockout, now, 2);
+        assert_eq!(lockout.record_failure(now + Duration::from_secs(61)), None);
+        assert_eq!(
+            lockout.state(now + Duration::from_secs(61)).recent_failures,
+            1
+        );
+    }
+
+    #[test]
+    fn test_backoff_escalates_and_resets() {
+        let mut lockout = lockout();
+        let now = Instant::now();
+        assert_eq!(fail(&mut lockout, now, 3), Some(Duration::from_secs(10)));
+        assert_eq!(fail(&mut lockout, now, 3), Some(Duration::from_secs(20)));
+        assert_eq!(fail(&mut lockout, now, 3), Some(Duration::from_secs(30)));
+        assert_eq!(fail(&mut lockout, now, 3), Some(Duration::from_secs(30)));
+        lockout.clear();
+        assert!(!lockout.is_locked(now));
+        assert_eq!(fail(&mut lockout, now, 3), Some(Duration::from_secs(10)));
+    }
+}
@@ -1,13 +1,207 @@
 /// Defines most of the traits and structures to communicate over threads/network.
-use serde::{de::DeserializeOwned, Serialize};
-use std::io::{Read, Write};
+use crate::link::Seal;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
-use std::net::TcpStream;
-use std::sync::mpsc;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
-#[derive(Debug)]
+/// Frames larger than this are refused on both ends.
+pub const MAX_FRAME_LEN: u64 = 4 * 1024 * 1024;
+
+/// Version of the request protocol spoken over the intercom link. Bump it when requests
+/// or responses change in a way older peers cannot decode.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest version still understood. Version 3 names the door in door requests, which
+/// older peers cannot decode.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     NotReady,
+    /// The peer closed the connection.
+    Disconnected,
+    Io(io::ErrorKind),
+    FrameTooLarge(u64),
+    InvalidUtf8,
+    Encode(String),
+    Decode(String),
+    /// The peer failed the link key handshake.
+    Unauthenticated,
+    /// A frame did not open with the link's session keys: it was altered, replayed, dropped
+    /// or reordered on the way.
+    Tampered,
+    /// The peers could not agree on a protocol version or encoding.
+    Incompatible(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotReady => write!(f, "No message ready"),
+            Error::Disconnected => write!(f, "Connection closed"),
+            Error::Io(kind) => write!(f, "I/O error: {:?}", kind),
+            Error::FrameTooLarge(len) => write!(
+                f,
+                "Frame of {} bytes exceeds the {} byte limit",
+                len, MAX_FRAME_LEN
+            ),
+            Error::InvalidUtf8 => write!(f, "Frame is not valid UTF-8"),
+            Error::Encode(error) => write!(f, "Could not encode message: {}", error),
+            Error::Decode(error) => write!(f, "Could not decode message: {}", error),
+            Error::Unauthenticated => write!(f, "Link key handshake failed"),
+            Error::Tampered => write!(f, "Frame failed the link key check"),
+            Error::Incompatible(error) => write!(f, "Incompatible peer: {}", error),
+        }
+    }
+}
+
+impl Error {
+    /// Whether the connection has to be dropped. After a frame that could not be
+    /// decoded the stream is still at a frame boundary and can be read from again.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Error::InvalidUtf8 | Error::Decode(_))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Error::Disconnected,
+            kind => Error::Io(kind),
+        }
+    }
+}
+
+/// How messages are serialized inside a frame, agreed on when a connection is opened.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Json,
+    /// Compact binary encoding (RFC 8949).
+    Cbor,
+}
+
+impl Encoding {
+    pub fn encode(&self, target: &impl Serialize) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Json => {
+                serde_json::to_vec(target).map_err(|error| Error::Encode(error.to_string()))
+            }
+            Encoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::ser::into_writer(target, &mut buf)
+                    .map_err(|error| Error::Encode(error.to_string()))?;
+                Ok(buf)
+            }
+        }
+    }
+
+    pub fn decode<T>(&self, frame: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            Encoding::Json => {
+                let json = std::str::from_utf8(frame).map_err(|_| Error::InvalidUtf8)?;
+                serde_json::from_str(json).map_err(|error| Error::Decode(error.to_string()))
+            }
+            Encoding::Cbor => {
+                ciborium::de::from_reader(frame).map_err(|error| Error::Decode(error.to_string()))
+            }
+        }
+    }
+}
+
+/// A connection to the intercom once the handshake in `link` has settled the protocol
+/// version and encoding.
+pub struct Connection {
+    stream: TcpStream,
+    encoding: Encoding,
+    version: u32,
+    /// Shared by every clone, so frames are numbered in the order they go on the wire.
+    seal: Option<Arc<Seal>>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, encoding: Encoding, version: u32) -> Connection {
+        Connection {
+            stream,
+            encoding,
+            version,
+            seal: None,
+        }
+    }
+
+    /// Encrypts and authenticates every frame with the keys from the link handshake.
+    pub fn sealed(self, seal: Option<Seal>) -> Connection {
+        Connection {
+            seal: seal.map(Arc::new),
+            ..self
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// Another handle to the same connection, for answering from another thread.
+    pub fn try_clone(&self) -> Result<Connection, Error> {
+        Ok(Connection {
+            stream: self.stream.try_clone()?,
+            encoding: self.encoding,
+            version: self.version,
+            seal: self.seal.clone(),
+        })
+    }
+
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        match &self.seal {
+            Some(seal) => seal.read_frame(&mut self.stream),
+            None => read_frame(&mut self.stream),
+        }
+    }
+
+    pub fn decode<T>(&self, frame: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        self.encoding.decode(frame)
+    }
+
+    pub fn read<T>(&mut self) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let frame = self.read_frame()?;
+        self.decode(&frame)
+    }
+
+    pub fn write(&mut self, target: &impl Serialize) -> Result<(), Error> {
+        let payload = self.encoding.encode(target)?;
+        match &self.seal {
+            Some(seal) => seal.write_frame(&mut self.stream, &payload),
+            None => write_frame(&mut self.stream, &payload),
+        }
+    }
 }
 
 pub trait Send<Type> {
@@ -16,6 +210,17 @@ pub trait Send<Type> {
 
 pub trait Receive<Type> {
     fn receive(&mut self) -> Result<Type, Error>;
+    /// Waits up to `timeout` for the next message. Receivers that cannot block check
+    /// again once the time is up.
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<Type, Error> {
+        match self.receive() {
+            Err(Error::NotReady) => {
+                thread::sleep(timeout);
+                self.receive()
+            }
+            received => received,
+        }
+    }
 }
 
 pub struct ThreadSender<Type, To>(pub mpsc::Sender<Type>, pub PhantomData<To>);
@@ -26,9 +231,9 @@ impl<Type, To> Clone for ThreadSender<Type, To> {
     }
 }
 
-impl<Type, To> Send<Type> for ThreadSender<Type, To> {
-    fn send(&mut self, target: Type) {
-        self.0.send(target).unwrap();
+impl<Type, To, T: Into<Type>> Send<T> for ThreadSender<Type, To> {
+    fn send(&mut self, target: T) {
+        self.0.send(target.into()).unwrap();
     }
 }
 
@@ -42,59 +247,86 @@ impl<Type> Receive<Type> for ThreadReceiver<Type> {
             _ => panic!("Cannot receive data."),
         }
     }
+
+    fn receive_timeout(&mut self, timeout: Duration) -> Result<Type, Error> {
+        match self.0.recv_timeout(timeout) {
+            Ok(x) => Ok(x),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::NotReady),
+            _ => panic!("Cannot receive data."),
+        }
+    }
 }
 
-pub struct TcpSender<Type>(pub Option<TcpStream>, pub PhantomData<Type>);
+pub struct TcpSender<Type>(pub Option<Connection>, pub PhantomData<Type>);
 
 impl<Type> Send<Type> for TcpSender<Type>
 where
     Type: Serialize,
 {
     fn send(&mut self, target: Type) {
-        if let Some(ref mut stream) = self.0 {
-            write_to_stream(stream, &target);
+        if let Some(ref mut connection) = self.0 {
+            // The requester may have gone away; that must not take the device down.
+            if let Err(error) = connection.write(&target) {
+                println!("Could not send response: {}", error);
+            }
         }
     }
 }
 
 impl<Type> TcpSender<Type> {
-    pub fn set_stream(&mut self, stream: TcpStream) {
-        self.0 = Some(stream);
+    pub fn set_stream(&mut self, connection: Connection) {
+        self.0 = Some(connection);
     }
 }
 
-pub struct TcpReceiver<Type>(pub TcpStream, pub PhantomData<Type>);
+pub struct TcpReceiver<Type>(pub Connection, pub PhantomData<Type>);
 
 impl<Type> Receive<Type> for TcpReceiver<Type>
 where
     Type: DeserializeOwned,
 {
     fn receive(&mut self) -> Result<Type, Error> {
-        read_from_stream(&mut self.0)
+        self.0.read()
     }
 }
 
+/// Reads one length-prefixed frame without decoding it.
+pub fn read_frame(stream: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut len_buf = [0u8; 8];
+    stream.read_exact(&mut len_buf)?;
+    let len: u64 = u64::from_le_bytes(len_buf);
+    if len > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge(len));
+    }
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf[..])?;
+    Ok(buf)
+}
+
+// The frame goes out in a single write so devices answering on clones of the same
+// connection do not interleave their responses.
+pub fn write_frame(stream: &mut impl Write, payload: &[u8]) -> Result<(), Error> {
+    let length = payload.len() as u64;
+    if length > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge(length));
+    }
+    let mut frame = length.to_le_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads a JSON frame, as used before an encoding has been agreed on.
 pub fn read_from_stream<T>(stream: &mut impl Read) -> Result<T, Error>
 where
     T: DeserializeOwned,
 {
-    let mut len_buf = [0u8; 8];
-    stream.read_exact(&mut len_buf).unwrap();
-    let len: u64 = u64::from_le_bytes(len_buf);
-    let mut buf = vec![0u8; usize::try_from(len).unwrap()];
-    stream.read_exact(&mut buf[..]).unwrap();
-    let json = String::from_utf8(buf).unwrap();
-    let return_value: T = serde_json::from_str(&json).unwrap();
-    Ok(return_value)
+    Encoding::Json.decode(&read_frame(stream)?)
 }
 
-pub fn write_to_stream(stream: &mut impl Write, target: &impl Serialize) {
-    let json = serde_json::to_string(&target).unwrap();
-    let bytes = json.as_bytes();
-    let length = bytes.len() as u64;
-    stream.write_all(&length.to_le_bytes()).unwrap();
-    stream.write_all(bytes).unwrap();
-    stream.flush().unwrap();
+pub fn write_to_stream(stream: &mut impl Write, target: &impl Serialize) -> Result<(), Error> {
+    write_frame(stream, &Encoding::Json.encode(target)?)
 }
 
 #[cfg(test)]
@@ -102,7 +334,6 @@ mod tests {
     use std::net::TcpListener;
 
     use super::*;
-    use serde::Deserialize;
 
     #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
     struct ShortTestData(String);
@@ -127,21 +358,38 @@ mod tests {
             Ok(x) => x,
             _ => return,
         };
-        let send_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
-        let receive_stream = listener.accept().unwrap();
-        let mut sender = TcpSender::<ShortTestData>(Some(send_stream), PhantomData);
-        let mut receiver = TcpReceiver::<ShortTestData>(receive_stream.0, PhantomData);
-        let data = ShortTestData("Hello there".to_string());
-        sender.send(data.clone());
-        let result = receiver.receive().unwrap();
-        assert_eq!(result, data);
+        for encoding in [Encoding::Json, Encoding::Cbor] {
+            let send_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let receive_stream = listener.accept().unwrap();
+            let send_connection = Connection::new(send_stream, encoding, PROTOCOL_VERSION);
+            let receive_connection = Connection::new(receive_stream.0, encoding, PROTOCOL_VERSION);
+            let mut sender = TcpSender::<ShortTestData>(Some(send_connection), PhantomData);
+            let mut receiver = TcpReceiver::<ShortTestData>(receive_connection, PhantomData);
+            let data = ShortTestData("Hello there".to_string());
+            sender.send(data.clone());
+            let result = receiver.receive().unwrap();
+            assert_eq!(result, data);
+        }
+    }
+
+    #[test]
+    fn test_cbor_is_smaller() {
+        let data = LongTestData(vec![63i32; 8192]);
+        let json = Encoding::Json.encode(&data).unwrap();
+        let cbor = Encoding::Cbor.encode(&data).unwrap();
+        assert!(cbor.len() < json.len());
+        assert_eq!(Encoding::Cbor.decode::<LongTestData>(&cbor).unwrap(), data);
+        assert!(matches!(
+            Encoding::Cbor.decode::<LongTestData>(&json),
+            Err(Error::Decode(_))
+        ));
     }
 
     #[test]
     fn test_short_io_stream() {
         let mut buf = [0u8; 256];
         let data = ShortTestData("Hello There".to_string());
-        write_to_stream(&mut buf.as_mut_slice(), &data);
+        write_to_stream(&mut buf.as_mut_slice(), &data).unwrap();
         let result: ShortTestData = read_from_stream(&mut buf.as_slice()).unwrap();
         assert_eq!(result, data);
     }
@@ -151,8 +399,29 @@ mod tests {
         let mut buf = [0u8; 65536];
         let vec = vec![63i32; 8192];
         let data = LongTestData(vec);
-        write_to_stream(&mut buf.as_mut_slice(), &data);
+        write_to_stream(&mut buf.as_mut_slice(), &data).unwrap();
         let result: LongTestData = read_from_stream(&mut buf.as_slice()).unwrap();
         assert_eq!(result, data);
     }
+
+    #[test]
+    fn test_stream_errors() {
+        let mut buf = [0u8; 64];
+        let data = ShortTestData("Hello There".to_string());
+        write_to_stream(&mut buf.as_mut_slice(), &data).unwrap();
+        let result = read_from_stream::<ShortTestData>(&mut &buf[..12]);
+        assert_eq!(result, Err(Error::Disconnected));
+        let result = read_from_stream::<LongTestData>(&mut buf.as_slice());
+        assert!(matches!(result, Err(Error::Decode(_))));
+
+        let mut frame = (MAX_FRAME_LEN + 1).to_le_bytes().to_vec();
+        frame.extend_from_slice(&[0u8; 16]);
+        let result = read_from_stream::<ShortTestData>(&mut frame.as_slice());
+        assert_eq!(result, Err(Error::FrameTooLarge(MAX_FRAME_LEN + 1)));
+
+        let mut frame = 2u64.to_le_bytes().to_vec();
+        frame.extend_from_slice(&[0xff, 0xfe]);
+        let result = read_from_stream::<ShortTestData>(&mut frame.as_slice());
+        assert_eq!(result, Err(Error::InvalidUtf8));
+    }
 }
@@ -0,0 +1,100 @@
+//! Text messages through Twilio, shared by every device that sends them so they all go
+//! to the number set from the web interface.
+
+use openapi::apis::{configuration::Configuration, default_api as twilio_api};
+use phonenumber::PhoneNumber;
+use std::env;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::{Builder, Runtime};
+
+#[derive(Clone)]
+pub struct Notifier {
+    to: Arc<Mutex<PhoneNumber>>,
+    runtime: Arc<Runtime>,
+}
+
+impl Notifier {
+    /// Texts `TO_NUMBER` until another number is set.
+    pub fn from_env() -> Notifier {
+        let to = phonenumber::parse(
+            None,
+            env::var("TO_NUMBER").expect("Failed to parse 'to' number"),
+        )
+        .unwrap();
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        Notifier {
+            to: Arc::new(Mutex::new(to)),
+            runtime: Arc::new(runtime),
+        }
+    }
+
+    pub fn number(&self) -> PhoneNumber {
+        self.to.lock().unwrap().clone()
+    }
+
+    pub fn set_number(&self, number: PhoneNumber) {
+        *self.to.lock().unwrap() = number;
+    }
+
+    /// Texts the current number in the background.
+    pub fn send(&self, body: String) {
+        self.send_to(self.number().to_string(), body);
+    }
+
+    pub fn send_to(&self, to: String, body: String) {
+        self.runtime.spawn(send_notification(to, body));
+    }
+}
+
+async fn send_notification(to: String, body: String) {
+    let account_sid = match env::var("TWILIO_ACCOUNT_SID") {
+        Ok(account_sid) => account_sid,
+        Err(_) => {
+            println!(
+                "Twilio is not configured, not sending notification to {}",
+                to
+            );
+            return;
+        }
+    };
+    let api_key = env::var("TWILIO_API_KEY").expect("Failed to parse API Key");
+    let api_key_secret = env::var("TWILIO_API_KEY_SECRET").expect("Failed to parse API Key Secret");
+    let from = env::var("TWILIO_PHONE_NUMBER").expect("Failed to parse 'from' number");
+
+    let mut twilio_config = Configuration::default();
+    twilio_config.basic_auth = Some((api_key, Some(api_key_secret)));
+
+    let message = twilio_api::create_message(
+        &twilio_config,
+        &account_sid,
+        &to,
+        None,
+        None,
+        None,
+        Some(&body),
+        None,
+        None,
+        Some(&from),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await;
+
+    let _result = match message {
+        Ok(result) => result,
+        Err(error) => panic!("Something went wrong, {:?}", error),
+    };
+}
@@ -15,6 +15,11 @@ pub trait Set<T, U> {
     fn set(&mut self, target: &U) -> Result<(), Error>;
 }
 
+/// A read that takes parameters, e.g. a filter, and returns `U`.
+pub trait Query<T, Q, U> {
+    fn query(&self, target: &Q) -> Result<U, Error>;
+}
+
 pub trait GetRequest<T, U, R>
 where
     T: Get<T, U>,
@@ -34,6 +39,16 @@ where
     fn get_id(&self) -> ID;
 }
 
+pub trait QueryRequest<T, Q, U, R>
+where
+    T: Query<T, Q, U>,
+    R: QueryResponse<T, Q, U>,
+{
+    fn get_query(&self) -> &Q;
+    fn get_response(self, target: &T) -> R;
+    fn get_id(&self) -> ID;
+}
+
 pub trait GetResponse<T, U>
 where
     T: Get<T, U>,
@@ -51,6 +66,14 @@ where
     fn get_id(&self) -> ID;
 }
 
+pub trait QueryResponse<T, Q, U>
+where
+    T: Query<T, Q, U>,
+{
+    fn get_result(self) -> Result<U, Error>;
+    fn get_id(&self) -> ID;
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct BasicGetResponse<T, U>(pub ID, pub Result<U, Error>, pub PhantomData<T>);
 
@@ -119,6 +142,45 @@ where
     }
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BasicQueryResponse<T, Q, U>(
+    pub ID,
+    pub Result<U, Error>,
+    pub PhantomData<T>,
+    pub PhantomData<Q>,
+);
+
+impl<T, Q, U> QueryResponse<T, Q, U> for BasicQueryResponse<T, Q, U>
+where
+    T: Query<T, Q, U>,
+{
+    fn get_id(&self) -> ID {
+        return self.0;
+    }
+    fn get_result(self) -> Result<U, Error> {
+        return self.1;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BasicQueryRequest<T, Q, U>(pub ID, pub Q, pub PhantomData<T>, pub PhantomData<U>);
+
+impl<T, Q, U> QueryRequest<T, Q, U, BasicQueryResponse<T, Q, U>> for BasicQueryRequest<T, Q, U>
+where
+    T: Query<T, Q, U>,
+{
+    fn get_response(self, target: &T) -> BasicQueryResponse<T, Q, U> {
+        let result = target.query(&self.1);
+        BasicQueryResponse(self.0, result, self.2, PhantomData)
+    }
+    fn get_id(&self) -> ID {
+        return self.0;
+    }
+    fn get_query(&self) -> &Q {
+        return &self.1;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -158,6 +220,12 @@ mod tests {
         }
     }
 
+    impl Query<Simple, Data1, Data2> for Simple {
+        fn query(&self, target: &Data1) -> Result<Data2, Error> {
+            return Ok(Data2(self.1 .0 + target.0));
+        }
+    }
+
     #[test]
     fn test_get() {
         let data = Simple(Data1(32), Data2(42));
@@ -212,4 +280,19 @@ mod tests {
         assert_eq!(response.get_result().unwrap(), ());
         assert_eq!(data.0, Data1(53));
     }
+
+    #[test]
+    fn test_query_response() {
+        let data = Simple(Data1(32), Data2(42));
+        let request = BasicQueryRequest::<Simple, Data1, Data2>(
+            ID(0u128),
+            Data1(8),
+            PhantomData,
+            PhantomData,
+        );
+        assert_eq!(*request.get_query(), Data1(8));
+        let response = request.get_response(&data);
+        assert_eq!(response.get_id(), ID(0u128));
+        assert_eq!(response.get_result().unwrap(), Data2(50));
+    }
 }
@@ -1,39 +1,152 @@
 /// All requests and response types used to communicate with devices in the Intercom.
-use crate::device::door::{Door, DoorState};
-use crate::device::keypad::{Code, KeyPad, PhoneNumberText};
-use crate::device::nfc::{NFCdev, NFCids};
+use crate::audit::{AuditEvent, AuditLog, AuditQuery};
+use crate::capabilities::{Capabilities, Inventory};
+use crate::codes::{CodeEntry, NewCode};
+use crate::device::audit::{AuditEvents, Subscribe};
+use crate::device::door::{DoorCommand, DoorList, Doors, ScheduledState};
+use crate::device::keypad::{ClearLockout, Code, CodeList, KeyPad, PhoneNumberText, RevokeCode};
+use crate::device::nfc::{
+    CardDoors, CardEnabled, CardList, NFCdev, NewCard, RemoveCard, RenameCard,
+};
+use crate::device::scheduler::{RemoveRule, RuleList, Scheduler};
 use crate::device::terminal::{Terminal, Text};
+use crate::feedback::Signal;
+use crate::lockout::LockoutState;
+use crate::message::Connection;
 use crate::request::*;
+use crate::schedule::{NewRule, ScheduleRule};
+use crate::supervisor::{Stop, Supervisor};
 use serde::{Deserialize, Serialize};
-use std::net::TcpStream;
-
-pub struct ThreadRequest(pub Requests, pub TcpStream);
-pub struct InternalThreadRequest(pub Requests);
-
-#[derive(Serialize, Deserialize)]
-pub enum Requests {
-    TerminalGetText(BasicGetRequest<Terminal, Text>),
-    TerminalSetText(BasicSetRequest<Terminal, Text>),
-    NFCGetID(BasicGetRequest<NFCdev, NFCids>),
-    NFCSetID(BasicSetRequest<NFCdev, NFCids>),
-    DoorGetState(BasicGetRequest<Door, DoorState>),
-    DoorSetState(BasicSetRequest<Door, DoorState>),
-    KeyPadGetCode(BasicGetRequest<KeyPad, Code>),
-    KeyPadSetCode(BasicSetRequest<KeyPad, Code>),
-    PhoneGet(BasicGetRequest<KeyPad, PhoneNumberText>),
-    PhoneSet(BasicSetRequest<KeyPad, PhoneNumberText>),
+use std::sync::mpsc;
+
+pub struct ThreadRequest(pub Requests, pub Connection);
+pub struct InternalThreadRequest(pub Internal);
+
+/// What one device asks of another. These never leave the intercom.
+#[allow(clippy::large_enum_variant)]
+pub enum Internal {
+    /// A request a client could also make, like the keypad unlocking the door.
+    Request(Requests),
+    /// Plays the pattern for what just happened at the door on the buzzer and LEDs.
+    FeedbackSignal(Signal),
+    /// Something that happened at the door, for the audit log and its subscribers.
+    AuditRecord(AuditEvent),
+    /// Sent by the scheduler when its rules for the doors start or end.
+    DoorApplySchedule(ScheduledState),
+    /// Unlocks the named door for a code or card and answers whether it did.
+    DoorUnlock(String, mpsc::Sender<Result<(), String>>),
+}
+
+/// Everything a device thread receives, on one channel so it can wait on all of it at once.
+pub enum DeviceRequest {
+    /// From a client, relayed by the dispatcher with the connection to answer on.
+    Relayed(ThreadRequest),
+    /// From another device; nothing is sent back.
+    Internal(InternalThreadRequest),
+    /// The device's hardware has something to report, like a GPIO edge.
+    Wake,
+}
+
+impl From<ThreadRequest> for DeviceRequest {
+    fn from(request: ThreadRequest) -> DeviceRequest {
+        DeviceRequest::Relayed(request)
+    }
+}
+
+impl From<InternalThreadRequest> for DeviceRequest {
+    fn from(request: InternalThreadRequest) -> DeviceRequest {
+        DeviceRequest::Internal(request)
+    }
+}
+
+/// Declares every request with the response it gets. Each line adds a variant to
+/// `Requests`, `Responses` and `RequestKind`, so a new request is one line here plus the
+/// device that answers it.
+macro_rules! protocol {
+    ($($(#[$doc:meta])* $name:ident($request:ty) => $response:ty,)*) => {
+        #[derive(Serialize, Deserialize)]
+        pub enum Requests {
+            $($(#[$doc])* $name($request),)*
+        }
+
+        #[derive(Serialize, Deserialize, Clone)]
+        pub enum Responses {
+            $($name($response),)*
+            /// Pushed to subscribers, tagged with the ID of their subscribe request.
+            AuditEvent(ID, AuditEvent),
+            /// Sent back for a request that could not be decoded or has no device to
+            /// answer it. The ID is read from the frame when possible and is `ID(0)`
+            /// otherwise.
+            Error(ID, String),
+        }
+
+        /// Which request a `Requests` is, used to route it to the device handling it.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        pub enum RequestKind {
+            $($name,)*
+        }
+
+        impl Requests {
+            pub fn kind(&self) -> RequestKind {
+                match self {
+                    $(Requests::$name(_) => RequestKind::$name,)*
+                }
+            }
+
+            pub fn get_id(&self) -> ID {
+                match self {
+                    $(Requests::$name(x) => x.0,)*
+                }
+            }
+        }
+
+        impl Responses {
+            pub fn get_id(&self) -> ID {
+                match self {
+                    $(Responses::$name(x) => x.0,)*
+                    Responses::AuditEvent(id, _) | Responses::Error(id, _) => *id,
+                }
+            }
+        }
+    };
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub enum Responses {
-    TerminalGetText(BasicGetResponse<Terminal, Text>),
-    TerminalSetText(BasicSetResponse<Terminal, Text>),
-    NFCGetID(BasicGetResponse<NFCdev, NFCids>),
-    NFCSetID(BasicSetResponse<NFCdev, NFCids>),
-    DoorGetState(BasicGetResponse<Door, DoorState>),
-    DoorSetState(BasicSetResponse<Door, DoorState>),
-    KeyPadGetCode(BasicGetResponse<KeyPad, Code>),
-    KeyPadSetCode(BasicSetResponse<KeyPad, Code>),
-    PhoneGet(BasicGetResponse<KeyPad, PhoneNumberText>),
-    PhoneSet(BasicSetResponse<KeyPad, PhoneNumberText>),
+protocol! {
+    TerminalGetText(BasicGetRequest<Terminal, Text>) => BasicGetResponse<Terminal, Text>,
+    TerminalSetText(BasicSetRequest<Terminal, Text>) => BasicSetResponse<Terminal, Text>,
+    NFCGetID(BasicGetRequest<NFCdev, CardList>) => BasicGetResponse<NFCdev, CardList>,
+    NFCSetID(BasicSetRequest<NFCdev, NewCard>) => BasicSetResponse<NFCdev, NewCard>,
+    NFCRemoveCard(BasicSetRequest<NFCdev, RemoveCard>) => BasicSetResponse<NFCdev, RemoveCard>,
+    NFCSetCardEnabled(BasicSetRequest<NFCdev, CardEnabled>)
+        => BasicSetResponse<NFCdev, CardEnabled>,
+    NFCRenameCard(BasicSetRequest<NFCdev, RenameCard>) => BasicSetResponse<NFCdev, RenameCard>,
+    NFCSetCardDoors(BasicSetRequest<NFCdev, CardDoors>) => BasicSetResponse<NFCdev, CardDoors>,
+    /// Every door and its state; addressed by name since protocol version 3.
+    DoorGetState(BasicGetRequest<Doors, DoorList>) => BasicGetResponse<Doors, DoorList>,
+    DoorSetState(BasicSetRequest<Doors, DoorCommand>) => BasicSetResponse<Doors, DoorCommand>,
+    KeyPadGetCode(BasicGetRequest<KeyPad, Code>) => BasicGetResponse<KeyPad, Code>,
+    KeyPadSetCode(BasicSetRequest<KeyPad, Code>) => BasicSetResponse<KeyPad, Code>,
+    PhoneGet(BasicGetRequest<KeyPad, PhoneNumberText>) => BasicGetResponse<KeyPad, PhoneNumberText>,
+    PhoneSet(BasicSetRequest<KeyPad, PhoneNumberText>) => BasicSetResponse<KeyPad, PhoneNumberText>,
+    KeyPadListCodes(BasicGetRequest<KeyPad, CodeList>) => BasicGetResponse<KeyPad, CodeList>,
+    KeyPadAddCode(BasicSetRequest<KeyPad, NewCode>) => BasicSetResponse<KeyPad, NewCode>,
+    KeyPadRevokeCode(BasicSetRequest<KeyPad, RevokeCode>) => BasicSetResponse<KeyPad, RevokeCode>,
+    KeyPadEditCode(BasicSetRequest<KeyPad, CodeEntry>) => BasicSetResponse<KeyPad, CodeEntry>,
+    KeyPadGetLockout(BasicGetRequest<KeyPad, LockoutState>)
+        => BasicGetResponse<KeyPad, LockoutState>,
+    KeyPadClearLockout(BasicSetRequest<KeyPad, ClearLockout>)
+        => BasicSetResponse<KeyPad, ClearLockout>,
+    AuditQuery(BasicQueryRequest<AuditLog, AuditQuery, AuditEvents>)
+        => BasicQueryResponse<AuditLog, AuditQuery, AuditEvents>,
+    AuditSubscribe(BasicSetRequest<AuditLog, Subscribe>) => BasicSetResponse<AuditLog, Subscribe>,
+    ScheduleListRules(BasicGetRequest<Scheduler, RuleList>) => BasicGetResponse<Scheduler, RuleList>,
+    ScheduleAddRule(BasicSetRequest<Scheduler, NewRule>) => BasicSetResponse<Scheduler, NewRule>,
+    ScheduleEditRule(BasicSetRequest<Scheduler, ScheduleRule>)
+        => BasicSetResponse<Scheduler, ScheduleRule>,
+    ScheduleRemoveRule(BasicSetRequest<Scheduler, RemoveRule>)
+        => BasicSetResponse<Scheduler, RemoveRule>,
+    /// Answered by the dispatcher itself; new in protocol version 2.
+    Hello(BasicGetRequest<Inventory, Capabilities>) => BasicGetResponse<Inventory, Capabilities>,
+    /// Relocks the door and stops every device; the intercom exits once they are done.
+    Shutdown(BasicSetRequest<Supervisor, Stop>) => BasicSetResponse<Supervisor, Stop>,
 }
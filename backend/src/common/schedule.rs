@@ -0,0 +1,312 @@
+//! Rules that change how the doors behave at set times, like keeping them unlocked during
+//! office hours, persisted as JSON. Every rule applies to all doors. Times are wall-clock
+//! times in the intercom's local time zone, so rules follow daylight saving changes.
+
+use crate::codes::TimeWindow;
+use crate::device::door::DoorState;
+use crate::request::Error;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Effect {
+    /// Holds the doors open.
+    Unlock,
+    /// Refuses codes marked as guest codes.
+    DisableGuestCodes,
+    /// Puts the doors in lockdown; wins over any unlock rule.
+    Lockdown,
+}
+
+/// When a rule applies. Unset fields do not restrict.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct RuleTimes {
+    pub weekdays: Vec<Weekday>,
+    /// Only on these dates, like public holidays.
+    pub dates: Vec<NaiveDate>,
+    pub hours: Option<TimeWindow>,
+}
+
+impl RuleTimes {
+    /// Hours that wrap past midnight belong to the day they started on, so a Friday
+    /// night rule still applies early on Saturday.
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let day = match self.hours {
+            None => now.date(),
+            Some(hours) if !hours.contains(now.time()) => return false,
+            Some(hours) if hours.start > hours.end && now.time() < hours.end => {
+                now.date().pred_opt().unwrap()
+            }
+            Some(_) => now.date(),
+        };
+        (self.weekdays.is_empty() || self.weekdays.contains(&day.weekday()))
+            && (self.dates.is_empty() || self.dates.contains(&day))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NewRule {
+    pub name: String,
+    pub effect: Effect,
+    #[serde(default)]
+    pub times: RuleTimes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduleRule {
+    pub id: u32,
+    pub name: String,
+    pub effect: Effect,
+    #[serde(default)]
+    pub times: RuleTimes,
+}
+
+fn validate_rule(name: &str, times: &RuleTimes) -> Result<(), Error> {
+    if name.trim().is_empty() {
+        return Err(Error("Rules need a name".to_string()));
+    }
+    if times.hours.is_some_and(|hours| hours.start == hours.end) {
+        return Err(Error(
+            "Rule hours cannot start and end together".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// What the schedule asks for at one moment.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Modes {
+    /// The door state the rules want, or `None` to leave the doors alone.
+    pub door: Option<DoorState>,
+    pub guest_codes_disabled: bool,
+}
+
+pub fn modes_at(rules: &[ScheduleRule], now: NaiveDateTime) -> Modes {
+    let active = |effect| {
+        rules
+            .iter()
+            .any(|x| x.effect == effect && x.times.contains(now))
+    };
+    let door = if active(Effect::Lockdown) {
+        Some(DoorState::Lockdown)
+    } else if active(Effect::Unlock) {
+        Some(DoorState::HoldOpen)
+    } else {
+        None
+    };
+    Modes {
+        door,
+        guest_codes_disabled: active(Effect::DisableGuestCodes),
+    }
+}
+
+/// Whether guest codes are refused, shared with the keypad.
+#[derive(Clone, Default)]
+pub struct GuestCodes(Arc<AtomicBool>);
+
+impl GuestCodes {
+    pub fn are_disabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set_disabled(&self, disabled: bool) {
+        self.0.store(disabled, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct ScheduleStore {
+    path: PathBuf,
+    rules: Vec<ScheduleRule>,
+}
+
+impl ScheduleStore {
+    const FILE: &'static str = "schedule.json";
+
+    pub fn load() -> ScheduleStore {
+        ScheduleStore::load_from(ScheduleStore::FILE)
+    }
+
+    pub fn load_from(path: impl AsRef<Path>) -> ScheduleStore {
+        let path = path.as_ref().to_path_buf();
+        let rules = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .unwrap_or_else(|error| panic!("Invalid schedule {:?}: {}", path, error)),
+            Err(_) => Vec::new(),
+        };
+        ScheduleStore { path, rules }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(&self.rules).unwrap();
+        fs::write(&self.path, json).map_err(|_| Error("Could not save schedule".to_string()))
+    }
+
+    pub fn list(&self) -> &[ScheduleRule] {
+        &self.rules
+    }
+
+    pub fn add(&mut self, rule: &NewRule) -> Result<u32, Error> {
+        validate_rule(&rule.name, &rule.times)?;
+        let id = self.rules.iter().map(|x| x.id + 1).max().unwrap_or(0);
+        self.rules.push(ScheduleRule {
+            id,
+            name: rule.name.clone(),
+            effect: rule.effect,
+            times: rule.times.clone(),
+        });
+        self.save()?;
+        Ok(id)
+    }
+
+    pub fn edit(&mut self, rule: &ScheduleRule) -> Result<(), Error> {
+        validate_rule(&rule.name, &rule.times)?;
+        let entry = self
+            .rules
+            .iter_mut()
+            .find(|x| x.id == rule.id)
+            .ok_or_else(|| Error("No such rule".to_string()))?;
+        *entry = rule.clone();
+        self.save()
+    }
+
+    pub fn remove(&mut self, id: u32) -> Result<(), Error> {
+        let length = self.rules.len();
+        self.rules.retain(|x| x.id != id);
+        if self.rules.len() == length {
+            return Err(Error("No such rule".to_string()));
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_file;
+    use chrono::NaiveTime;
+
+    fn store(name: &str) -> ScheduleStore {
+        ScheduleStore::load_from(temp_file(&format!("schedule-{}.json", name)))
+    }
+
+    fn hours(start: u32, end: u32) -> Option<TimeWindow> {
+        Some(TimeWindow {
+            start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+        })
+    }
+
+    // 2024-03-08 is a Friday.
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    fn rule(effect: Effect, times: RuleTimes) -> ScheduleRule {
+        ScheduleRule {
+            id: 0,
+            name: format!("{:?}", effect),
+            effect,
+            times,
+        }
+    }
+
+    #[test]
+    fn test_office_hours_and_holidays() {
+        let weekdays = vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+        ];
+        let rules = vec![
+            rule(
+                Effect::Unlock,
+                RuleTimes {
+                    weekdays: weekdays.clone(),
+                    hours: hours(9, 17),
+                    ..RuleTimes::default()
+                },
+            ),
+            rule(
+                Effect::Lockdown,
+                RuleTimes {
+                    dates: vec![NaiveDate::from_ymd_opt(2024, 3, 11).unwrap()],
+                    ..RuleTimes::default()
+                },
+            ),
+        ];
+        assert_eq!(modes_at(&rules, at(8, 12)).door, Some(DoorState::HoldOpen));
+        assert_eq!(modes_at(&rules, at(8, 17)).door, None);
+        // Saturday.
+        assert_eq!(modes_at(&rules, at(9, 12)).door, None);
+        // The holiday lockdown wins over office hours.
+        assert_eq!(modes_at(&rules, at(11, 12)).door, Some(DoorState::Lockdown));
+    }
+
+    #[test]
+    fn test_overnight_rule() {
+        let rules = vec![rule(
+            Effect::DisableGuestCodes,
+            RuleTimes {
+                weekdays: vec![Weekday::Fri],
+                hours: hours(22, 6),
+                ..RuleTimes::default()
+            },
+        )];
+        assert!(!modes_at(&rules, at(8, 21)).guest_codes_disabled);
+        assert!(modes_at(&rules, at(8, 23)).guest_codes_disabled);
+        // Still Friday night early on Saturday, but not early on Friday.
+        assert!(modes_at(&rules, at(9, 3)).guest_codes_disabled);
+        assert!(!modes_at(&rules, at(8, 3)).guest_codes_disabled);
+        assert_eq!(modes_at(&rules, at(9, 3)).door, None);
+    }
+
+    #[test]
+    fn test_store_add_edit_remove() {
+        let mut store = store("edit");
+        let new = NewRule {
+            name: "Office hours".to_string(),
+            effect: Effect::Unlock,
+            times: RuleTimes {
+                hours: hours(9, 17),
+                ..RuleTimes::default()
+            },
+        };
+        let id = store.add(&new).unwrap();
+        assert!(store
+            .add(&NewRule {
+                name: " ".to_string(),
+                ..new.clone()
+            })
+            .is_err());
+        assert!(store
+            .add(&NewRule {
+                times: RuleTimes {
+                    hours: hours(9, 9),
+                    ..RuleTimes::default()
+                },
+                ..new.clone()
+            })
+            .is_err());
+
+        let mut rule = store.list()[0].clone();
+        rule.effect = Effect::Lockdown;
+        store.edit(&rule).unwrap();
+        let reloaded = ScheduleStore::load_from(&store.path);
+        assert_eq!(reloaded.list(), &[rule]);
+
+        store.remove(id).unwrap();
+        assert!(store.remove(id).is_err());
+        assert!(store.list().is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Key sequences that make the keypad do something other than check a code, like
+//! ringing a resident or locking the door. They are entered like codes, ending with `#`.
+
+use crate::keyscan::ENTER_KEY;
+use crate::request::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Texts the number set from the web interface.
+    Ring,
+    /// Texts one resident instead.
+    RingResident { name: String, phone: String },
+    /// Opens the door like a code and sends an alert; nothing at the door shows the
+    /// difference.
+    Duress { name: String },
+    /// Locks the door straight away.
+    Lock,
+    /// Asks for someone to open the door from the web interface.
+    AdminOverride,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sequence {
+    pub keys: String,
+    pub action: Action,
+}
+
+/// Just the doorbell, which the keypad has always had.
+pub fn default_sequences() -> Vec<Sequence> {
+    vec![Sequence {
+        keys: "***".to_string(),
+        action: Action::Ring,
+    }]
+}
+
+/// The action for what was typed, if it is in the table.
+pub fn find<'a>(sequences: &'a [Sequence], keys: &str) -> Option<&'a Action> {
+    sequences.iter().find(|x| x.keys == keys).map(|x| &x.action)
+}
+
+/// Checks that every sequence can be typed on a keypad with `keys` and that none is
+/// listed twice.
+pub fn validate(sequences: &[Sequence], keys: &HashSet<char>) -> Result<(), Error> {
+    let mut seen = HashSet::new();
+    for sequence in sequences {
+        if sequence.keys.is_empty() {
+            return Err(Error("Key sequences cannot be empty".to_string()));
+        }
+        if let Some(key) = sequence
+            .keys
+            .chars()
+            .find(|x| *x == ENTER_KEY || !keys.contains(x))
+        {
+            return Err(Error(format!(
+                "Sequence {} uses '{}', which cannot be part of a sequence",
+                sequence.keys, key
+            )));
+        }
+        if !seen.insert(&sequence.keys) {
+            return Err(Error(format!(
+                "Sequence {} is listed more than once",
+                sequence.keys
+            )));
+        }
+        if let Action::RingResident { phone, .. } = &sequence.action {
+            if !phonenumber::is_viable(phone) || phonenumber::parse(None, phone).is_err() {
+                return Err(Error(format!(
+                    "Invalid phone number {} for sequence {}",
+                    phone, sequence.keys
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(keys: &str, action: Action) -> Sequence {
+        Sequence {
+            keys: keys.to_string(),
+            action,
+        }
+    }
+
+    #[test]
+    fn test_find_sequence() {
+        let sequences = vec![
+            sequence("***", Action::Ring),
+            sequence("*0", Action::Lock),
+            sequence(
+                "9111",
+                Action::Duress {
+                    name: "Alice".to_string(),
+                },
+            ),
+        ];
+        assert_eq!(find(&sequences, "*0"), Some(&Action::Lock));
+        assert!(matches!(
+            find(&sequences, "9111"),
+            Some(Action::Duress { .. })
+        ));
+        assert_eq!(find(&sequences, "**"), None);
+    }
+
+    #[test]
+    fn test_validate_sequences() {
+        let keys: HashSet<char> = "123A456B789C*0#D".chars().collect();
+        assert!(validate(&default_sequences(), &keys).is_ok());
+        let resident = |phone: &str| {
+            sequence(
+                "*1",
+                Action::RingResident {
+                    name: "Flat 1".to_string(),
+                    phone: phone.to_string(),
+                },
+            )
+        };
+        assert!(validate(&[resident("+15555550101")], &keys).is_ok());
+        assert!(validate(&[resident("not a number")], &keys).is_err());
+
+        assert!(validate(&[sequence("", Action::Lock)], &keys).is_err());
+        assert!(validate(&[sequence("*#", Action::Lock)], &keys).is_err());
+        assert!(validate(&[sequence("*E", Action::Lock)], &keys).is_err());
+        let twice = [sequence("*0", Action::Lock), sequence("*0", Action::Ring)];
+        assert!(validate(&twice, &keys).is_err());
+    }
+}
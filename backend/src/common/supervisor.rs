@@ -0,0 +1,214 @@
+//! Runs the device threads, restarting any that panic and stopping them all on request.
+
+use crate::capabilities::{DeviceKind, Health, Inventory};
+use crate::device::Device;
+use crate::request::{Error, Set};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A device that ran this long before crashing starts again from the shortest backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Asks the intercom to stop every device and exit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Stop;
+
+#[derive(Clone)]
+pub struct Supervisor {
+    inventory: Inventory,
+    stopping: Arc<AtomicBool>,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+fn panic_message(panic: &(dyn Any + std::marker::Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Unknown panic".to_string(),
+        },
+    }
+}
+
+impl Supervisor {
+    pub fn new(inventory: Inventory) -> Supervisor {
+        Supervisor {
+            inventory,
+            stopping: Arc::new(AtomicBool::new(false)),
+            threads: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn inventory(&self) -> &Inventory {
+        &self.inventory
+    }
+
+    /// Stops on SIGTERM or Ctrl-C instead of being killed with the door in any state.
+    pub fn stop_on_signals(&self) {
+        for signal in [SIGTERM, SIGINT] {
+            signal_hook::flag::register(signal, self.stopping.clone())
+                .expect("Unable to register signal handler");
+        }
+    }
+
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    /// Sleeps for `duration`, returning early with false if the supervisor is stopping.
+    fn wait_unless_stopping(&self, duration: Duration) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < duration {
+            if self.is_stopping() {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        !self.is_stopping()
+    }
+
+    /// Runs the device on its own thread until the supervisor stops. A panic is reported
+    /// as the device's health and the device is run again after a growing delay.
+    pub fn launch<T, U>(&self, kind: DeviceKind, mut device: impl Device<T, U>) {
+        let supervisor = self.clone();
+        let thread = thread::spawn(move || {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                supervisor.inventory.set(kind, Health::Ok);
+                let started = Instant::now();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    device.run(&supervisor.stopping);
+                }));
+                let error = match result {
+                    Ok(()) => break,
+                    Err(panic) => panic_message(&*panic),
+                };
+                if started.elapsed() >= STABLE_AFTER {
+                    backoff = MIN_BACKOFF;
+                }
+                println!(
+                    "{:?} device crashed ({}), restarting in {}s",
+                    kind,
+                    error,
+                    backoff.as_secs()
+                );
+                supervisor.inventory.set(kind, Health::Restarting(error));
+                if !supervisor.wait_unless_stopping(backoff) {
+                    break;
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            if panic::catch_unwind(AssertUnwindSafe(|| device.shutdown())).is_err() {
+                println!("{:?} device failed to shut down cleanly", kind);
+            }
+            supervisor.inventory.set(kind, Health::Stopped);
+        });
+        self.threads.lock().unwrap().push(thread);
+    }
+
+    /// Blocks until the supervisor is stopped, then until every device has shut down.
+    pub fn wait(&self) {
+        while !self.is_stopping() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        println!("Stopping devices");
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Set<Supervisor, Stop> for Supervisor {
+    fn set(&mut self, _: &Stop) -> Result<(), Error> {
+        self.stop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Shutdown;
+    use crate::message::{self, Receive, Send};
+    use crate::testing::wait_until;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Panics on its first two steps, then runs normally.
+    struct Flaky {
+        steps: Arc<AtomicUsize>,
+        shut_down: Arc<AtomicUsize>,
+    }
+
+    impl Receive<()> for Flaky {
+        fn receive(&mut self) -> Result<(), message::Error> {
+            Err(message::Error::NotReady)
+        }
+    }
+
+    impl Send<()> for Flaky {
+        fn send(&mut self, _: ()) {}
+    }
+
+    impl Device<(), ()> for Flaky {
+        fn handle_command(&mut self, _: ()) -> Shutdown {
+            Shutdown(false)
+        }
+        fn get_step_delay(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+        fn step(&mut self) {
+            if self.steps.fetch_add(1, Ordering::Relaxed) < 2 {
+                panic!("Hardware fell off");
+            }
+        }
+        fn shutdown(&mut self) {
+            self.shut_down.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The device's health, once its thread has started.
+    fn health(supervisor: &Supervisor) -> Option<Health> {
+        let devices = supervisor.inventory().devices();
+        devices.first().map(|x| x.health.clone())
+    }
+
+    #[test]
+    fn test_restart_and_stop() {
+        let supervisor = Supervisor::new(Inventory::default());
+        let steps = Arc::new(AtomicUsize::new(0));
+        let shut_down = Arc::new(AtomicUsize::new(0));
+        let device = Flaky {
+            steps: steps.clone(),
+            shut_down: shut_down.clone(),
+        };
+        let started = Instant::now();
+        supervisor.launch(DeviceKind::Door, device);
+        let crashed = Some(Health::Restarting("Hardware fell off".to_string()));
+        assert!(wait_until(Duration::from_secs(5), || health(&supervisor) == crashed));
+
+        // Restarted after one second, crashed again, then ran after waiting two.
+        let ran = || steps.load(Ordering::Relaxed) > 2;
+        assert!(wait_until(MIN_BACKOFF * 10, ran));
+        assert!(started.elapsed() >= MIN_BACKOFF * 3);
+        assert_eq!(health(&supervisor), Some(Health::Ok));
+
+        supervisor.stop();
+        supervisor.wait();
+        assert_eq!(health(&supervisor), Some(Health::Stopped));
+        assert_eq!(shut_down.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,24 @@
+//! Helpers shared by the tests.
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+/// Polls `done` until it holds, giving up after `within`. Returns whether it held.
+pub fn wait_until(within: Duration, done: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + within;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// A path for `name` in the temp directory, unique to this test run and with no file at
+/// it yet, so a store loaded from it starts empty.
+pub fn temp_file(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
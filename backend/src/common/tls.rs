@@ -0,0 +1,135 @@
+//! HTTPS settings for the web server, with an optional self-signed certificate.
+
+use crate::request::Error;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain and private key.
+    pub cert: String,
+    pub key: String,
+    /// Generate a self-signed certificate at `cert` and `key` if they do not exist yet.
+    pub self_signed: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            enabled: false,
+            cert: "cert.pem".to_string(),
+            key: "key.pem".to_string(),
+            self_signed: false,
+        }
+    }
+}
+
+impl TlsConfig {
+    const SELF_SIGNED_DAYS: u32 = 3650;
+
+    /// Makes sure the certificate and key files exist, generating them if allowed.
+    pub fn ensure_certificate(&self) -> Result<(), Error> {
+        if Path::new(&self.cert).exists() && Path::new(&self.key).exists() {
+            return Ok(());
+        }
+        if !self.self_signed {
+            return Err(Error(format!(
+                "Missing TLS certificate {} or key {}",
+                self.cert, self.key
+            )));
+        }
+        let (cert, key) = self_signed_certificate()
+            .map_err(|_| Error("Could not generate certificate".to_string()))?;
+        let write_error = |path: &str| Error(format!("Could not write {}", path));
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.key)
+            .and_then(|mut file| file.write_all(&key))
+            .map_err(|_| write_error(&self.key))?;
+        fs::write(&self.cert, cert).map_err(|_| write_error(&self.cert))
+    }
+}
+
+/// PEM certificate and PKCS#8 key for `localhost` and the intercom's USB address.
+fn self_signed_certificate() -> Result<(Vec<u8>, Vec<u8>), openssl::error::ErrorStack> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "cautious-axela")?;
+    let name = name.build();
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(TlsConfig::SELF_SIGNED_DAYS)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let names = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .ip("192.168.7.2")
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(names)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((builder.build().to_pem()?, key.private_key_to_pem_pkcs8()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_self_signed_certificate() {
+        let dir = env::temp_dir();
+        let id = std::process::id();
+        let config = TlsConfig {
+            enabled: true,
+            cert: dir
+                .join(format!("cert-{}.pem", id))
+                .to_str()
+                .unwrap()
+                .to_string(),
+            key: dir
+                .join(format!("key-{}.pem", id))
+                .to_str()
+                .unwrap()
+                .to_string(),
+            self_signed: false,
+        };
+        assert!(config.ensure_certificate().is_err());
+
+        let config = TlsConfig {
+            self_signed: true,
+            ..config
+        };
+        config.ensure_certificate().unwrap();
+        let cert = X509::from_pem(&fs::read(&config.cert).unwrap()).unwrap();
+        let key = PKey::private_key_from_pem(&fs::read(&config.key).unwrap()).unwrap();
+        assert!(cert.public_key().unwrap().public_eq(&key));
+        let _ = fs::remove_file(&config.cert);
+        let _ = fs::remove_file(&config.key);
+    }
+}
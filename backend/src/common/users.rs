@@ -0,0 +1,191 @@
+//! Accounts for the web control panel, persisted as JSON with salted PBKDF2 password hashes.
+
+use crate::request::Error;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const ITERATIONS: usize = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// Everything, including codes, cards and the phone number.
+    Admin,
+    /// Can open the door and talk through the intercom.
+    Resident,
+    /// Can watch the camera and read the door state and history.
+    Viewer,
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Role, Error> {
+        match s.to_lowercase().as_str() {
+            "admin" => Ok(Role::Admin),
+            "resident" => Ok(Role::Resident),
+            "viewer" => Ok(Role::Viewer),
+            _ => Err(Error(format!("Unknown role {}", s))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    salt: String,
+    hash: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> Vec<u8> {
+    let mut hash = vec![0; HASH_LEN];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        ITERATIONS,
+        MessageDigest::sha256(),
+        &mut hash,
+    )
+    .expect("PBKDF2 failed");
+    hash
+}
+
+/// Random hex string, used for salts and session tokens.
+pub fn random_token(bytes: usize) -> String {
+    let mut buffer = vec![0; bytes];
+    rand_bytes(&mut buffer).expect("Could not generate random bytes");
+    to_hex(&buffer)
+}
+
+impl User {
+    pub fn new(name: &str, password: &str, role: Role) -> User {
+        let salt = random_token(SALT_LEN);
+        User {
+            name: name.to_string(),
+            role,
+            hash: to_hex(&hash_password(password, salt.as_bytes())),
+            salt,
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let hash = to_hex(&hash_password(password, self.salt.as_bytes()));
+        hash.len() == self.hash.len() && memcmp::eq(hash.as_bytes(), self.hash.as_bytes())
+    }
+}
+
+#[derive(Clone)]
+pub struct UserStore {
+    path: PathBuf,
+    users: Vec<User>,
+}
+
+impl UserStore {
+    pub const DEFAULT_FILE: &'static str = "users.json";
+
+    pub fn load_from(path: impl AsRef<Path>) -> UserStore {
+        UserStore::read(path).unwrap_or_else(|error| panic!("{}", error.0))
+    }
+
+    /// Like `load_from`, but a file that cannot be parsed is an error rather than a panic,
+    /// for servers that re-read it while running.
+    pub fn read(path: impl AsRef<Path>) -> Result<UserStore, Error> {
+        let path = path.as_ref().to_path_buf();
+        let users = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|error| Error(format!("Invalid user store {:?}: {}", path, error)))?,
+            Err(_) => Vec::new(),
+        };
+        Ok(UserStore { path, users })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(&self.users).unwrap();
+        fs::write(&self.path, json).map_err(|_| Error("Could not save users".to_string()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Adds the user, or replaces the password and role of an existing one.
+    pub fn set(&mut self, name: &str, password: &str, role: Role) -> Result<(), Error> {
+        if name.is_empty() || password.is_empty() {
+            return Err(Error("Name and password must not be empty".to_string()));
+        }
+        self.users.retain(|x| x.name != name);
+        self.users.push(User::new(name, password, role));
+        self.save()
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), Error> {
+        let length = self.users.len();
+        self.users.retain(|x| x.name != name);
+        if self.users.len() == length {
+            return Err(Error("No such user".to_string()));
+        }
+        self.save()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|x| x.name == name)
+    }
+
+    /// Returns the user if the name and password match.
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<&User> {
+        self.get(name).filter(|x| x.verify(password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_file;
+
+    fn store(name: &str) -> UserStore {
+        UserStore::load_from(temp_file(&format!("users-{}.json", name)))
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let mut store = store("auth");
+        store.set("alice", "hunter2", Role::Admin).unwrap();
+        assert_eq!(
+            store.authenticate("alice", "hunter2").unwrap().role,
+            Role::Admin
+        );
+        assert!(store.authenticate("alice", "hunter3").is_none());
+        assert!(store.authenticate("bob", "hunter2").is_none());
+    }
+
+    #[test]
+    fn test_persist_and_replace() {
+        let mut store = store("persist");
+        store.set("alice", "hunter2", Role::Admin).unwrap();
+        store.set("alice", "letmein", Role::Viewer).unwrap();
+        let reloaded = UserStore::load_from(&store.path);
+        assert!(reloaded.authenticate("alice", "hunter2").is_none());
+        assert_eq!(
+            reloaded.authenticate("alice", "letmein").unwrap().role,
+            Role::Viewer
+        );
+        assert!(!fs::read_to_string(&store.path).unwrap().contains("letmein"));
+        store.remove("alice").unwrap();
+        assert!(store.is_empty());
+
+        fs::write(&store.path, "[{").unwrap();
+        assert!(UserStore::read(&store.path).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 "use strict";
-let socket = new WebSocket(`ws://${location.host}/socket`)
+let socket = new WebSocket(`${location.protocol == "https:" ? "wss" : "ws"}://${location.host}/socket`)
 
 let g_message_id = 0
 const messages = {}
@@ -23,28 +23,95 @@ socket.addEventListener("open", ev => {
   console.log("ws", "connected", ev)
 })
 
+// The server refuses the upgrade without a valid session cookie.
+let g_connected = false
+socket.addEventListener("close", () => {
+  if (!g_connected) {
+    location.href = "login.html"
+  }
+  connection_indicator.textContent = "Disconnected"
+})
+
 socket.onmessage = ev => {
   const msg = JSON.parse(ev.data)
-  if (msg.id && msg.id in messages) {
+  if (msg.event) {
+    handleEvent(msg.event)
+  } else if (msg.error) {
+    console.log("ws", msg.error)
+    connection_indicator.textContent = `Connected (${msg.error})`
+    delete messages[msg.id]
+  } else if (msg.id && msg.id in messages) {
+    connection_indicator.textContent = "Connected"
     messages[msg.id](msg)
     delete messages[msg.id]
   }
 }
 
 socket.onopen = () => {
+  g_connected = true
   connection_indicator.textContent = "Connected"
   btn_connect.disabled = false
+  send("Whoami", "", resp => applySession(JSON.parse(resp.response)))
+  send("Capabilities", "", resp => applyCapabilities(JSON.parse(resp.response)))
+  getHistory()
+}
+
+// Hides what the user's role may not do; the server enforces the same rules.
+const applySession = (session) => {
+  user_name.textContent = `${session.name} (${session.role})`
+  let hidden = { Admin: [], Resident: [".admin-only"], Viewer: [".admin-only", ".resident-only"] }
+  for (const selector of hidden[session.role]) {
+    document.querySelectorAll(selector).forEach(x => x.style.display = "none")
+  }
+  if (session.role == "Admin") {
+    getCodes()
+    getCards()
+    getRules()
+    setInterval(getKeyPadCode, 1000)
+    setInterval(getPhone, 1000)
+  }
+}
+
+// Hides controls for hardware the intercom does not have.
+const applyCapabilities = (capabilities) => {
+  const attached = capabilities.devices.map(x => x.kind)
+  document.querySelectorAll("[data-device]").forEach(x => {
+    if (!attached.includes(x.dataset.device)) x.style.display = "none"
+  })
+  device_status.textContent = capabilities.devices.map(x => {
+    const health = typeof x.health == "string"
+      ? x.health
+      : Object.entries(x.health).map(([state, error]) => `${state}: ${error}`)[0]
+    return `${x.kind} (${health})`
+  }).join(", ") || "None"
 }
 
 // Helper Functions
+const describeDoorState = (state) => {
+  switch (state) {
+    case "Lock":
+      return "Locked"
+    case "Unlock":
+      return "Unlocked"
+    case "HoldOpen":
+      return "Held open"
+    case "Lockdown":
+      return "Lockdown"
+    default:
+      return state && state.UnlockFor ? `Unlocked for ${state.UnlockFor}s` : "Unknown"
+  }
+}
+
 const updateDoorStatus = (status) => {
-  switch (status) {
-    case "\"Lock\"":
-      door_status.textContent = "Locked"
-      break
-    case "\"Unlock\"":
-      door_status.textContent = "Unlocked"
-      break
+  const doors = JSON.parse(status)
+  door_status.textContent = doors.map(x => `${x.door_id}: ${describeDoorState(x.state)}`).join(", ")
+  const names = doors.map(x => x.door_id)
+  if (names.join() != Array.from(door_select.options).map(x => x.value).join()) {
+    const selected = door_select.value
+    door_select.innerHTML = ""
+    for (const name of names) {
+      door_select.appendChild(new Option(name, name, false, name == selected))
+    }
   }
 }
 
@@ -53,47 +120,82 @@ const updatePinStatus = (code) => {
   pin_number.textContent = code.data
 }
 
+const updateLockoutStatus = (state) => {
+  state = JSON.parse(state)
+  lockout_status.textContent = state.locked
+    ? `Locked for ${state.remaining_secs}s after too many wrong codes`
+    : `Active (${state.recent_failures} recent wrong codes)`
+  clear_lockout.disabled = !state.locked
+}
+
 const updatePhoneStatus = (phone) => {
    phone = JSON.parse(phone)
    phone_number.textContent = phone
 }
 
+const describeEvent = (event) => {
+  switch (event.kind) {
+    case "Unlock":
+      return event.credential ? `Door unlocked by ${event.credential}` : `Door unlocked (${event.source})`
+    case "Lock":
+      return "Door locked"
+    case "FailedCode":
+      return "Wrong code entered on the keypad"
+    case "UnknownCard":
+      return `Unknown card ${event.credential} tapped`
+    case "DoorbellRing":
+      return event.credential ? `Someone rang ${event.credential}` : "Someone rang the doorbell"
+    case "KeypadLockout":
+      return "Keypad locked after too many wrong codes"
+    case "Duress":
+      return `Duress code entered for ${event.credential}`
+    case "OverrideRequest":
+      return "Someone at the door asked to be let in"
+    case "Lockdown":
+      return "Door put in lockdown"
+    case "DoorAjar":
+      return "Door left open"
+    case "ForcedEntry":
+      return "Door opened while locked"
+    case "Exit":
+      return "Exit button pressed"
+    default:
+      return event.kind
+  }
+}
+
+// Pushed by the intercom whenever something happens at the door.
+const handleEvent = (event) => {
+  if (["Unlock", "Lock", "Lockdown", "Exit"].includes(event.kind)) getDoorStatus()
+  const door = event.door ? ` (${event.door})` : ""
+  last_event.textContent = `${new Date(event.time).toLocaleTimeString()}: ${describeEvent(event)}${door}`
+  getHistory()
+}
+
 // Event Listeners
-btn_lock.addEventListener("click", () => {
-  send("DoorSet", "\"Lock\"", resp => {
-    updateDoorStatus(resp.response)
-  })
-})
+const setDoor = (state) => {
+  send("DoorSet", JSON.stringify({ door_id: door_select.value, state: state }), getDoorStatus)
+}
 
-btn_unlock.addEventListener("click", () => {
-  send("DoorSet", "\"Unlock\"", resp => {
-    updateDoorStatus(resp.response)
-  })
-})
+btn_lock.addEventListener("click", () => setDoor("Lock"))
+btn_unlock.addEventListener("click", () => setDoor("Unlock"))
+btn_hold_open.addEventListener("click", () => setDoor("HoldOpen"))
+btn_lockdown.addEventListener("click", () => setDoor("Lockdown"))
+btn_unlock_for.addEventListener("click", () => setDoor({ UnlockFor: Number(unlock_secs.value) }))
 
 scan_card.addEventListener("click", () => {
   document.getElementById("scan_card").innerText = "Scanning New Card..."
-  send("NFCSet", "scanCard", _resp => {
+  send("NFCSet", card_name.value || "Unnamed", _resp => {
     document.getElementById("scan_card").innerText = "Scan New Card"
-    alert("Your card was added successfully.")
+    card_name.value = ""
+    getCards()
   })
 })
 
-show_card.addEventListener("click", () => {
-  let msg = show_card.innerHTML
-  if (msg == "Show") {
-    send("NFCGet", msg, resp => {
-      console.log(resp.response)
-      let response = resp.response.slice(8, -2)
-      document.getElementById("display_ids").innerText = response
-      show_card.innerHTML = "Hide"
-      document.getElementById("display_ids").style.display = "block"
-    })
-  }
-  else {
-    show_card.innerHTML = "Show"
-    document.getElementById("display_ids").style.display = "none"
-  }
+btn_logout.addEventListener("click", () => {
+  fetch("logout", { method: "POST" }).then(() => {
+    location.href = "login.html"
+  })
 })
 
 btn_ping.addEventListener("click", () => {
@@ -111,12 +213,285 @@ submit_new_pin.addEventListener("click", () => {
   })
 })
 
+clear_lockout.addEventListener("click", () => {
+  send("KeypadClearLockout", "", getLockout)
+})
+
 submit_new_phone.addEventListener("click", () => {
    let phone = JSON.stringify(document.getElementById("phone_input").value)
    send("PhoneSet", phone, _ => {
    })
 })
 
+// Codes
+let editing_code = null
+
+const describeRules = (rules) => {
+  let parts = []
+  if (rules.valid_from) parts.push(`from ${new Date(rules.valid_from).toLocaleString()}`)
+  if (rules.valid_until) parts.push(`until ${new Date(rules.valid_until).toLocaleString()}`)
+  if (rules.weekdays && rules.weekdays.length) parts.push(rules.weekdays.join(","))
+  if (rules.hours) parts.push(`${rules.hours.start}-${rules.hours.end}`)
+  if (rules.guest) parts.push("guest")
+  if (rules.doors && rules.doors.length) parts.push(`doors: ${rules.doors.join(",")}`)
+  return parts.length ? parts.join(", ") : "always"
+}
+
+const toLocalInput = (time) => {
+  if (!time) return ""
+  let date = new Date(time)
+  return new Date(date.getTime() - date.getTimezoneOffset() * 60000).toISOString().slice(0, 16)
+}
+
+const splitList = (text) => text.split(",").map(x => x.trim()).filter(x => x)
+
+const readCodeForm = () => {
+  let rules = {}
+  if (code_from.value) rules.valid_from = new Date(code_from.value).toISOString()
+  if (code_until.value) rules.valid_until = new Date(code_until.value).toISOString()
+  let weekdays = code_weekdays.value.split(",").map(x => x.trim()).filter(x => x)
+  if (weekdays.length) rules.weekdays = weekdays
+  let hours = code_hours.value.split("-").map(x => x.trim())
+  if (hours.length == 2) rules.hours = { start: `${hours[0]}:00`, end: `${hours[1]}:00` }
+  if (code_max_uses.value) rules.max_uses = parseInt(code_max_uses.value)
+  rules.guest = code_guest.checked
+  rules.doors = splitList(code_doors.value)
+  return { owner: code_owner.value, code: code_value.value, rules: rules }
+}
+
+const resetCodeForm = () => {
+  editing_code = null
+  for (const input of [code_owner, code_value, code_max_uses, code_from, code_until, code_weekdays, code_hours, code_doors]) {
+    input.value = ""
+  }
+  code_guest.checked = false
+  submit_code.innerText = "Add Code"
+}
+
+const editCode = (entry) => {
+  editing_code = entry
+  code_owner.value = entry.owner
+  code_value.value = entry.code
+  code_max_uses.value = entry.rules.max_uses ?? ""
+  code_from.value = toLocalInput(entry.rules.valid_from)
+  code_until.value = toLocalInput(entry.rules.valid_until)
+  code_weekdays.value = (entry.rules.weekdays || []).join(",")
+  code_hours.value = entry.rules.hours ? `${entry.rules.hours.start.slice(0, 5)}-${entry.rules.hours.end.slice(0, 5)}` : ""
+  code_guest.checked = entry.rules.guest
+  code_doors.value = (entry.rules.doors || []).join(",")
+  submit_code.innerText = "Save Code"
+}
+
+const updateCodes = (codes) => {
+  code_rows.innerHTML = ""
+  for (const entry of JSON.parse(codes)) {
+    let row = document.createElement("tr")
+    for (const text of [entry.owner, entry.code, describeRules(entry.rules),
+      entry.rules.max_uses ? `${entry.uses}/${entry.rules.max_uses}` : `${entry.uses}`]) {
+      let cell = document.createElement("td")
+      cell.innerText = text
+      row.appendChild(cell)
+    }
+    let actions = document.createElement("td")
+    let edit = document.createElement("button")
+    edit.innerText = "Edit"
+    edit.addEventListener("click", () => editCode(entry))
+    let revoke = document.createElement("button")
+    revoke.innerText = "Revoke"
+    revoke.addEventListener("click", () => {
+      send("KeypadRevokeCode", JSON.stringify(entry.id), getCodes)
+    })
+    actions.appendChild(edit)
+    actions.appendChild(revoke)
+    row.appendChild(actions)
+    code_rows.appendChild(row)
+  }
+}
+
+const getCodes = () => {
+  send("KeypadListCodes", "", (resp) => {
+    updateCodes(resp.response)
+  })
+}
+
+submit_code.addEventListener("click", () => {
+  let code = readCodeForm()
+  if (editing_code) {
+    code = { ...code, id: editing_code.id, uses: editing_code.uses }
+    send("KeypadEditCode", JSON.stringify(code), getCodes)
+  } else {
+    send("KeypadAddCode", JSON.stringify(code), getCodes)
+  }
+  resetCodeForm()
+})
+
+cancel_code_edit.addEventListener("click", resetCodeForm)
+
+// Schedule
+let editing_rule = null
+
+const describeTimes = (times) => {
+  let parts = []
+  if (times.weekdays.length) parts.push(times.weekdays.join(","))
+  if (times.dates.length) parts.push(times.dates.join(","))
+  if (times.hours) parts.push(`${times.hours.start.slice(0, 5)}-${times.hours.end.slice(0, 5)}`)
+  return parts.length ? parts.join(", ") : "always"
+}
+
+const readRuleForm = () => {
+  let list = (input) => input.value.split(",").map(x => x.trim()).filter(x => x)
+  let times = { weekdays: list(rule_weekdays), dates: list(rule_dates) }
+  let hours = rule_hours.value.split("-").map(x => x.trim())
+  if (hours.length == 2) times.hours = { start: `${hours[0]}:00`, end: `${hours[1]}:00` }
+  return { name: rule_name.value, effect: rule_effect.value, times: times }
+}
+
+const resetRuleForm = () => {
+  editing_rule = null
+  for (const input of [rule_name, rule_weekdays, rule_hours, rule_dates]) {
+    input.value = ""
+  }
+  rule_effect.value = "Unlock"
+  submit_rule.innerText = "Add Rule"
+}
+
+const editRule = (rule) => {
+  editing_rule = rule
+  rule_name.value = rule.name
+  rule_effect.value = rule.effect
+  rule_weekdays.value = rule.times.weekdays.join(",")
+  rule_dates.value = rule.times.dates.join(",")
+  rule_hours.value = rule.times.hours ? `${rule.times.hours.start.slice(0, 5)}-${rule.times.hours.end.slice(0, 5)}` : ""
+  submit_rule.innerText = "Save Rule"
+}
+
+const updateRules = (rules) => {
+  rule_rows.innerHTML = ""
+  for (const rule of JSON.parse(rules)) {
+    let row = document.createElement("tr")
+    for (const text of [rule.name, rule.effect, describeTimes(rule.times)]) {
+      let cell = document.createElement("td")
+      cell.innerText = text
+      row.appendChild(cell)
+    }
+    let actions = document.createElement("td")
+    let edit = document.createElement("button")
+    edit.innerText = "Edit"
+    edit.addEventListener("click", () => editRule(rule))
+    let remove = document.createElement("button")
+    remove.innerText = "Remove"
+    remove.addEventListener("click", () => {
+      send("ScheduleRemoveRule", JSON.stringify(rule.id), getRules)
+    })
+    actions.appendChild(edit)
+    actions.appendChild(remove)
+    row.appendChild(actions)
+    rule_rows.appendChild(row)
+  }
+}
+
+const getRules = () => {
+  send("ScheduleList", "", (resp) => {
+    updateRules(resp.response)
+  })
+}
+
+submit_rule.addEventListener("click", () => {
+  let rule = readRuleForm()
+  if (editing_rule) {
+    send("ScheduleEditRule", JSON.stringify({ ...rule, id: editing_rule.id }), getRules)
+  } else {
+    send("ScheduleAddRule", JSON.stringify(rule), getRules)
+  }
+  resetRuleForm()
+})
+
+cancel_rule_edit.addEventListener("click", resetRuleForm)
+
+// Cards
+const updateCards = (cards) => {
+  card_rows.innerHTML = ""
+  for (const card of JSON.parse(cards)) {
+    let row = document.createElement("tr")
+    for (const text of [card.name, card.uid, new Date(card.enrolled).toLocaleString(),
+      card.doors.length ? card.doors.join(",") : "All"]) {
+      let cell = document.createElement("td")
+      cell.innerText = text
+      row.appendChild(cell)
+    }
+    let enabled = document.createElement("td")
+    let toggle = document.createElement("input")
+    toggle.type = "checkbox"
+    toggle.checked = card.enabled
+    toggle.addEventListener("change", () => {
+      send("NFCSetCardEnabled", JSON.stringify({ uid: card.uid, enabled: toggle.checked }), getCards)
+    })
+    enabled.appendChild(toggle)
+    row.appendChild(enabled)
+    let actions = document.createElement("td")
+    let rename = document.createElement("button")
+    rename.innerText = "Rename"
+    rename.addEventListener("click", () => {
+      let name = prompt("New name for this card", card.name)
+      if (name) {
+        send("NFCRenameCard", JSON.stringify({ uid: card.uid, name: name }), getCards)
+      }
+    })
+    let doors = document.createElement("button")
+    doors.innerText = "Doors"
+    doors.addEventListener("click", () => {
+      let names = prompt("Doors this card opens, e.g. Front,Garage (empty for all)", card.doors.join(","))
+      if (names != null) {
+        send("NFCSetCardDoors", JSON.stringify({ uid: card.uid, doors: splitList(names) }), getCards)
+      }
+    })
+    let remove = document.createElement("button")
+    remove.innerText = "Remove"
+    remove.addEventListener("click", () => {
+      send("NFCRemoveCard", card.uid, getCards)
+    })
+    actions.appendChild(rename)
+    actions.appendChild(doors)
+    actions.appendChild(remove)
+    row.appendChild(actions)
+    card_rows.appendChild(row)
+  }
+}
+
+const getCards = () => {
+  send("NFCGet", "", (resp) => {
+    updateCards(resp.response)
+  })
+}
+
+// History
+const updateHistory = (events) => {
+  history_rows.innerHTML = ""
+  for (const event of JSON.parse(events)) {
+    let row = document.createElement("tr")
+    let result = "Ok" in event.result ? "Ok" : event.result.Err
+    for (const text of [new Date(event.time).toLocaleString(), event.kind, event.door ?? "", event.source,
+      event.credential ?? "", result]) {
+      let cell = document.createElement("td")
+      cell.innerText = text
+      row.appendChild(cell)
+    }
+    history_rows.appendChild(row)
+  }
+}
+
+const getHistory = () => {
+  let query = {}
+  if (history_from.value) query.from = new Date(history_from.value).toISOString()
+  if (history_until.value) query.until = new Date(history_until.value).toISOString()
+  if (history_kind.value) query.kinds = [history_kind.value]
+  send("AuditQuery", JSON.stringify(query), (resp) => {
+    updateHistory(resp.response)
+  })
+}
+
+history_refresh.addEventListener("click", getHistory)
+
 // Timers
 const getDoorStatus = () => {
   send("DoorGet", "", (resp) => {
@@ -138,9 +513,13 @@ const getPhone = () => {
   })
 }
 
-const keypadStatusTimeout = setInterval(getKeyPadCode, 1000)
+const getLockout = () => {
+  send("KeypadGetLockout", "", (resp) => {
+    updateLockoutStatus(resp.response)
+  })
+}
 
-const phoneNumberStatusTimeout = setInterval(getPhone, 1000);
+const lockoutStatusTimeout = setInterval(getLockout, 1000)
 
 /* camera related stuff */
 
@@ -206,7 +585,7 @@ mic_on.addEventListener("click", async () => {
       })
     }
   }
-  // since we don't have HTTPS, go to about:config set to true media.devices.insecure.enabled and media.getusermedia.insecure.enabled
+  // without HTTPS (`tls.enabled`), go to about:config set to true media.devices.insecure.enabled and media.getusermedia.insecure.enabled
   navigator.mediaDevices
     .getUserMedia({ audio: true, video: false })
     .then(stream => {
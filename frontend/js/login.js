@@ -0,0 +1,17 @@
+"use strict";
+
+login_form.addEventListener("submit", ev => {
+  ev.preventDefault()
+  fetch("login", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ name: login_name.value, password: login_password.value })
+  }).then(resp => {
+    if (resp.ok) {
+      location.href = "/"
+    } else {
+      login_password.value = ""
+      resp.text().then(text => login_error.textContent = text)
+    }
+  }).catch(err => login_error.textContent = err)
+})